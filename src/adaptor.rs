@@ -1,28 +1,104 @@
 use crate::display;
-use crate::errors::Result;
-use crate::migration::Migration;
+use crate::errors::{Error, Result};
+use crate::history::{HistoryEntry, Outcome};
+use crate::migration::{Migration, MigrationBuilder};
 use crate::plan_builder::Step;
+use crate::schema::{self, SchemaObject};
+use crate::statements::{self, Dialect, Statement};
+use chrono::prelude::*;
+use std::thread;
+use std::time::{Duration, Instant};
 
+#[cfg(feature = "with-tokio-postgres")]
+mod async_adaptor;
+#[cfg(feature = "mysql")]
+mod mysql;
 mod postgres;
 mod sqlite;
+mod table;
+#[cfg(feature = "with-tokio-postgres")]
+mod tokio_postgres;
+
+#[cfg(feature = "with-tokio-postgres")]
+pub use async_adaptor::AsyncDbAdaptor;
+pub use table::MigrationTable;
 
 pub trait DbAdaptor {
-    fn init_up_sql(&self) -> &'static str;
-    fn init_down_sql(&self) -> &'static str;
-    fn load_migrations(&mut self) -> Result<Vec<Migration>>;
-    fn run_up_migration(&mut self, migration: &Migration) -> Result<()>;
-    fn run_down_migration(&mut self, migration: &Migration) -> Result<()>;
+    fn init_up_sql(&self, table: &MigrationTable) -> String;
+    fn init_down_sql(&self, table: &MigrationTable) -> String;
+    /// Every applied migration. Tables created by older versions of Movine are read as they are,
+    /// without upgrading them.
+    fn load_migrations(&mut self, table: &MigrationTable) -> Result<Vec<Migration>>;
+    /// Every up and down step ever run, oldest first. Empty if there is no history table yet.
+    fn load_history(&mut self, table: &MigrationTable) -> Result<Vec<HistoryEntry>>;
+    fn run_up_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()>;
+    fn run_down_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()>;
+    fn update_migration_hash(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        hash: &str,
+    ) -> Result<()>;
+    fn update_migration_down_sql(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        down_sql: &str,
+    ) -> Result<()>;
+
+    /// Record `migration` as applied without running its SQL, for adopting Movine on a database
+    /// that already has its schema.
+    fn baseline_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()>;
+
+    /// Add anything newer versions of Movine expect to a migrations table created by an older
+    /// version. Does nothing if the table is already up to date. Only called while holding the
+    /// lock, so that commands which just read the table leave it as it was.
+    fn upgrade_schema(&mut self, table: &MigrationTable) -> Result<()>;
+
+    /// Take a lock that stops other Movine instances from running migrations at the same time,
+    /// waiting for up to `timeout` (or forever if `None`) for it to be released.
+    fn acquire_lock(&mut self, table: &MigrationTable, timeout: Option<Duration>) -> Result<()>;
+    fn release_lock(&mut self, table: &MigrationTable) -> Result<()>;
+
+    /// Release the lock whoever holds it, for when an instance stopped without releasing it.
+    /// Does nothing for databases that release the lock when the holder's connection closes.
+    fn break_lock(&mut self, _table: &MigrationTable) -> Result<()> {
+        Ok(())
+    }
+
+    /// Read back every table, constraint, index and view in the database apart from Movine's
+    /// own, in a stable order so that two schemas can be compared.
+    fn load_schema(&mut self, table: &MigrationTable) -> Result<Vec<SchemaObject>>;
+
+    /// The schema from `load_schema`, written out as SQL.
+    fn dump_schema(&mut self, table: &MigrationTable) -> Result<String> {
+        Ok(schema::dump(&self.load_schema(table)?))
+    }
 
-    fn run_migration_plan(&mut self, plan: &[(Step, &Migration)]) -> Result<()> {
+    /// Run the whole plan inside a single transaction, so either every step is applied or none
+    /// are. Only adaptors whose databases support transactional DDL can do this.
+    fn run_atomic_migration_plan(
+        &mut self,
+        _table: &MigrationTable,
+        _plan: &[(Step, &Migration)],
+    ) -> Result<()> {
+        Err(Error::AtomicUnsupported)
+    }
+
+    fn run_migration_plan(
+        &mut self,
+        table: &MigrationTable,
+        plan: &[(Step, &Migration)],
+    ) -> Result<()> {
         for (step, migration) in plan {
             display::print_step(&(*step, migration));
             match step {
                 Step::Up => {
-                    self.run_up_migration(&migration)?;
+                    self.run_up_migration(table, migration)?;
                 }
                 Step::Down => {
                     if migration.is_reversable() {
-                        self.run_down_migration(&migration)?;
+                        self.run_down_migration(table, migration)?;
                     }
                 }
             }
@@ -32,53 +108,310 @@ pub trait DbAdaptor {
 }
 
 impl<T: DbAdaptor + ?Sized> DbAdaptor for &'_ mut T {
-    fn init_up_sql(&self) -> &'static str {
-        (**self).init_up_sql()
+    fn init_up_sql(&self, table: &MigrationTable) -> String {
+        (**self).init_up_sql(table)
+    }
+
+    fn init_down_sql(&self, table: &MigrationTable) -> String {
+        (**self).init_down_sql(table)
+    }
+
+    fn load_migrations(&mut self, table: &MigrationTable) -> Result<Vec<Migration>> {
+        (**self).load_migrations(table)
+    }
+
+    fn load_history(&mut self, table: &MigrationTable) -> Result<Vec<HistoryEntry>> {
+        (**self).load_history(table)
+    }
+
+    fn run_up_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()> {
+        (**self).run_up_migration(table, migration)
     }
 
-    fn init_down_sql(&self) -> &'static str {
-        (**self).init_down_sql()
+    fn run_down_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()> {
+        (**self).run_down_migration(table, migration)
     }
 
-    fn load_migrations(&mut self) -> Result<Vec<Migration>> {
-        (**self).load_migrations()
+    fn update_migration_hash(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        hash: &str,
+    ) -> Result<()> {
+        (**self).update_migration_hash(table, name, hash)
     }
 
-    fn run_up_migration(&mut self, migration: &Migration) -> Result<()> {
-        (**self).run_up_migration(migration)
+    fn update_migration_down_sql(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        down_sql: &str,
+    ) -> Result<()> {
+        (**self).update_migration_down_sql(table, name, down_sql)
     }
 
-    fn run_down_migration(&mut self, migration: &Migration) -> Result<()> {
-        (**self).run_down_migration(migration)
+    fn baseline_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()> {
+        (**self).baseline_migration(table, migration)
     }
 
-    fn run_migration_plan(&mut self, plan: &[(Step, &Migration)]) -> Result<()> {
-        (**self).run_migration_plan(plan)
+    fn upgrade_schema(&mut self, table: &MigrationTable) -> Result<()> {
+        (**self).upgrade_schema(table)
+    }
+
+    fn acquire_lock(&mut self, table: &MigrationTable, timeout: Option<Duration>) -> Result<()> {
+        (**self).acquire_lock(table, timeout)
+    }
+
+    fn release_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        (**self).release_lock(table)
+    }
+
+    fn break_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        (**self).break_lock(table)
+    }
+
+    fn load_schema(&mut self, table: &MigrationTable) -> Result<Vec<SchemaObject>> {
+        (**self).load_schema(table)
+    }
+
+    fn run_migration_plan(
+        &mut self,
+        table: &MigrationTable,
+        plan: &[(Step, &Migration)],
+    ) -> Result<()> {
+        (**self).run_migration_plan(table, plan)
+    }
+
+    fn run_atomic_migration_plan(
+        &mut self,
+        table: &MigrationTable,
+        plan: &[(Step, &Migration)],
+    ) -> Result<()> {
+        (**self).run_atomic_migration_plan(table, plan)
     }
 }
 
 impl<T: DbAdaptor + ?Sized> DbAdaptor for Box<T> {
-    fn init_up_sql(&self) -> &'static str {
-        (**self).init_up_sql()
+    fn init_up_sql(&self, table: &MigrationTable) -> String {
+        (**self).init_up_sql(table)
+    }
+
+    fn init_down_sql(&self, table: &MigrationTable) -> String {
+        (**self).init_down_sql(table)
+    }
+
+    fn load_migrations(&mut self, table: &MigrationTable) -> Result<Vec<Migration>> {
+        (**self).load_migrations(table)
+    }
+
+    fn load_history(&mut self, table: &MigrationTable) -> Result<Vec<HistoryEntry>> {
+        (**self).load_history(table)
+    }
+
+    fn run_up_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()> {
+        (**self).run_up_migration(table, migration)
     }
 
-    fn init_down_sql(&self) -> &'static str {
-        (**self).init_down_sql()
+    fn run_down_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()> {
+        (**self).run_down_migration(table, migration)
     }
 
-    fn load_migrations(&mut self) -> Result<Vec<Migration>> {
-        (**self).load_migrations()
+    fn update_migration_hash(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        hash: &str,
+    ) -> Result<()> {
+        (**self).update_migration_hash(table, name, hash)
     }
 
-    fn run_up_migration(&mut self, migration: &Migration) -> Result<()> {
-        (**self).run_up_migration(migration)
+    fn update_migration_down_sql(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        down_sql: &str,
+    ) -> Result<()> {
+        (**self).update_migration_down_sql(table, name, down_sql)
     }
 
-    fn run_down_migration(&mut self, migration: &Migration) -> Result<()> {
-        (**self).run_down_migration(migration)
+    fn baseline_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()> {
+        (**self).baseline_migration(table, migration)
     }
 
-    fn run_migration_plan(&mut self, plan: &[(Step, &Migration)]) -> Result<()> {
-        (**self).run_migration_plan(plan)
+    fn upgrade_schema(&mut self, table: &MigrationTable) -> Result<()> {
+        (**self).upgrade_schema(table)
+    }
+
+    fn acquire_lock(&mut self, table: &MigrationTable, timeout: Option<Duration>) -> Result<()> {
+        (**self).acquire_lock(table, timeout)
+    }
+
+    fn release_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        (**self).release_lock(table)
+    }
+
+    fn break_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        (**self).break_lock(table)
+    }
+
+    fn load_schema(&mut self, table: &MigrationTable) -> Result<Vec<SchemaObject>> {
+        (**self).load_schema(table)
+    }
+
+    fn run_migration_plan(
+        &mut self,
+        table: &MigrationTable,
+        plan: &[(Step, &Migration)],
+    ) -> Result<()> {
+        (**self).run_migration_plan(table, plan)
+    }
+
+    fn run_atomic_migration_plan(
+        &mut self,
+        table: &MigrationTable,
+        plan: &[(Step, &Migration)],
+    ) -> Result<()> {
+        (**self).run_atomic_migration_plan(table, plan)
+    }
+}
+
+/// The migration `init` writes to create the migrations table.
+const INIT_MIGRATION: &str = "1970-01-01-000000_movine_init";
+
+/// Recorded alongside each migration that is applied.
+const MOVINE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// A row of the migrations table, as read by an adaptor.
+struct MigrationRow {
+    name: String,
+    hash: String,
+    down_sql: Option<String>,
+    applied_at_ms: Option<i64>,
+    applied_by: Option<String>,
+    duration_ms: Option<i64>,
+    movine_version: Option<String>,
+}
+
+impl MigrationRow {
+    fn into_migration(self) -> Result<Migration> {
+        let mut builder = MigrationBuilder::new();
+        builder
+            .compound_name(&self.name)
+            .hash(&self.hash)
+            .down_sql(self.down_sql.as_deref().unwrap_or(""));
+        if let Some(applied_at) = self
+            .applied_at_ms
+            .and_then(|x| Utc.timestamp_millis_opt(x).single())
+        {
+            builder.applied_at(applied_at);
+        }
+        if let Some(applied_by) = &self.applied_by {
+            builder.applied_by(applied_by);
+        }
+        if let Some(duration_ms) = self.duration_ms {
+            builder.duration_ms(duration_ms);
+        }
+        if let Some(movine_version) = &self.movine_version {
+            builder.movine_version(movine_version);
+        }
+        builder.build()
+    }
+}
+
+/// A row of the history table, as read by an adaptor.
+struct HistoryRow {
+    name: String,
+    direction: String,
+    hash: Option<String>,
+    actor: Option<String>,
+    outcome: String,
+    recorded_at_ms: Option<i64>,
+    duration_ms: Option<i64>,
+    movine_version: Option<String>,
+}
+
+impl HistoryRow {
+    fn into_entry(self) -> Result<HistoryEntry> {
+        let direction = match self.direction.as_str() {
+            "up" => Step::Up,
+            "down" => Step::Down,
+            _ => return Err(Error::BadHistoryEntry(self.direction)),
+        };
+        Ok(HistoryEntry {
+            name: self.name,
+            direction,
+            hash: self.hash,
+            actor: self.actor,
+            outcome: Outcome::parse(&self.outcome)?,
+            recorded_at: self
+                .recorded_at_ms
+                .and_then(|x| Utc.timestamp_millis_opt(x).single()),
+            duration_ms: self.duration_ms,
+            movine_version: self.movine_version,
+        })
+    }
+}
+
+/// Run a migration's `sql` as one batch with `execute`. If it fails, `locate` is given the
+/// statements of `sql` and the error to work out which statement failed, so that the error can
+/// say where.
+fn run_sql<F, E, L>(
+    migration: &Migration,
+    step: Step,
+    sql: &str,
+    dialect: Dialect,
+    execute: F,
+    locate: L,
+) -> Result<()>
+where
+    F: FnOnce(&str) -> std::result::Result<(), E>,
+    E: Into<Error>,
+    L: FnOnce(&[Statement], &Error) -> Option<usize>,
+{
+    let error = match execute(sql) {
+        Ok(()) => return Ok(()),
+        Err(e) => e.into(),
+    };
+    let statements = statements::split(sql, dialect);
+    let failed = locate(&statements, &error).and_then(|i| Some((i, statements.get(i)?)));
+    Err(migration_failed(migration, step, failed, error))
+}
+
+fn migration_failed(
+    migration: &Migration,
+    step: Step,
+    statement: Option<(usize, &Statement)>,
+    source: Error,
+) -> Error {
+    Error::MigrationFailed {
+        name: migration.name.clone(),
+        step,
+        statement_index: statement.map(|(index, _)| index),
+        line: statement.map(|(_, x)| x.line),
+        source: Box::new(source),
+    }
+}
+
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Keep calling `try_lock` until it succeeds, giving up once `timeout` has passed.
+fn wait_for_lock<F>(timeout: Option<Duration>, mut try_lock: F) -> Result<()>
+where
+    F: FnMut() -> Result<bool>,
+{
+    let start = Instant::now();
+    let mut waiting = false;
+    while !try_lock()? {
+        if let Some(timeout) = timeout {
+            if start.elapsed() >= timeout {
+                return Err(Error::LockTimeout);
+            }
+        }
+        if !waiting {
+            info!("Waiting for another Movine instance to release the migration lock");
+            waiting = true;
+        }
+        thread::sleep(LOCK_POLL_INTERVAL);
     }
+    Ok(())
 }
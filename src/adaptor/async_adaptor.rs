@@ -0,0 +1,324 @@
+use super::{MigrationTable, LOCK_POLL_INTERVAL};
+use crate::display;
+use crate::errors::{Error, Result};
+use crate::history::HistoryEntry;
+use crate::migration::Migration;
+use crate::plan_builder::Step;
+use crate::schema::{self, SchemaObject};
+use async_trait::async_trait;
+use std::time::{Duration, Instant};
+
+/// The async counterpart of [`DbAdaptor`](crate::DbAdaptor), for connections driven by an
+/// existing runtime.
+#[async_trait]
+pub trait AsyncDbAdaptor: Send {
+    fn init_up_sql(&self, table: &MigrationTable) -> String;
+    fn init_down_sql(&self, table: &MigrationTable) -> String;
+    /// Every applied migration. Tables created by older versions of Movine are read as they are,
+    /// without upgrading them.
+    async fn load_migrations(&mut self, table: &MigrationTable) -> Result<Vec<Migration>>;
+    /// Every up and down step ever run, oldest first. Empty if there is no history table yet.
+    async fn load_history(&mut self, table: &MigrationTable) -> Result<Vec<HistoryEntry>>;
+    async fn run_up_migration(
+        &mut self,
+        table: &MigrationTable,
+        migration: &Migration,
+    ) -> Result<()>;
+    async fn run_down_migration(
+        &mut self,
+        table: &MigrationTable,
+        migration: &Migration,
+    ) -> Result<()>;
+    async fn update_migration_hash(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        hash: &str,
+    ) -> Result<()>;
+    async fn update_migration_down_sql(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        down_sql: &str,
+    ) -> Result<()>;
+
+    /// Record `migration` as applied without running its SQL, for adopting Movine on a database
+    /// that already has its schema.
+    async fn baseline_migration(
+        &mut self,
+        table: &MigrationTable,
+        migration: &Migration,
+    ) -> Result<()>;
+
+    /// Add anything newer versions of Movine expect to a migrations table created by an older
+    /// version. Does nothing if the table is already up to date. Only called while holding the
+    /// lock, so that commands which just read the table leave it as it was.
+    async fn upgrade_schema(&mut self, table: &MigrationTable) -> Result<()>;
+
+    /// Take a lock that stops other Movine instances from running migrations at the same time,
+    /// waiting for up to `timeout` (or forever if `None`) for it to be released.
+    async fn acquire_lock(
+        &mut self,
+        table: &MigrationTable,
+        timeout: Option<Duration>,
+    ) -> Result<()>;
+    async fn release_lock(&mut self, table: &MigrationTable) -> Result<()>;
+
+    /// Read back every table, constraint, index and view in the database apart from Movine's
+    /// own, in a stable order so that two schemas can be compared.
+    async fn load_schema(&mut self, table: &MigrationTable) -> Result<Vec<SchemaObject>>;
+
+    /// The schema from `load_schema`, written out as SQL.
+    async fn dump_schema(&mut self, table: &MigrationTable) -> Result<String> {
+        Ok(schema::dump(&self.load_schema(table).await?))
+    }
+
+    /// Run the whole plan inside a single transaction, so either every step is applied or none
+    /// are. Only adaptors whose databases support transactional DDL can do this.
+    async fn run_atomic_migration_plan(
+        &mut self,
+        _table: &MigrationTable,
+        _plan: &[(Step, &Migration)],
+    ) -> Result<()> {
+        Err(Error::AtomicUnsupported)
+    }
+
+    async fn run_migration_plan(
+        &mut self,
+        table: &MigrationTable,
+        plan: &[(Step, &Migration)],
+    ) -> Result<()> {
+        for (step, migration) in plan {
+            display::print_step(&(*step, migration));
+            match step {
+                Step::Up => {
+                    self.run_up_migration(table, migration).await?;
+                }
+                Step::Down => {
+                    if migration.is_reversable() {
+                        self.run_down_migration(table, migration).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<T: AsyncDbAdaptor + ?Sized> AsyncDbAdaptor for &'_ mut T {
+    fn init_up_sql(&self, table: &MigrationTable) -> String {
+        (**self).init_up_sql(table)
+    }
+
+    fn init_down_sql(&self, table: &MigrationTable) -> String {
+        (**self).init_down_sql(table)
+    }
+
+    async fn load_migrations(&mut self, table: &MigrationTable) -> Result<Vec<Migration>> {
+        (**self).load_migrations(table).await
+    }
+
+    async fn load_history(&mut self, table: &MigrationTable) -> Result<Vec<HistoryEntry>> {
+        (**self).load_history(table).await
+    }
+
+    async fn run_up_migration(
+        &mut self,
+        table: &MigrationTable,
+        migration: &Migration,
+    ) -> Result<()> {
+        (**self).run_up_migration(table, migration).await
+    }
+
+    async fn run_down_migration(
+        &mut self,
+        table: &MigrationTable,
+        migration: &Migration,
+    ) -> Result<()> {
+        (**self).run_down_migration(table, migration).await
+    }
+
+    async fn update_migration_hash(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        hash: &str,
+    ) -> Result<()> {
+        (**self).update_migration_hash(table, name, hash).await
+    }
+
+    async fn update_migration_down_sql(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        down_sql: &str,
+    ) -> Result<()> {
+        (**self)
+            .update_migration_down_sql(table, name, down_sql)
+            .await
+    }
+
+    async fn baseline_migration(
+        &mut self,
+        table: &MigrationTable,
+        migration: &Migration,
+    ) -> Result<()> {
+        (**self).baseline_migration(table, migration).await
+    }
+
+    async fn upgrade_schema(&mut self, table: &MigrationTable) -> Result<()> {
+        (**self).upgrade_schema(table).await
+    }
+
+    async fn acquire_lock(
+        &mut self,
+        table: &MigrationTable,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        (**self).acquire_lock(table, timeout).await
+    }
+
+    async fn release_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        (**self).release_lock(table).await
+    }
+
+    async fn load_schema(&mut self, table: &MigrationTable) -> Result<Vec<SchemaObject>> {
+        (**self).load_schema(table).await
+    }
+
+    async fn run_migration_plan(
+        &mut self,
+        table: &MigrationTable,
+        plan: &[(Step, &Migration)],
+    ) -> Result<()> {
+        (**self).run_migration_plan(table, plan).await
+    }
+
+    async fn run_atomic_migration_plan(
+        &mut self,
+        table: &MigrationTable,
+        plan: &[(Step, &Migration)],
+    ) -> Result<()> {
+        (**self).run_atomic_migration_plan(table, plan).await
+    }
+}
+
+#[async_trait]
+impl<T: AsyncDbAdaptor + ?Sized> AsyncDbAdaptor for Box<T> {
+    fn init_up_sql(&self, table: &MigrationTable) -> String {
+        (**self).init_up_sql(table)
+    }
+
+    fn init_down_sql(&self, table: &MigrationTable) -> String {
+        (**self).init_down_sql(table)
+    }
+
+    async fn load_migrations(&mut self, table: &MigrationTable) -> Result<Vec<Migration>> {
+        (**self).load_migrations(table).await
+    }
+
+    async fn load_history(&mut self, table: &MigrationTable) -> Result<Vec<HistoryEntry>> {
+        (**self).load_history(table).await
+    }
+
+    async fn run_up_migration(
+        &mut self,
+        table: &MigrationTable,
+        migration: &Migration,
+    ) -> Result<()> {
+        (**self).run_up_migration(table, migration).await
+    }
+
+    async fn run_down_migration(
+        &mut self,
+        table: &MigrationTable,
+        migration: &Migration,
+    ) -> Result<()> {
+        (**self).run_down_migration(table, migration).await
+    }
+
+    async fn update_migration_hash(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        hash: &str,
+    ) -> Result<()> {
+        (**self).update_migration_hash(table, name, hash).await
+    }
+
+    async fn update_migration_down_sql(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        down_sql: &str,
+    ) -> Result<()> {
+        (**self)
+            .update_migration_down_sql(table, name, down_sql)
+            .await
+    }
+
+    async fn baseline_migration(
+        &mut self,
+        table: &MigrationTable,
+        migration: &Migration,
+    ) -> Result<()> {
+        (**self).baseline_migration(table, migration).await
+    }
+
+    async fn upgrade_schema(&mut self, table: &MigrationTable) -> Result<()> {
+        (**self).upgrade_schema(table).await
+    }
+
+    async fn acquire_lock(
+        &mut self,
+        table: &MigrationTable,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        (**self).acquire_lock(table, timeout).await
+    }
+
+    async fn release_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        (**self).release_lock(table).await
+    }
+
+    async fn load_schema(&mut self, table: &MigrationTable) -> Result<Vec<SchemaObject>> {
+        (**self).load_schema(table).await
+    }
+
+    async fn run_migration_plan(
+        &mut self,
+        table: &MigrationTable,
+        plan: &[(Step, &Migration)],
+    ) -> Result<()> {
+        (**self).run_migration_plan(table, plan).await
+    }
+
+    async fn run_atomic_migration_plan(
+        &mut self,
+        table: &MigrationTable,
+        plan: &[(Step, &Migration)],
+    ) -> Result<()> {
+        (**self).run_atomic_migration_plan(table, plan).await
+    }
+}
+
+/// Called after a failed attempt to take the lock: gives up once `timeout` has passed since
+/// `start`, and otherwise sleeps before the next attempt.
+pub(super) async fn retry_lock(
+    timeout: Option<Duration>,
+    start: Instant,
+    waiting: &mut bool,
+) -> Result<()> {
+    if let Some(timeout) = timeout {
+        if start.elapsed() >= timeout {
+            return Err(Error::LockTimeout);
+        }
+    }
+    if !*waiting {
+        info!("Waiting for another Movine instance to release the migration lock");
+        *waiting = true;
+    }
+    tokio::time::sleep(LOCK_POLL_INTERVAL).await;
+    Ok(())
+}
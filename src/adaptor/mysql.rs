@@ -0,0 +1,591 @@
+use crate::adaptor::{
+    run_sql, wait_for_lock, DbAdaptor, HistoryRow, MigrationRow, MigrationTable, INIT_MIGRATION,
+    MOVINE_VERSION,
+};
+use crate::errors::{Error, Result};
+use crate::history::{HistoryEntry, Outcome};
+use crate::migration::Migration;
+use crate::plan_builder::Step;
+use crate::schema::SchemaObject;
+use crate::statements::Dialect;
+use mysql::prelude::Queryable;
+use mysql::{Conn, Row, TxOpts};
+use std::time::{Duration, Instant};
+
+// MySQL commits implicitly after most DDL statements, so there is no support for atomic plans.
+impl DbAdaptor for Conn {
+    fn init_up_sql(&self, table: &MigrationTable) -> String {
+        sql(INIT_UP_SQL, table)
+    }
+
+    fn init_down_sql(&self, table: &MigrationTable) -> String {
+        sql(INIT_DOWN_SQL, table)
+    }
+
+    fn load_migrations(&mut self, table: &MigrationTable) -> Result<Vec<Migration>> {
+        let has_columns: Option<bool> = self.query_first(sql(HAS_METADATA_COLUMNS, table))?;
+        let query = if has_columns == Some(true) {
+            LOAD_MIGRATIONS
+        } else {
+            LOAD_LEGACY_MIGRATIONS
+        };
+        let mut migrations = Vec::new();
+        let rows = self.query_map(
+            sql(query, table),
+            |(name, hash, down_sql, applied_at_ms, applied_by, duration_ms, movine_version)| {
+                MigrationRow {
+                    name,
+                    hash,
+                    down_sql,
+                    applied_at_ms,
+                    applied_by,
+                    duration_ms,
+                    movine_version,
+                }
+            },
+        )?;
+        for row in rows {
+            migrations.push(row.into_migration()?);
+        }
+        Ok(migrations)
+    }
+
+    fn load_history(&mut self, table: &MigrationTable) -> Result<Vec<HistoryEntry>> {
+        let has_history: Option<bool> = self.query_first(sql(HAS_HISTORY_TABLE, table))?;
+        if has_history != Some(true) {
+            return Ok(Vec::new());
+        }
+        let mut entries = Vec::new();
+        let rows = self.query_map(
+            sql(LOAD_HISTORY, table),
+            |(
+                name,
+                direction,
+                hash,
+                actor,
+                outcome,
+                recorded_at_ms,
+                duration_ms,
+                movine_version,
+            )| {
+                HistoryRow {
+                    name,
+                    direction,
+                    hash,
+                    actor,
+                    outcome,
+                    recorded_at_ms,
+                    duration_ms,
+                    movine_version,
+                }
+            },
+        )?;
+        for row in rows {
+            entries.push(row.into_entry()?);
+        }
+        Ok(entries)
+    }
+
+    fn run_up_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()> {
+        let result = if migration.transactional {
+            self.start_transaction(TxOpts::default())
+                .map_err(Error::from)
+                .and_then(|mut transaction| {
+                    up_migration(&mut transaction, table, migration)?;
+                    Ok(transaction.commit()?)
+                })
+        } else {
+            up_migration(self, table, migration)
+        };
+        if result.is_err() {
+            log_failure(self, table, Step::Up, migration);
+        }
+        result
+    }
+
+    fn run_down_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()> {
+        let result = if migration.transactional {
+            self.start_transaction(TxOpts::default())
+                .map_err(Error::from)
+                .and_then(|mut transaction| {
+                    down_migration(&mut transaction, table, migration)?;
+                    Ok(transaction.commit()?)
+                })
+        } else {
+            down_migration(self, table, migration)
+        };
+        if result.is_err() {
+            log_failure(self, table, Step::Down, migration);
+        }
+        result
+    }
+
+    fn update_migration_hash(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        hash: &str,
+    ) -> Result<()> {
+        self.exec_drop(sql(UPDATE_MIGRATION_HASH, table), (hash, name))?;
+        Ok(())
+    }
+
+    fn update_migration_down_sql(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        down_sql: &str,
+    ) -> Result<()> {
+        self.exec_drop(sql(UPDATE_MIGRATION_DOWN_SQL, table), (down_sql, name))?;
+        Ok(())
+    }
+
+    fn baseline_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()> {
+        let mut transaction = self.start_transaction(TxOpts::default())?;
+        log_up_migration(&mut transaction, table, migration, None)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn upgrade_schema(&mut self, table: &MigrationTable) -> Result<()> {
+        upgrade_tables(self, table)
+    }
+
+    fn acquire_lock(&mut self, table: &MigrationTable, timeout: Option<Duration>) -> Result<()> {
+        wait_for_lock(timeout, || {
+            let acquired: Option<Option<i64>> = self.query_first(sql(TRY_ACQUIRE_LOCK, table))?;
+            Ok(acquired.flatten() == Some(1))
+        })
+    }
+
+    fn release_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        self.query_drop(sql(RELEASE_LOCK, table))?;
+        Ok(())
+    }
+
+    fn load_schema(&mut self, table: &MigrationTable) -> Result<Vec<SchemaObject>> {
+        let objects: Vec<(String, bool)> = self.query(sql(LIST_SCHEMA_OBJECTS, table))?;
+        let mut schema = Vec::new();
+        for (name, is_view) in objects {
+            let kind = if is_view { "VIEW" } else { "TABLE" };
+            let row: Option<Row> = self.query_first(format!("SHOW CREATE {} `{}`", kind, name))?;
+            let ddl: String = row.and_then(|x| x.get(1)).ok_or(Error::Unknown)?;
+            // The next auto-increment value changes with the data, not the schema.
+            let ddl: Vec<&str> = ddl
+                .split(' ')
+                .filter(|x| !x.starts_with("AUTO_INCREMENT="))
+                .collect();
+            schema.push(SchemaObject {
+                kind: kind.to_lowercase(),
+                name,
+                sql: ddl.join(" ") + ";",
+            });
+        }
+        Ok(schema)
+    }
+}
+
+fn upgrade_tables<Q: Queryable>(client: &mut Q, table: &MigrationTable) -> Result<()> {
+    let is_current: Option<bool> = client.query_first(sql(SCHEMA_IS_CURRENT, table))?;
+    if is_current != Some(true) {
+        info!("Upgrading the Movine tables");
+        let has_columns: Option<bool> = client.query_first(sql(HAS_METADATA_COLUMNS, table))?;
+        if has_columns != Some(true) {
+            client.query_drop(sql(UPGRADE_MIGRATIONS_TABLE, table))?;
+        }
+        client.query_drop(sql(CREATE_HISTORY_TABLE, table))?;
+    }
+    Ok(())
+}
+
+fn up_migration<Q: Queryable>(
+    client: &mut Q,
+    table: &MigrationTable,
+    migration: &Migration,
+) -> Result<()> {
+    let name = &migration.name;
+    let up_sql = migration.up_sql.as_ref().ok_or(Error::BadMigration)?;
+
+    let start = Instant::now();
+    // MySQL doesn't say where in a batch a statement failed.
+    run_sql(
+        migration,
+        Step::Up,
+        up_sql,
+        Dialect::Mysql,
+        |x| client.query_drop(x),
+        |_, _| None,
+    )?;
+    let duration_ms = start.elapsed().as_millis() as i64;
+    if name == INIT_MIGRATION {
+        // Init migrations written by older versions of Movine create older tables, which need
+        // upgrading before the migration can be recorded.
+        upgrade_tables(client, table)?;
+    }
+    log_up_migration(client, table, migration, Some(duration_ms))
+}
+
+fn log_up_migration<Q: Queryable>(
+    client: &mut Q,
+    table: &MigrationTable,
+    migration: &Migration,
+    duration_ms: Option<i64>,
+) -> Result<()> {
+    let name = &migration.name;
+    let hash = migration.hash.as_ref().ok_or(Error::BadMigration)?;
+    let down_sql = migration.down_sql.as_deref().unwrap_or("");
+
+    client.exec_drop(
+        sql(LOG_UP_MIGRATION, table),
+        (
+            name.as_str(),
+            hash.as_str(),
+            down_sql,
+            migration.applied_by.as_deref(),
+            duration_ms,
+            MOVINE_VERSION,
+        ),
+    )?;
+    log_history(
+        client,
+        table,
+        Step::Up,
+        migration,
+        Outcome::Success,
+        duration_ms,
+    )
+}
+
+fn down_migration<Q: Queryable>(
+    client: &mut Q,
+    table: &MigrationTable,
+    migration: &Migration,
+) -> Result<()> {
+    let name = &migration.name;
+    let down_sql = migration.down_sql.as_ref().ok_or(Error::BadMigration)?;
+
+    let start = Instant::now();
+    run_sql(
+        migration,
+        Step::Down,
+        down_sql,
+        Dialect::Mysql,
+        |x| client.query_drop(x),
+        |_, _| None,
+    )?;
+    let duration_ms = start.elapsed().as_millis() as i64;
+    client.exec_drop(sql(LOG_DOWN_MIGRATION, table), (name.as_str(),))?;
+    log_history(
+        client,
+        table,
+        Step::Down,
+        migration,
+        Outcome::Success,
+        Some(duration_ms),
+    )
+}
+
+fn log_history<Q: Queryable>(
+    client: &mut Q,
+    table: &MigrationTable,
+    step: Step,
+    migration: &Migration,
+    outcome: Outcome,
+    duration_ms: Option<i64>,
+) -> Result<()> {
+    client.exec_drop(
+        sql(LOG_HISTORY, table),
+        (
+            migration.name.as_str(),
+            step.as_str(),
+            migration.hash.as_deref(),
+            migration.applied_by.as_deref(),
+            outcome.as_str(),
+            duration_ms,
+            MOVINE_VERSION,
+        ),
+    )?;
+    Ok(())
+}
+
+/// Recording the failure is best effort, since there may not be a history table yet.
+fn log_failure<Q: Queryable>(
+    client: &mut Q,
+    table: &MigrationTable,
+    step: Step,
+    migration: &Migration,
+) {
+    if let Err(e) = log_history(client, table, step, migration, Outcome::Failure, None) {
+        debug!("Unable to record the failure in the history: {}", e);
+    }
+}
+
+/// Fill in the table names in one of the SQL templates below. Tables without a schema live in
+/// the connection's current database.
+fn sql(template: &str, table: &MigrationTable) -> String {
+    let schema = match table.schema() {
+        Some(schema) => format!("'{}'", schema),
+        None => "DATABASE()".to_owned(),
+    };
+    template
+        .replace("{table}", &table.qualified_name())
+        .replace("{history}", &table.qualified_history_name())
+        .replace("{table_name}", table.name())
+        .replace("{history_name}", &table.history_name())
+        .replace("{schema}", &schema)
+}
+
+pub const LOAD_MIGRATIONS: &str = "\
+SELECT
+    name,
+    hash,
+    down_sql,
+    CAST(UNIX_TIMESTAMP(created_at) * 1000 AS SIGNED),
+    applied_by,
+    duration_ms,
+    movine_version
+FROM {table}
+ORDER BY created_at DESC, id DESC;
+";
+
+/// For tables created by older versions of Movine, which only get upgraded by commands that
+/// write to them.
+pub const LOAD_LEGACY_MIGRATIONS: &str = "\
+SELECT
+    name,
+    hash,
+    down_sql,
+    CAST(UNIX_TIMESTAMP(created_at) * 1000 AS SIGNED),
+    NULL,
+    NULL,
+    NULL
+FROM {table}
+ORDER BY created_at DESC, id DESC;
+";
+
+pub const LOG_UP_MIGRATION: &str = "\
+INSERT INTO {table} (name, hash, down_sql, applied_by, duration_ms, movine_version)
+VALUES (?, ?, ?, COALESCE(?, CURRENT_USER()), ?, ?);
+";
+
+pub const LOG_DOWN_MIGRATION: &str = "\
+DELETE FROM {table}
+WHERE name = ?;
+";
+
+pub const UPDATE_MIGRATION_HASH: &str = "\
+UPDATE {table}
+SET hash = ?, updated_at = CURRENT_TIMESTAMP(6)
+WHERE name = ?;
+";
+
+pub const UPDATE_MIGRATION_DOWN_SQL: &str = "\
+UPDATE {table}
+SET down_sql = ?, updated_at = CURRENT_TIMESTAMP(6)
+WHERE name = ?;
+";
+
+pub const LOAD_HISTORY: &str = "\
+SELECT
+    name,
+    direction,
+    hash,
+    actor,
+    outcome,
+    CAST(UNIX_TIMESTAMP(created_at) * 1000 AS SIGNED),
+    duration_ms,
+    movine_version
+FROM {history}
+ORDER BY id;
+";
+
+pub const LOG_HISTORY: &str = "\
+INSERT INTO {history}
+    (name, direction, hash, actor, outcome, duration_ms, movine_version)
+VALUES (?, ?, ?, COALESCE(?, CURRENT_USER()), ?, ?, ?);
+";
+
+pub const SCHEMA_IS_CURRENT: &str = "\
+SELECT EXISTS (
+    SELECT 1 FROM information_schema.columns
+    WHERE table_schema = {schema}
+    AND table_name = '{table_name}'
+    AND column_name = 'movine_version'
+) AND EXISTS (
+    SELECT 1 FROM information_schema.tables
+    WHERE table_schema = {schema}
+    AND table_name = '{history_name}'
+);
+";
+
+pub const HAS_METADATA_COLUMNS: &str = "\
+SELECT EXISTS (
+    SELECT 1 FROM information_schema.columns
+    WHERE table_schema = {schema}
+    AND table_name = '{table_name}'
+    AND column_name = 'movine_version'
+);
+";
+
+pub const HAS_HISTORY_TABLE: &str = "\
+SELECT EXISTS (
+    SELECT 1 FROM information_schema.tables
+    WHERE table_schema = {schema}
+    AND table_name = '{history_name}'
+);
+";
+
+pub const UPGRADE_MIGRATIONS_TABLE: &str = "\
+ALTER TABLE {table}
+    ADD COLUMN applied_by TEXT,
+    ADD COLUMN duration_ms BIGINT,
+    ADD COLUMN movine_version TEXT;
+";
+
+pub const CREATE_HISTORY_TABLE: &str = "\
+CREATE TABLE IF NOT EXISTS {history} (
+    id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    created_at TIMESTAMP(6) DEFAULT CURRENT_TIMESTAMP(6),
+    name TEXT NOT NULL,
+    direction TEXT NOT NULL,
+    hash TEXT,
+    actor TEXT,
+    outcome TEXT NOT NULL,
+    duration_ms BIGINT,
+    movine_version TEXT
+);
+";
+
+// Named locks are per-server rather than per-database, so name the lock after the database and
+// table to keep it distinct from other databases' migrations and from the application's locks.
+pub const TRY_ACQUIRE_LOCK: &str = "\
+SELECT GET_LOCK(CONCAT({schema}, '.{table_name}'), 0);
+";
+
+pub const RELEASE_LOCK: &str = "\
+SELECT RELEASE_LOCK(CONCAT({schema}, '.{table_name}'));
+";
+
+pub const INIT_UP_SQL: &str = "\
+CREATE TABLE {table} (
+    id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    created_at TIMESTAMP(6) DEFAULT CURRENT_TIMESTAMP(6),
+    updated_at TIMESTAMP(6) DEFAULT CURRENT_TIMESTAMP(6),
+    name TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    down_sql LONGTEXT,
+    applied_by TEXT,
+    duration_ms BIGINT,
+    movine_version TEXT
+);
+
+CREATE TABLE {history} (
+    id INTEGER NOT NULL AUTO_INCREMENT PRIMARY KEY,
+    created_at TIMESTAMP(6) DEFAULT CURRENT_TIMESTAMP(6),
+    name TEXT NOT NULL,
+    direction TEXT NOT NULL,
+    hash TEXT,
+    actor TEXT,
+    outcome TEXT NOT NULL,
+    duration_ms BIGINT,
+    movine_version TEXT
+);
+";
+
+pub const INIT_DOWN_SQL: &str = "\
+DROP TABLE {history};
+DROP TABLE {table};
+";
+
+pub const LIST_SCHEMA_OBJECTS: &str = "\
+SELECT table_name, table_type = 'VIEW'
+FROM information_schema.tables
+WHERE table_schema = DATABASE()
+    AND ({schema} <> DATABASE() OR table_name NOT IN ('{table_name}', '{history_name}'))
+ORDER BY table_type = 'VIEW', table_name;
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migration::MigrationBuilder;
+
+    /// Connect to the server in `MYSQL_URL`. The tests that need one are ignored by default, so
+    /// run them with `MYSQL_URL=mysql://... cargo test --features mysql -- --ignored`.
+    fn connect() -> Conn {
+        let url = std::env::var("MYSQL_URL").expect("MYSQL_URL should be set for MySQL tests");
+        Conn::new(mysql::Opts::from_url(&url).unwrap()).unwrap()
+    }
+
+    #[test]
+    /// Lock names are shared by the whole server, so they should include the database.
+    fn test_lock_sql() {
+        let table = MigrationTable::default();
+        assert_eq!(
+            sql(TRY_ACQUIRE_LOCK, &table),
+            "SELECT GET_LOCK(CONCAT(DATABASE(), '.movine_migrations'), 0);\n"
+        );
+        let table = MigrationTable::new(Some("app"), "app_migrations").unwrap();
+        assert_eq!(
+            sql(RELEASE_LOCK, &table),
+            "SELECT RELEASE_LOCK(CONCAT('app', '.app_migrations'));\n"
+        );
+    }
+
+    #[test]
+    #[ignore]
+    /// Only one connection should hold the lock at a time.
+    fn test_lock() {
+        let table = MigrationTable::default();
+        let mut first = connect();
+        let mut second = connect();
+
+        first.acquire_lock(&table, None).unwrap();
+        match second.acquire_lock(&table, Some(Duration::from_millis(0))) {
+            Err(Error::LockTimeout) => {}
+            x => panic!("expected LockTimeout, got {:?}", x),
+        }
+        first.release_lock(&table).unwrap();
+        second
+            .acquire_lock(&table, Some(Duration::from_millis(0)))
+            .unwrap();
+        second.release_lock(&table).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    /// Running a migration up and down should record both steps and leave the schema as it was.
+    fn test_round_trip() {
+        let table = MigrationTable::new(None, "movine_test_migrations").unwrap();
+        let mut conn = connect();
+        conn.query_drop(format!(
+            "DROP TABLE IF EXISTS {}, {}, movine_test_t;",
+            table.qualified_history_name(),
+            table.qualified_name()
+        ))
+        .unwrap();
+        let init = MigrationBuilder::new()
+            .compound_name(INIT_MIGRATION)
+            .up_sql(&conn.init_up_sql(&table))
+            .down_sql(&conn.init_down_sql(&table))
+            .build()
+            .unwrap();
+        let migration = MigrationBuilder::new()
+            .compound_name("2020-01-02-030405_test")
+            .up_sql("CREATE TABLE movine_test_t (id INTEGER);")
+            .down_sql("DROP TABLE movine_test_t;")
+            .build()
+            .unwrap();
+
+        conn.run_up_migration(&table, &init).unwrap();
+        let before = conn.dump_schema(&table).unwrap();
+        conn.run_up_migration(&table, &migration).unwrap();
+        assert_eq!(conn.load_migrations(&table).unwrap().len(), 2);
+        conn.run_down_migration(&table, &migration).unwrap();
+
+        assert_eq!(conn.load_migrations(&table).unwrap().len(), 1);
+        assert_eq!(conn.load_history(&table).unwrap().len(), 3);
+        assert_eq!(conn.dump_schema(&table).unwrap(), before);
+        conn.query_drop(sql(INIT_DOWN_SQL, &table)).unwrap();
+    }
+}
@@ -1,85 +1,685 @@
-use crate::adaptor::DbAdaptor;
+use crate::adaptor::{
+    run_sql, wait_for_lock, DbAdaptor, HistoryRow, MigrationRow, MigrationTable, INIT_MIGRATION,
+    MOVINE_VERSION,
+};
+use crate::display;
 use crate::errors::{Error, Result};
-use crate::migration::{Migration, MigrationBuilder};
+use crate::history::{HistoryEntry, Outcome};
+use crate::migration::Migration;
+use crate::plan_builder::Step;
+use crate::schema::SchemaObject;
+use crate::statements::{self, Dialect, Statement};
+use postgres::error::ErrorPosition;
+use postgres::{GenericClient, Row};
+use std::time::{Duration, Instant};
 
 impl DbAdaptor for postgres::Client {
-    fn init_up_sql(&self) -> &'static str {
-        INIT_UP_SQL
-    }
-
-    fn init_down_sql(&self) -> &'static str {
-        INIT_DOWN_SQL
-    }
-
-    fn load_migrations(&mut self) -> Result<Vec<Migration>> {
-        let mut migrations = Vec::new();
-        let sql = "
-            SELECT name, hash, down_sql
-            FROM movine_migrations
-            ORDER BY created_at DESC;
-        ";
-        let rows = self.query(sql, &[])?;
-        for row in &rows {
-            let name: String = row.get(0);
-            let hash: String = row.get(1);
-            let down_sql: String = row.get(2);
-            let migration = MigrationBuilder::new()
-                .compound_name(&name)
-                .hash(&hash)
-                .down_sql(&down_sql)
-                .build()?;
-            migrations.push(migration);
+    fn init_up_sql(&self, table: &MigrationTable) -> String {
+        sql(INIT_UP_SQL, table)
+    }
+
+    fn init_down_sql(&self, table: &MigrationTable) -> String {
+        sql(INIT_DOWN_SQL, table)
+    }
+
+    fn load_migrations(&mut self, table: &MigrationTable) -> Result<Vec<Migration>> {
+        let row = self.query_one(&sql(HAS_METADATA_COLUMNS, table), &[])?;
+        let query = if row.get(0) {
+            LOAD_MIGRATIONS
+        } else {
+            LOAD_LEGACY_MIGRATIONS
+        };
+        let rows = self.query(&sql(query, table), &[])?;
+        rows.iter().map(migration_from_row).collect()
+    }
+
+    fn load_history(&mut self, table: &MigrationTable) -> Result<Vec<HistoryEntry>> {
+        let row = self.query_one(&sql(HAS_HISTORY_TABLE, table), &[])?;
+        if !row.get::<_, bool>(0) {
+            return Ok(Vec::new());
+        }
+        let rows = self.query(&sql(LOAD_HISTORY, table), &[])?;
+        rows.iter().map(history_entry_from_row).collect()
+    }
+
+    fn run_up_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()> {
+        let result = if migration.transactional {
+            self.transaction()
+                .map_err(Error::from)
+                .and_then(|mut transaction| {
+                    up_migration(&mut transaction, table, migration)?;
+                    Ok(transaction.commit()?)
+                })
+        } else {
+            up_migration(self, table, migration)
+        };
+        if result.is_err() {
+            log_failure(self, table, Step::Up, migration);
         }
-        Ok(migrations)
+        result
     }
 
-    fn run_up_migration(&mut self, migration: &Migration) -> Result<()> {
-        let name = &migration.name;
-        let hash = migration.hash.as_ref().ok_or(Error::BadMigration)?;
-        let up_sql = migration.up_sql.as_ref().ok_or(Error::BadMigration)?;
-        let empty_string = "".to_string();
-        let down_sql = migration.down_sql.as_ref().unwrap_or(&empty_string);
+    fn run_down_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()> {
+        let result = if migration.transactional {
+            self.transaction()
+                .map_err(Error::from)
+                .and_then(|mut transaction| {
+                    down_migration(&mut transaction, table, migration)?;
+                    Ok(transaction.commit()?)
+                })
+        } else {
+            down_migration(self, table, migration)
+        };
+        if result.is_err() {
+            log_failure(self, table, Step::Down, migration);
+        }
+        result
+    }
 
+    fn run_atomic_migration_plan(
+        &mut self,
+        table: &MigrationTable,
+        plan: &[(Step, &Migration)],
+    ) -> Result<()> {
         let mut transaction = self.transaction()?;
-        transaction.batch_execute(&up_sql)?;
-        transaction.execute(LOG_UP_MIGRATION, &[&name, &hash, &down_sql])?;
+        for (step, migration) in plan {
+            display::print_step(&(*step, migration));
+            let result = match step {
+                Step::Up => up_migration(&mut transaction, table, migration),
+                Step::Down if migration.is_reversable() => {
+                    down_migration(&mut transaction, table, migration)
+                }
+                Step::Down => Ok(()),
+            };
+            if let Err(e) = result {
+                // Roll back before recording the failure so the record isn't rolled back too.
+                drop(transaction);
+                log_failure(self, table, *step, migration);
+                return Err(e);
+            }
+        }
         transaction.commit()?;
         Ok(())
     }
 
-    fn run_down_migration(&mut self, migration: &Migration) -> Result<()> {
-        let name = &migration.name;
-        let down_sql = migration.down_sql.as_ref().ok_or(Error::BadMigration)?;
+    fn update_migration_hash(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        hash: &str,
+    ) -> Result<()> {
+        self.execute(&sql(UPDATE_MIGRATION_HASH, table), &[&name, &hash])?;
+        Ok(())
+    }
+
+    fn update_migration_down_sql(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        down_sql: &str,
+    ) -> Result<()> {
+        self.execute(&sql(UPDATE_MIGRATION_DOWN_SQL, table), &[&name, &down_sql])?;
+        Ok(())
+    }
 
+    fn baseline_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()> {
         let mut transaction = self.transaction()?;
-        transaction.batch_execute(&down_sql)?;
-        transaction.execute(LOG_DOWN_MIGRATION, &[&name])?;
+        log_up_migration(&mut transaction, table, migration, None)?;
         transaction.commit()?;
         Ok(())
     }
+
+    fn upgrade_schema(&mut self, table: &MigrationTable) -> Result<()> {
+        upgrade_tables(self, table)
+    }
+
+    fn acquire_lock(&mut self, table: &MigrationTable, timeout: Option<Duration>) -> Result<()> {
+        wait_for_lock(timeout, || {
+            let row = self.query_one(&sql(TRY_ACQUIRE_LOCK, table), &[])?;
+            Ok(row.get(0))
+        })
+    }
+
+    fn release_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        self.execute(&sql(RELEASE_LOCK, table), &[])?;
+        Ok(())
+    }
+
+    fn load_schema(&mut self, table: &MigrationTable) -> Result<Vec<SchemaObject>> {
+        let rows = self.query(&sql(LOAD_SCHEMA, table), &[])?;
+        Ok(rows.iter().map(schema_object_from_row).collect())
+    }
+}
+
+fn up_migration<C: GenericClient>(
+    client: &mut C,
+    table: &MigrationTable,
+    migration: &Migration,
+) -> Result<()> {
+    let name = &migration.name;
+    let up_sql = migration.up_sql.as_ref().ok_or(Error::BadMigration)?;
+
+    let start = Instant::now();
+    run_sql(
+        migration,
+        Step::Up,
+        up_sql,
+        Dialect::Postgres,
+        |x| client.batch_execute(x),
+        |statements, e| failed_statement(up_sql, statements, e),
+    )?;
+    let duration_ms = start.elapsed().as_millis() as i64;
+    if name == INIT_MIGRATION {
+        // Init migrations written by older versions of Movine create older tables, which need
+        // upgrading before the migration can be recorded.
+        upgrade_tables(client, table)?;
+    }
+    log_up_migration(client, table, migration, Some(duration_ms))
+}
+
+fn log_up_migration<C: GenericClient>(
+    client: &mut C,
+    table: &MigrationTable,
+    migration: &Migration,
+    duration_ms: Option<i64>,
+) -> Result<()> {
+    let name = &migration.name;
+    let hash = migration.hash.as_ref().ok_or(Error::BadMigration)?;
+    let empty_string = "".to_string();
+    let down_sql = migration.down_sql.as_ref().unwrap_or(&empty_string);
+
+    client.execute(
+        &sql(LOG_UP_MIGRATION, table),
+        &[
+            &name,
+            &hash,
+            &down_sql,
+            &migration.applied_by,
+            &duration_ms,
+            &MOVINE_VERSION,
+        ],
+    )?;
+    log_history(
+        client,
+        table,
+        Step::Up,
+        migration,
+        Outcome::Success,
+        duration_ms,
+    )
+}
+
+fn down_migration<C: GenericClient>(
+    client: &mut C,
+    table: &MigrationTable,
+    migration: &Migration,
+) -> Result<()> {
+    let name = &migration.name;
+    let down_sql = migration.down_sql.as_ref().ok_or(Error::BadMigration)?;
+
+    let start = Instant::now();
+    run_sql(
+        migration,
+        Step::Down,
+        down_sql,
+        Dialect::Postgres,
+        |x| client.batch_execute(x),
+        |statements, e| failed_statement(down_sql, statements, e),
+    )?;
+    let duration_ms = start.elapsed().as_millis() as i64;
+    client.execute(&sql(LOG_DOWN_MIGRATION, table), &[&name])?;
+    log_history(
+        client,
+        table,
+        Step::Down,
+        migration,
+        Outcome::Success,
+        Some(duration_ms),
+    )
+}
+
+fn upgrade_tables<C: GenericClient>(client: &mut C, table: &MigrationTable) -> Result<()> {
+    let row = client.query_one(&sql(SCHEMA_IS_CURRENT, table), &[])?;
+    if !row.get::<_, bool>(0) {
+        info!("Upgrading the Movine tables");
+        client.batch_execute(&sql(UPGRADE_SCHEMA, table))?;
+    }
+    Ok(())
+}
+
+fn log_history<C: GenericClient>(
+    client: &mut C,
+    table: &MigrationTable,
+    step: Step,
+    migration: &Migration,
+    outcome: Outcome,
+    duration_ms: Option<i64>,
+) -> Result<()> {
+    client.execute(
+        &sql(LOG_HISTORY, table),
+        &[
+            &migration.name,
+            &step.as_str(),
+            &migration.hash,
+            &migration.applied_by,
+            &outcome.as_str(),
+            &duration_ms,
+            &MOVINE_VERSION,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Recording the failure is best effort, since there may not be a history table yet.
+fn log_failure<C: GenericClient>(
+    client: &mut C,
+    table: &MigrationTable,
+    step: Step,
+    migration: &Migration,
+) {
+    if let Err(e) = log_history(client, table, step, migration, Outcome::Failure, None) {
+        debug!("Unable to record the failure in the history: {}", e);
+    }
+}
+
+// The row conversions are shared with the tokio-postgres adaptor, which reads the same queries.
+
+/// The statement of `sql` that a failed batch stopped at, if Postgres said where. It only does
+/// for errors found while parsing or planning a statement, not for ones raised running it.
+pub fn failed_statement(sql: &str, statements: &[Statement], error: &Error) -> Option<usize> {
+    let position = match error {
+        Error::PgError(e) => e.as_db_error()?.position()?,
+        _ => return None,
+    };
+    let position = match position {
+        ErrorPosition::Original(position) => *position as usize,
+        ErrorPosition::Internal { .. } => return None,
+    };
+    // Positions count characters rather than bytes, from 1.
+    let (offset, _) = sql.char_indices().nth(position.checked_sub(1)?)?;
+    statements::containing(statements, offset)
+}
+
+/// Read a row of `LOAD_MIGRATIONS`.
+pub fn migration_from_row(row: &Row) -> Result<Migration> {
+    MigrationRow {
+        name: row.get(0),
+        hash: row.get(1),
+        down_sql: row.get(2),
+        applied_at_ms: row.get(3),
+        applied_by: row.get(4),
+        duration_ms: row.get(5),
+        movine_version: row.get(6),
+    }
+    .into_migration()
+}
+
+/// Read a row of `LOAD_HISTORY`.
+pub fn history_entry_from_row(row: &Row) -> Result<HistoryEntry> {
+    HistoryRow {
+        name: row.get(0),
+        direction: row.get(1),
+        hash: row.get(2),
+        actor: row.get(3),
+        outcome: row.get(4),
+        recorded_at_ms: row.get(5),
+        duration_ms: row.get(6),
+        movine_version: row.get(7),
+    }
+    .into_entry()
+}
+
+/// Read a row of `LOAD_SCHEMA`.
+pub fn schema_object_from_row(row: &Row) -> SchemaObject {
+    SchemaObject {
+        kind: row.get(0),
+        name: row.get(1),
+        sql: row.get(2),
+    }
 }
 
+/// Fill in the table names in one of the SQL templates below.
+pub fn sql(template: &str, table: &MigrationTable) -> String {
+    template
+        .replace("{table}", &table.qualified_name())
+        .replace("{history}", &table.qualified_history_name())
+}
+
+pub const LOAD_MIGRATIONS: &str = "\
+SELECT
+    name,
+    hash,
+    down_sql,
+    (EXTRACT(EPOCH FROM created_at::TIMESTAMPTZ) * 1000)::BIGINT,
+    applied_by,
+    duration_ms,
+    movine_version
+FROM {table}
+ORDER BY created_at DESC;
+";
+
+/// For tables created by older versions of Movine, which only get upgraded by commands that
+/// write to them.
+pub const LOAD_LEGACY_MIGRATIONS: &str = "\
+SELECT
+    name,
+    hash,
+    down_sql,
+    (EXTRACT(EPOCH FROM created_at::TIMESTAMPTZ) * 1000)::BIGINT,
+    NULL::TEXT,
+    NULL::BIGINT,
+    NULL::TEXT
+FROM {table}
+ORDER BY created_at DESC;
+";
+
 pub const LOG_UP_MIGRATION: &str = "\
-INSERT INTO movine_migrations (name, hash, down_sql)
-VALUES ($1, $2, $3);
+INSERT INTO {table} (name, hash, down_sql, applied_by, duration_ms, movine_version)
+VALUES ($1, $2, $3, COALESCE($4::TEXT, current_user::TEXT), $5, $6);
 ";
 
 pub const LOG_DOWN_MIGRATION: &str = "\
-DELETE FROM movine_migrations 
+DELETE FROM {table} 
+WHERE name = $1;
+";
+
+pub const LOAD_HISTORY: &str = "\
+SELECT
+    name,
+    direction,
+    hash,
+    actor,
+    outcome,
+    (EXTRACT(EPOCH FROM created_at::TIMESTAMPTZ) * 1000)::BIGINT,
+    duration_ms,
+    movine_version
+FROM {history}
+ORDER BY id;
+";
+
+pub const LOG_HISTORY: &str = "\
+INSERT INTO {history}
+    (name, direction, hash, actor, outcome, duration_ms, movine_version)
+VALUES ($1, $2, $3, COALESCE($4::TEXT, current_user::TEXT), $5, $6, $7);
+";
+
+pub const UPDATE_MIGRATION_HASH: &str = "\
+UPDATE {table}
+SET hash = $2, updated_at = now()
+WHERE name = $1;
+";
+
+pub const UPDATE_MIGRATION_DOWN_SQL: &str = "\
+UPDATE {table}
+SET down_sql = $2, updated_at = now()
 WHERE name = $1;
 ";
 
+pub const SCHEMA_IS_CURRENT: &str = "\
+SELECT EXISTS (
+    SELECT 1 FROM pg_attribute
+    WHERE attrelid = '{table}'::REGCLASS
+    AND attname = 'movine_version'
+    AND NOT attisdropped
+) AND to_regclass('{history}') IS NOT NULL;
+";
+
+pub const HAS_METADATA_COLUMNS: &str = "\
+SELECT EXISTS (
+    SELECT 1 FROM pg_attribute
+    WHERE attrelid = '{table}'::REGCLASS
+    AND attname = 'movine_version'
+    AND NOT attisdropped
+);
+";
+
+pub const HAS_HISTORY_TABLE: &str = "\
+SELECT to_regclass('{history}') IS NOT NULL;
+";
+
+pub const UPGRADE_SCHEMA: &str = "\
+ALTER TABLE {table}
+    ADD COLUMN IF NOT EXISTS applied_by TEXT,
+    ADD COLUMN IF NOT EXISTS duration_ms BIGINT,
+    ADD COLUMN IF NOT EXISTS movine_version TEXT;
+
+CREATE TABLE IF NOT EXISTS {history} (
+    id SERIAL PRIMARY KEY,
+    created_at TIMESTAMP DEFAULT now(),
+    name TEXT NOT NULL,
+    direction TEXT NOT NULL,
+    hash TEXT,
+    actor TEXT,
+    outcome TEXT NOT NULL,
+    duration_ms BIGINT,
+    movine_version TEXT
+);
+";
+
+// Advisory locks are keyed on the migrations table so unrelated locks don't collide.
+pub const TRY_ACQUIRE_LOCK: &str = "\
+SELECT pg_try_advisory_lock(hashtext('{table}'));
+";
+
+pub const RELEASE_LOCK: &str = "\
+SELECT pg_advisory_unlock(hashtext('{table}'));
+";
+
 pub const INIT_UP_SQL: &str = "\
-CREATE TABLE movine_migrations (
+CREATE TABLE {table} (
     id SERIAL PRIMARY KEY,
     created_at TIMESTAMP DEFAULT now(),
     updated_at TIMESTAMP DEFAULT now(),
     name TEXT NOT NULL,
     hash TEXT NOT NULL,
-    down_sql TEXT
+    down_sql TEXT,
+    applied_by TEXT,
+    duration_ms BIGINT,
+    movine_version TEXT
+);
+
+CREATE TABLE {history} (
+    id SERIAL PRIMARY KEY,
+    created_at TIMESTAMP DEFAULT now(),
+    name TEXT NOT NULL,
+    direction TEXT NOT NULL,
+    hash TEXT,
+    actor TEXT,
+    outcome TEXT NOT NULL,
+    duration_ms BIGINT,
+    movine_version TEXT
 );
 ";
 
 pub const INIT_DOWN_SQL: &str = "\
-DROP TABLE movine_migrations;
+DROP TABLE {history};
+DROP TABLE {table};
 ";
+
+/// Rebuilds DDL for tables, constraints, indexes and views from the catalog, since there may be
+/// no `pg_dump` to hand. Movine's tables are left out.
+pub const LOAD_SCHEMA: &str = "\
+SELECT object_type, qualified_name, ddl FROM (
+    SELECT
+        'table' AS object_type,
+        n.nspname || '.' || c.relname AS qualified_name,
+        n.nspname AS schema_name,
+        c.oid AS table_oid,
+        c.relname AS table_name,
+        c.relkind IN ('v', 'm') AS is_view,
+        0 AS kind,
+        '' AS object_name,
+        'CREATE TABLE ' || quote_ident(n.nspname) || '.' || quote_ident(c.relname) || E' (\\n'
+            || COALESCE((
+                SELECT string_agg(
+                    '    ' || quote_ident(a.attname) || ' ' || format_type(a.atttypid, a.atttypmod)
+                        || CASE WHEN a.attnotnull THEN ' NOT NULL' ELSE '' END
+                        || COALESCE(' DEFAULT ' || pg_get_expr(d.adbin, d.adrelid), ''),
+                    E',\\n' ORDER BY a.attnum
+                )
+                FROM pg_attribute a
+                LEFT JOIN pg_attrdef d ON d.adrelid = a.attrelid AND d.adnum = a.attnum
+                WHERE a.attrelid = c.oid AND a.attnum > 0 AND NOT a.attisdropped
+            ) || E'\\n', '')
+            || ');' AS ddl
+    FROM pg_class c
+    JOIN pg_namespace n ON n.oid = c.relnamespace
+    WHERE c.relkind IN ('r', 'p')
+    UNION ALL
+    SELECT
+        'constraint',
+        n.nspname || '.' || c.relname || '.' || con.conname,
+        n.nspname,
+        c.oid,
+        c.relname,
+        false,
+        1,
+        con.conname,
+        'ALTER TABLE ' || quote_ident(n.nspname) || '.' || quote_ident(c.relname)
+            || ' ADD CONSTRAINT ' || quote_ident(con.conname) || ' '
+            || pg_get_constraintdef(con.oid) || ';'
+    FROM pg_constraint con
+    JOIN pg_class c ON c.oid = con.conrelid
+    JOIN pg_namespace n ON n.oid = c.relnamespace
+    UNION ALL
+    SELECT
+        'index',
+        n.nspname || '.' || i.relname,
+        n.nspname,
+        c.oid,
+        c.relname,
+        false,
+        2,
+        i.relname,
+        pg_get_indexdef(i.oid) || ';'
+    FROM pg_index x
+    JOIN pg_class i ON i.oid = x.indexrelid
+    JOIN pg_class c ON c.oid = x.indrelid
+    JOIN pg_namespace n ON n.oid = c.relnamespace
+    WHERE NOT EXISTS (SELECT 1 FROM pg_constraint con WHERE con.conindid = x.indexrelid)
+    UNION ALL
+    SELECT
+        CASE c.relkind WHEN 'm' THEN 'materialized view' ELSE 'view' END,
+        n.nspname || '.' || c.relname,
+        n.nspname,
+        c.oid,
+        c.relname,
+        true,
+        3,
+        '',
+        CASE c.relkind WHEN 'm' THEN 'CREATE MATERIALIZED VIEW ' ELSE 'CREATE VIEW ' END
+            || quote_ident(n.nspname) || '.' || quote_ident(c.relname) || E' AS\\n'
+            || pg_get_viewdef(c.oid)
+    FROM pg_class c
+    JOIN pg_namespace n ON n.oid = c.relnamespace
+    WHERE c.relkind IN ('v', 'm')
+) objects
+WHERE schema_name NOT IN ('pg_catalog', 'information_schema')
+    AND schema_name NOT LIKE 'pg\\_%'
+    AND table_oid NOT IN (
+        COALESCE(to_regclass('{table}')::OID, 0),
+        COALESCE(to_regclass('{history}')::OID, 0)
+    )
+ORDER BY is_view, schema_name, table_name, kind, object_name;
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migration::MigrationBuilder;
+    use postgres::{Client, NoTls};
+
+    /// Connect to the server in `POSTGRES_URL`. The tests that need one are ignored by default, so
+    /// run them with `POSTGRES_URL=postgres://... cargo test -- --ignored`.
+    fn connect() -> Client {
+        let url =
+            std::env::var("POSTGRES_URL").expect("POSTGRES_URL should be set for Postgres tests");
+        Client::connect(&url, NoTls).unwrap()
+    }
+
+    /// A fresh migrations table, named so as not to touch a real one.
+    fn init(client: &mut Client) -> MigrationTable {
+        let table = MigrationTable::new(None, "movine_test_migrations").unwrap();
+        client
+            .batch_execute(&format!(
+                "DROP TABLE IF EXISTS {}, {}, movine_test_t;",
+                table.qualified_history_name(),
+                table.qualified_name()
+            ))
+            .unwrap();
+        client.batch_execute(&client.init_up_sql(&table)).unwrap();
+        table
+    }
+
+    fn migration(name: &str, up_sql: &str) -> Migration {
+        MigrationBuilder::new()
+            .compound_name(name)
+            .up_sql(up_sql)
+            .down_sql("DROP TABLE movine_test_t;")
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    #[ignore]
+    /// Migrations should run as one batch, and a failure should point at its statement when
+    /// Postgres says where it was.
+    fn test_failed_statement() {
+        let mut client = connect();
+        let table = init(&mut client);
+
+        let unknown_column = migration(
+            "2020-01-02-030405_unknown_column",
+            "CREATE TABLE movine_test_t (id INTEGER, note TEXT DEFAULT 'café');\n\n\
+             SELECT missing FROM movine_test_t;",
+        );
+        assert!(matches!(
+            client.run_up_migration(&table, &unknown_column),
+            Err(Error::MigrationFailed {
+                step: Step::Up,
+                statement_index: Some(1),
+                line: Some(3),
+                ..
+            })
+        ));
+
+        let duplicate = migration(
+            "2020-01-02-030405_duplicate",
+            "CREATE TABLE movine_test_t (id INTEGER PRIMARY KEY);\n\
+             INSERT INTO movine_test_t VALUES (1), (1);",
+        );
+        assert!(matches!(
+            client.run_up_migration(&table, &duplicate),
+            Err(Error::MigrationFailed {
+                statement_index: None,
+                line: None,
+                ..
+            })
+        ));
+
+        let block = migration(
+            "2020-01-02-030405_block",
+            "CREATE TABLE movine_test_t (id INTEGER);\n\
+             DO $$ BEGIN INSERT INTO movine_test_t VALUES (1); END $$;",
+        );
+        client.run_up_migration(&table, &block).unwrap();
+        let row = client
+            .query_one("SELECT COUNT(*) FROM movine_test_t", &[])
+            .unwrap();
+        assert_eq!(row.get::<_, i64>(0), 1);
+        client.run_down_migration(&table, &block).unwrap();
+
+        let history = client.load_history(&table).unwrap();
+        let outcomes: Vec<_> = history.iter().map(|x| x.outcome).collect();
+        assert_eq!(
+            outcomes,
+            [
+                Outcome::Failure,
+                Outcome::Failure,
+                Outcome::Success,
+                Outcome::Success
+            ]
+        );
+        client.batch_execute(&sql(INIT_DOWN_SQL, &table)).unwrap();
+    }
+}
@@ -1,91 +1,1234 @@
-use crate::adaptor::DbAdaptor;
+use crate::adaptor::{
+    run_sql, wait_for_lock, DbAdaptor, HistoryRow, MigrationRow, MigrationTable, INIT_MIGRATION,
+    MOVINE_VERSION,
+};
+use crate::display;
 use crate::errors::{Error, Result};
-use crate::migration::{Migration, MigrationBuilder};
-use rusqlite::{params, Connection};
+use crate::history::{HistoryEntry, Outcome};
+use crate::migration::Migration;
+use crate::plan_builder::Step;
+use crate::schema::SchemaObject;
+use crate::statements::{Dialect, Statement};
+use rusqlite::{params, Connection, ErrorCode, TransactionBehavior};
+use std::time::{Duration, Instant};
 
 impl DbAdaptor for Connection {
-    fn init_up_sql(&self) -> &'static str {
-        INIT_UP_SQL
+    fn init_up_sql(&self, table: &MigrationTable) -> String {
+        sql(INIT_UP_SQL, table)
     }
 
-    fn init_down_sql(&self) -> &'static str {
-        INIT_DOWN_SQL
+    fn init_down_sql(&self, table: &MigrationTable) -> String {
+        sql(INIT_DOWN_SQL, table)
     }
 
-    fn load_migrations(&mut self) -> Result<Vec<Migration>> {
+    fn load_migrations(&mut self, table: &MigrationTable) -> Result<Vec<Migration>> {
+        let has_columns: bool =
+            self.query_row(&sql(HAS_METADATA_COLUMNS, table), params![], |row| {
+                row.get(0)
+            })?;
+        let query = if has_columns {
+            LOAD_MIGRATIONS
+        } else {
+            LOAD_LEGACY_MIGRATIONS
+        };
         let mut migrations = Vec::new();
-        let sql = "
-            SELECT name, hash, down_sql
-            FROM movine_migrations
-            ORDER BY created_at DESC;
-        ";
-        let mut stmt = self.prepare(&sql)?;
-        let rows: std::result::Result<Vec<(String, String, String)>, _> = stmt
-            .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
-            .collect();
-        let rows: Vec<(String, String, String)> = rows.unwrap();
+        let mut stmt = self.prepare(&sql(query, table))?;
+        let rows = stmt.query_map(params![], |row| {
+            Ok(MigrationRow {
+                name: row.get(0)?,
+                hash: row.get(1)?,
+                down_sql: row.get(2)?,
+                applied_at_ms: row.get(3)?,
+                applied_by: row.get(4)?,
+                duration_ms: row.get(5)?,
+                movine_version: row.get(6)?,
+            })
+        })?;
 
         for row in rows {
-            let name: String = row.0;
-            let hash: String = row.1;
-            let down_sql: String = row.2;
-            let migration = MigrationBuilder::new()
-                .compound_name(&name)
-                .hash(&hash)
-                .down_sql(&down_sql)
-                .build()?;
-            migrations.push(migration);
+            migrations.push(row?.into_migration()?);
         }
         Ok(migrations)
     }
 
-    fn run_up_migration(&mut self, migration: &Migration) -> Result<()> {
-        let name = &migration.name;
-        let hash = migration.hash.as_ref().ok_or(Error::BadMigration)?;
-        let up_sql = migration.up_sql.as_ref().ok_or(Error::BadMigration)?;
-        let empty_string = "".to_string();
-        let down_sql = migration.down_sql.as_ref().unwrap_or(&empty_string);
+    fn load_history(&mut self, table: &MigrationTable) -> Result<Vec<HistoryEntry>> {
+        let has_history: bool =
+            self.query_row(&sql(HAS_HISTORY_TABLE, table), params![], |row| row.get(0))?;
+        if !has_history {
+            return Ok(Vec::new());
+        }
+        let mut entries = Vec::new();
+        let mut stmt = self.prepare(&sql(LOAD_HISTORY, table))?;
+        let rows = stmt.query_map(params![], |row| {
+            Ok(HistoryRow {
+                name: row.get(0)?,
+                direction: row.get(1)?,
+                hash: row.get(2)?,
+                actor: row.get(3)?,
+                outcome: row.get(4)?,
+                recorded_at_ms: row.get(5)?,
+                duration_ms: row.get(6)?,
+                movine_version: row.get(7)?,
+            })
+        })?;
+
+        for row in rows {
+            entries.push(row?.into_entry()?);
+        }
+        Ok(entries)
+    }
+
+    fn run_up_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()> {
+        let result = if migration.transactional {
+            self.transaction()
+                .map_err(Error::from)
+                .and_then(|transaction| {
+                    up_migration(&transaction, table, migration)?;
+                    Ok(transaction.commit()?)
+                })
+        } else {
+            up_migration(self, table, migration)
+        };
+        if result.is_err() {
+            log_failure(self, table, Step::Up, migration);
+        }
+        result
+    }
+
+    fn run_down_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()> {
+        let result = if migration.transactional {
+            self.transaction()
+                .map_err(Error::from)
+                .and_then(|transaction| {
+                    down_migration(&transaction, table, migration)?;
+                    Ok(transaction.commit()?)
+                })
+        } else {
+            down_migration(self, table, migration)
+        };
+        if result.is_err() {
+            log_failure(self, table, Step::Down, migration);
+        }
+        result
+    }
 
+    fn run_atomic_migration_plan(
+        &mut self,
+        table: &MigrationTable,
+        plan: &[(Step, &Migration)],
+    ) -> Result<()> {
         let transaction = self.transaction()?;
-        transaction.execute_batch(&up_sql)?;
-        transaction.execute(LOG_UP_MIGRATION, &[&name, &hash, &down_sql])?;
+        for (step, migration) in plan {
+            display::print_step(&(*step, migration));
+            let result = match step {
+                Step::Up => up_migration(&transaction, table, migration),
+                Step::Down if migration.is_reversable() => {
+                    down_migration(&transaction, table, migration)
+                }
+                Step::Down => Ok(()),
+            };
+            if let Err(e) = result {
+                // Roll back before recording the failure so the record isn't rolled back too.
+                drop(transaction);
+                log_failure(self, table, *step, migration);
+                return Err(e);
+            }
+        }
         transaction.commit()?;
         Ok(())
     }
 
-    fn run_down_migration(&mut self, migration: &Migration) -> Result<()> {
-        let name = &migration.name;
-        let down_sql = migration.down_sql.as_ref().ok_or(Error::BadMigration)?;
+    fn update_migration_hash(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        hash: &str,
+    ) -> Result<()> {
+        self.execute(&sql(UPDATE_MIGRATION_HASH, table), &[&name, &hash])?;
+        Ok(())
+    }
 
+    fn update_migration_down_sql(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        down_sql: &str,
+    ) -> Result<()> {
+        self.execute(&sql(UPDATE_MIGRATION_DOWN_SQL, table), &[&name, &down_sql])?;
+        Ok(())
+    }
+
+    fn baseline_migration(&mut self, table: &MigrationTable, migration: &Migration) -> Result<()> {
         let transaction = self.transaction()?;
-        transaction.execute_batch(&down_sql)?;
-        transaction.execute(LOG_DOWN_MIGRATION, &[&name])?;
+        log_up_migration(&transaction, table, migration, None)?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn upgrade_schema(&mut self, table: &MigrationTable) -> Result<()> {
+        // Check again once we hold the write lock, in case another instance got here first.
+        let transaction = self.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        upgrade_tables(&transaction, table)?;
         transaction.commit()?;
         Ok(())
     }
+
+    fn acquire_lock(&mut self, table: &MigrationTable, timeout: Option<Duration>) -> Result<()> {
+        let mut reported = false;
+        wait_for_lock(timeout, || {
+            let acquired = try_lock(self, table)?;
+            if !acquired && !reported {
+                info!("If no other Movine instance is running, the lock was left by one that stopped early. Release it with `movine unlock`");
+                reported = true;
+            }
+            Ok(acquired)
+        })
+    }
+
+    fn release_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        self.execute_batch(&sql(RELEASE_LOCK, table))?;
+        Ok(())
+    }
+
+    fn break_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        self.release_lock(table)
+    }
+
+    fn load_schema(&mut self, table: &MigrationTable) -> Result<Vec<SchemaObject>> {
+        let mut stmt = self.prepare(&sql(LOAD_SCHEMA, table))?;
+        let rows = stmt.query_map(params![], |row| {
+            Ok(SchemaObject {
+                kind: row.get(0)?,
+                name: row.get(1)?,
+                sql: row.get::<_, String>(2)? + ";",
+            })
+        })?;
+        Ok(rows.collect::<std::result::Result<_, _>>()?)
+    }
+}
+
+fn upgrade_tables(conn: &Connection, table: &MigrationTable) -> Result<()> {
+    let is_current: bool =
+        conn.query_row(&sql(SCHEMA_IS_CURRENT, table), params![], |row| row.get(0))?;
+    if !is_current {
+        info!("Upgrading the Movine tables");
+        let has_columns: bool =
+            conn.query_row(&sql(HAS_METADATA_COLUMNS, table), params![], |row| {
+                row.get(0)
+            })?;
+        if !has_columns {
+            conn.execute_batch(&sql(UPGRADE_MIGRATIONS_TABLE, table))?;
+        }
+        conn.execute_batch(&sql(CREATE_HISTORY_TABLE, table))?;
+    }
+    Ok(())
+}
+
+/// Create the lock table unless another instance already has. Another instance writing to the
+/// database at the same time counts as holding the lock.
+fn try_lock(conn: &mut Connection, table: &MigrationTable) -> Result<bool> {
+    let result = conn
+        .transaction_with_behavior(TransactionBehavior::Immediate)
+        .and_then(|transaction| {
+            let is_held: bool =
+                transaction.query_row(&sql(LOCK_IS_HELD, table), params![], |row| row.get(0))?;
+            if !is_held {
+                transaction.execute_batch(&sql(CREATE_LOCK_TABLE, table))?;
+            }
+            transaction.commit()?;
+            Ok(!is_held)
+        });
+    match result {
+        Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::DatabaseBusy => Ok(false),
+        result => Ok(result?),
+    }
+}
+
+// Takes a `Connection` so it can be run either directly or on a `Transaction`.
+fn up_migration(conn: &Connection, table: &MigrationTable, migration: &Migration) -> Result<()> {
+    let name = &migration.name;
+    let up_sql = migration.up_sql.as_ref().ok_or(Error::BadMigration)?;
+
+    let start = Instant::now();
+    run_sql(
+        migration,
+        Step::Up,
+        up_sql,
+        Dialect::Sqlite,
+        |x| conn.execute_batch(x),
+        |statements, e| failed_statement(conn, statements, e),
+    )?;
+    let duration_ms = start.elapsed().as_millis() as i64;
+    if name == INIT_MIGRATION {
+        // Init migrations written by older versions of Movine create older tables, which need
+        // upgrading before the migration can be recorded.
+        upgrade_tables(conn, table)?;
+    }
+    log_up_migration(conn, table, migration, Some(duration_ms))
+}
+
+fn log_up_migration(
+    conn: &Connection,
+    table: &MigrationTable,
+    migration: &Migration,
+    duration_ms: Option<i64>,
+) -> Result<()> {
+    let name = &migration.name;
+    let hash = migration.hash.as_ref().ok_or(Error::BadMigration)?;
+    let empty_string = "".to_string();
+    let down_sql = migration.down_sql.as_ref().unwrap_or(&empty_string);
+
+    conn.execute(
+        &sql(LOG_UP_MIGRATION, table),
+        params![
+            name,
+            hash,
+            down_sql,
+            migration.applied_by,
+            duration_ms,
+            MOVINE_VERSION
+        ],
+    )?;
+    log_history(
+        conn,
+        table,
+        Step::Up,
+        migration,
+        Outcome::Success,
+        duration_ms,
+    )
+}
+
+fn down_migration(conn: &Connection, table: &MigrationTable, migration: &Migration) -> Result<()> {
+    let name = &migration.name;
+    let down_sql = migration.down_sql.as_ref().ok_or(Error::BadMigration)?;
+
+    let start = Instant::now();
+    run_sql(
+        migration,
+        Step::Down,
+        down_sql,
+        Dialect::Sqlite,
+        |x| conn.execute_batch(x),
+        |statements, e| failed_statement(conn, statements, e),
+    )?;
+    let duration_ms = start.elapsed().as_millis() as i64;
+    conn.execute(&sql(LOG_DOWN_MIGRATION, table), &[&name])?;
+    log_history(
+        conn,
+        table,
+        Step::Down,
+        migration,
+        Outcome::Success,
+        Some(duration_ms),
+    )
+}
+
+fn log_history(
+    conn: &Connection,
+    table: &MigrationTable,
+    step: Step,
+    migration: &Migration,
+    outcome: Outcome,
+    duration_ms: Option<i64>,
+) -> Result<()> {
+    conn.execute(
+        &sql(LOG_HISTORY, table),
+        params![
+            migration.name,
+            step.as_str(),
+            migration.hash,
+            migration.applied_by,
+            outcome.as_str(),
+            duration_ms,
+            MOVINE_VERSION
+        ],
+    )?;
+    Ok(())
+}
+
+/// Recording the failure is best effort, since there may not be a history table yet.
+fn log_failure(conn: &Connection, table: &MigrationTable, step: Step, migration: &Migration) {
+    if let Err(e) = log_history(conn, table, step, migration, Outcome::Failure, None) {
+        debug!("Unable to record the failure in the history: {}", e);
+    }
+}
+
+/// The statement of a failed batch that SQLite can't prepare, for the same reason the batch
+/// failed. SQLite doesn't say where in a batch it failed, and errors raised running a statement
+/// rather than preparing it, such as constraint violations, can't be traced this way.
+fn failed_statement(conn: &Connection, statements: &[Statement], error: &Error) -> Option<usize> {
+    let message = match error {
+        Error::SqliteError(e) => e.to_string(),
+        _ => return None,
+    };
+    let (index, prepare_error) = statements
+        .iter()
+        .enumerate()
+        .find_map(|(index, x)| Some((index, conn.prepare(x.sql).err()?)))?;
+    (prepare_error.to_string() == message).then_some(index)
 }
 
+/// Fill in the table names in one of the SQL templates below. Tables without a schema live in
+/// the main database.
+fn sql(template: &str, table: &MigrationTable) -> String {
+    template
+        .replace("{table}", &table.qualified_name())
+        .replace("{history}", &table.qualified_history_name())
+        .replace("{table_name}", table.name())
+        .replace("{history_name}", &table.history_name())
+        .replace("{schema}", table.schema().unwrap_or("main"))
+}
+
+pub const LOAD_MIGRATIONS: &str = "\
+SELECT
+    name,
+    hash,
+    down_sql,
+    CAST(strftime('%s', created_at) AS INTEGER) * 1000,
+    applied_by,
+    duration_ms,
+    movine_version
+FROM {table}
+ORDER BY created_at DESC;
+";
+
+/// For tables created by older versions of Movine, which only get upgraded by commands that
+/// write to them.
+pub const LOAD_LEGACY_MIGRATIONS: &str = "\
+SELECT
+    name,
+    hash,
+    down_sql,
+    CAST(strftime('%s', created_at) AS INTEGER) * 1000,
+    NULL,
+    NULL,
+    NULL
+FROM {table}
+ORDER BY created_at DESC;
+";
+
 pub const LOG_UP_MIGRATION: &str = "\
-INSERT INTO movine_migrations (name, hash, down_sql)
-VALUES ($1, $2, $3);
+INSERT INTO {table} (name, hash, down_sql, applied_by, duration_ms, movine_version)
+VALUES ($1, $2, $3, $4, $5, $6);
 ";
 
 pub const LOG_DOWN_MIGRATION: &str = "\
-DELETE FROM movine_migrations 
+DELETE FROM {table} 
 WHERE name = $1;
 ";
 
+pub const UPDATE_MIGRATION_HASH: &str = "\
+UPDATE {table}
+SET hash = ?2, updated_at = CURRENT_TIMESTAMP
+WHERE name = ?1;
+";
+
+pub const UPDATE_MIGRATION_DOWN_SQL: &str = "\
+UPDATE {table}
+SET down_sql = ?2, updated_at = CURRENT_TIMESTAMP
+WHERE name = ?1;
+";
+
+pub const LOAD_HISTORY: &str = "\
+SELECT
+    name,
+    direction,
+    hash,
+    actor,
+    outcome,
+    CAST(strftime('%s', created_at) AS INTEGER) * 1000,
+    duration_ms,
+    movine_version
+FROM {history}
+ORDER BY id;
+";
+
+pub const LOG_HISTORY: &str = "\
+INSERT INTO {history}
+    (name, direction, hash, actor, outcome, duration_ms, movine_version)
+VALUES ($1, $2, $3, $4, $5, $6, $7);
+";
+
+pub const SCHEMA_IS_CURRENT: &str = "\
+SELECT EXISTS (
+    SELECT 1 FROM pragma_table_info('{table_name}', '{schema}')
+    WHERE name = 'movine_version'
+) AND EXISTS (
+    SELECT 1 FROM {schema}.sqlite_master
+    WHERE type = 'table' AND name = '{history_name}'
+);
+";
+
+pub const HAS_METADATA_COLUMNS: &str = "\
+SELECT EXISTS (
+    SELECT 1 FROM pragma_table_info('{table_name}', '{schema}')
+    WHERE name = 'movine_version'
+);
+";
+
+pub const HAS_HISTORY_TABLE: &str = "\
+SELECT EXISTS (
+    SELECT 1 FROM {schema}.sqlite_master
+    WHERE type = 'table' AND name = '{history_name}'
+);
+";
+
+pub const UPGRADE_MIGRATIONS_TABLE: &str = "\
+ALTER TABLE {table} ADD COLUMN applied_by TEXT;
+ALTER TABLE {table} ADD COLUMN duration_ms INTEGER;
+ALTER TABLE {table} ADD COLUMN movine_version TEXT;
+";
+
+pub const CREATE_HISTORY_TABLE: &str = "\
+CREATE TABLE IF NOT EXISTS {history} (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    name TEXT NOT NULL,
+    direction TEXT NOT NULL,
+    hash TEXT,
+    actor TEXT,
+    outcome TEXT NOT NULL,
+    duration_ms INTEGER,
+    movine_version TEXT
+);
+";
+
+// SQLite has no advisory locks, so the lock is a table that only exists while an instance holds
+// it. A table left behind by an instance that was killed stays until `movine unlock` drops it.
+pub const LOCK_IS_HELD: &str = "\
+SELECT EXISTS (
+    SELECT 1 FROM {schema}.sqlite_master
+    WHERE type = 'table' AND name = 'movine_lock'
+);
+";
+
+pub const CREATE_LOCK_TABLE: &str = "\
+CREATE TABLE {schema}.movine_lock (
+    locked_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+";
+
+pub const RELEASE_LOCK: &str = "\
+DROP TABLE IF EXISTS {schema}.movine_lock;
+";
+
 pub const INIT_UP_SQL: &str = "\
-CREATE TABLE movine_migrations (
+CREATE TABLE {table} (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
     created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
     updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
     name TEXT NOT NULL,
     hash TEXT NOT NULL,
-    down_sql TEXT
+    down_sql TEXT,
+    applied_by TEXT,
+    duration_ms INTEGER,
+    movine_version TEXT
+);
+
+CREATE TABLE {history} (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+    name TEXT NOT NULL,
+    direction TEXT NOT NULL,
+    hash TEXT,
+    actor TEXT,
+    outcome TEXT NOT NULL,
+    duration_ms INTEGER,
+    movine_version TEXT
 );
 ";
 
 pub const INIT_DOWN_SQL: &str = "\
-DROP TABLE movine_migrations;
+DROP TABLE {history};
+DROP TABLE {table};
 ";
+
+pub const LOAD_SCHEMA: &str = "\
+SELECT type, name, sql
+FROM main.sqlite_master
+WHERE sql IS NOT NULL
+    AND name NOT LIKE 'sqlite\\_%' ESCAPE '\\'
+    AND ('{schema}' <> 'main' OR tbl_name NOT IN ('{table_name}', '{history_name}', 'movine_lock'))
+ORDER BY
+    CASE type WHEN 'table' THEN 0 WHEN 'index' THEN 1 WHEN 'trigger' THEN 2 ELSE 3 END,
+    tbl_name,
+    name;
+";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::match_maker::MigrationState;
+    use crate::migration::MigrationBuilder;
+    use crate::plan_builder::OwnedPlan;
+    use chrono::prelude::*;
+
+    #[test]
+    /// Tables created by older versions of Movine should gain the new columns and keep their rows.
+    fn test_upgrade_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE movine_migrations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                name TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                down_sql TEXT
+            );
+            INSERT INTO movine_migrations (created_at, name, hash, down_sql)
+            VALUES ('2020-01-02 03:04:05', 'test', '1234abcd', '');",
+        )
+        .unwrap();
+
+        let table = MigrationTable::default();
+        conn.upgrade_schema(&table).unwrap();
+        conn.upgrade_schema(&table).unwrap();
+
+        let migrations = conn.load_migrations(&table).unwrap();
+        assert_eq!(migrations.len(), 1);
+        assert_eq!(
+            migrations[0].applied_at,
+            Utc.with_ymd_and_hms(2020, 1, 2, 3, 4, 5).single()
+        );
+        assert_eq!(migrations[0].applied_by, None);
+        assert!(conn.load_history(&table).unwrap().is_empty());
+    }
+
+    #[test]
+    /// Commands that only read should work on tables created by older versions of Movine without
+    /// upgrading them.
+    fn test_read_old_tables() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE movine_migrations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                name TEXT NOT NULL,
+                hash TEXT NOT NULL,
+                down_sql TEXT
+            );
+            INSERT INTO movine_migrations (created_at, name, hash, down_sql)
+            VALUES ('2020-01-02 03:04:05', '2020-01-02-030405_test', '1234abcd', '');",
+        )
+        .unwrap();
+        let local = MigrationBuilder::new()
+            .compound_name("2020-01-02-030405_test")
+            .up_sql("CREATE TABLE t (id INTEGER);")
+            .build()
+            .unwrap();
+
+        let mut movine = crate::Movine::with_migrations(&mut conn, vec![local]);
+        let status = movine.get_status().unwrap();
+        assert_eq!(status.len(), 1);
+        assert!(movine.get_history(&Default::default()).unwrap().is_empty());
+        movine.get_plan_up().unwrap();
+
+        let table = MigrationTable::default();
+        let is_current: bool = conn
+            .query_row(&sql(SCHEMA_IS_CURRENT, &table), params![], |row| row.get(0))
+            .unwrap();
+        assert!(!is_current);
+        let migrations = conn.load_migrations(&table).unwrap();
+        assert_eq!(
+            migrations[0].applied_at,
+            Utc.with_ymd_and_hms(2020, 1, 2, 3, 4, 5).single()
+        );
+    }
+
+    #[test]
+    /// Checking for drift shouldn't change anything in the live database, even when its tables
+    /// were created by an older version of Movine.
+    fn test_drift_is_read_only() {
+        let old_init_sql = "CREATE TABLE movine_migrations (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+            name TEXT NOT NULL,
+            hash TEXT NOT NULL,
+            down_sql TEXT
+        );";
+        let local = vec![
+            MigrationBuilder::new()
+                .compound_name("1970-01-01-000000_movine_init")
+                .up_sql(old_init_sql)
+                .down_sql("DROP TABLE movine_migrations;")
+                .build()
+                .unwrap(),
+            MigrationBuilder::new()
+                .compound_name("2020-01-02-030405_test")
+                .up_sql("CREATE TABLE t (id INTEGER);")
+                .down_sql("DROP TABLE t;")
+                .build()
+                .unwrap(),
+        ];
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(old_init_sql).unwrap();
+        conn.execute_batch("CREATE TABLE t (id INTEGER);").unwrap();
+        for migration in &local {
+            conn.execute(
+                "INSERT INTO movine_migrations (name, hash, down_sql) VALUES (?1, ?2, ?3)",
+                params![migration.name, migration.legacy_hash(), migration.down_sql],
+            )
+            .unwrap();
+        }
+        let dump_table = |conn: &Connection| -> Vec<String> {
+            let mut stmt = conn
+                .prepare("SELECT name, hash, updated_at FROM movine_migrations ORDER BY id")
+                .unwrap();
+            let rows = stmt
+                .query_map(params![], |row| {
+                    Ok(format!(
+                        "{} {} {}",
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?
+                    ))
+                })
+                .unwrap();
+            rows.map(|x| x.unwrap()).collect()
+        };
+        let before = dump_table(&conn);
+        let schema_before = conn.dump_schema(&MigrationTable::default()).unwrap();
+
+        let mut movine = crate::Movine::with_migrations(&mut conn, local);
+        let drift = movine
+            .get_drift(Connection::open_in_memory().unwrap())
+            .unwrap();
+        assert!(drift.is_empty());
+
+        assert_eq!(dump_table(&conn), before);
+        let sqlite_master: String = conn
+            .query_row(
+                "SELECT group_concat(sql, ';') FROM sqlite_master ORDER BY name",
+                params![],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert!(!sqlite_master.contains("movine_version"));
+        assert!(!sqlite_master.contains("movine_migrations_history"));
+        assert_eq!(
+            conn.dump_schema(&MigrationTable::default()).unwrap(),
+            schema_before
+        );
+    }
+
+    #[test]
+    /// Verifying should run the pending migrations in the scratch database, skipping any without
+    /// down SQL, and leave the live database as it was.
+    fn test_verify_uses_scratch() {
+        let table = MigrationTable::default();
+        let mut conn = Connection::open_in_memory().unwrap();
+        let init = MigrationBuilder::new()
+            .compound_name("1970-01-01-000000_movine_init")
+            .up_sql(&conn.init_up_sql(&table))
+            .down_sql(&conn.init_down_sql(&table))
+            .build()
+            .unwrap();
+        conn.run_up_migration(&table, &init).unwrap();
+        let local = vec![
+            init,
+            MigrationBuilder::new()
+                .compound_name("2020-01-02-030405_reversable")
+                .up_sql("CREATE TABLE t (id INTEGER);")
+                .down_sql("DROP TABLE t;")
+                .build()
+                .unwrap(),
+            MigrationBuilder::new()
+                .compound_name("2020-01-03-030405_unreversable")
+                .up_sql("CREATE TABLE u (id INTEGER);")
+                .build()
+                .unwrap(),
+        ];
+        let schema_before = conn.dump_schema(&table).unwrap();
+
+        let mut scratch = Connection::open_in_memory().unwrap();
+        let mut movine = crate::Movine::with_migrations(&mut conn, local.clone());
+        movine.verify_reversibility(&mut scratch).unwrap();
+
+        assert_eq!(conn.load_migrations(&table).unwrap().len(), 1);
+        assert_eq!(conn.dump_schema(&table).unwrap(), schema_before);
+        assert_eq!(scratch.load_migrations(&table).unwrap().len(), 3);
+
+        let mut movine = crate::Movine::with_migrations(&mut conn, local);
+        match movine.verify_reversibility(&mut scratch) {
+            Err(Error::ScratchDatabaseNotEmpty) => {}
+            x => panic!("expected ScratchDatabaseNotEmpty, got {:?}", x),
+        }
+    }
+
+    #[test]
+    /// An init migration written by an older version of Movine should still be recorded.
+    fn test_old_init_migration() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let table = MigrationTable::default();
+        let migration = MigrationBuilder::new()
+            .compound_name("1970-01-01-000000_movine_init")
+            .up_sql(
+                "CREATE TABLE movine_migrations (
+                    id INTEGER PRIMARY KEY AUTOINCREMENT,
+                    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+                    name TEXT NOT NULL,
+                    hash TEXT NOT NULL,
+                    down_sql TEXT
+                );",
+            )
+            .down_sql("DROP TABLE movine_migrations;")
+            .build()
+            .unwrap();
+        conn.run_up_migration(&table, &migration).unwrap();
+
+        assert_eq!(conn.load_migrations(&table).unwrap().len(), 1);
+        assert_eq!(conn.load_history(&table).unwrap().len(), 1);
+    }
+
+    #[test]
+    /// Updating a hash or down SQL should only change that migration.
+    fn test_update_migration_hash() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let table = MigrationTable::default();
+        conn.execute_batch(&conn.init_up_sql(&table)).unwrap();
+        let migration = MigrationBuilder::new()
+            .compound_name("2020-01-02-030405_test")
+            .up_sql("CREATE TABLE t (id INTEGER);")
+            .build()
+            .unwrap();
+        conn.baseline_migration(&table, &migration).unwrap();
+        conn.update_migration_hash(&table, &migration.name, "sha256:1234")
+            .unwrap();
+
+        conn.update_migration_down_sql(&table, &migration.name, "DROP TABLE t;")
+            .unwrap();
+
+        let migrations = conn.load_migrations(&table).unwrap();
+        assert_eq!(migrations[0].hash.as_deref(), Some("sha256:1234"));
+        assert_eq!(migrations[0].down_sql.as_deref(), Some("DROP TABLE t;"));
+    }
+
+    #[test]
+    /// Baselining records a migration as applied without running it.
+    fn test_baseline_migration() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let table = MigrationTable::default();
+        conn.execute_batch(&conn.init_up_sql(&table)).unwrap();
+        let migration = MigrationBuilder::new()
+            .compound_name("2020-01-02-030405_test")
+            .up_sql("SELECT * FROM missing;")
+            .build()
+            .unwrap();
+        conn.baseline_migration(&table, &migration).unwrap();
+
+        let migrations = conn.load_migrations(&table).unwrap();
+        assert_eq!(migrations.len(), 1);
+        assert_eq!(migrations[0].hash, migration.hash);
+        assert_eq!(migrations[0].duration_ms, None);
+        assert_eq!(conn.load_history(&table).unwrap().len(), 1);
+    }
+
+    #[test]
+    /// The schema dump should leave out Movine's tables and list tables before indexes.
+    fn test_dump_schema() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let table = MigrationTable::default();
+        conn.execute_batch(&conn.init_up_sql(&table)).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT);
+            CREATE INDEX users_email ON users (email);
+            CREATE TABLE posts (id INTEGER);",
+        )
+        .unwrap();
+
+        assert_eq!(
+            conn.dump_schema(&table).unwrap(),
+            "CREATE TABLE posts (id INTEGER);\n\
+             CREATE TABLE users (id INTEGER PRIMARY KEY, email TEXT);\n\
+             CREATE INDEX users_email ON users (email);\n"
+        );
+        let objects: Vec<_> = conn
+            .load_schema(&table)
+            .unwrap()
+            .into_iter()
+            .map(|x| (x.kind, x.name))
+            .collect();
+        assert_eq!(
+            objects,
+            [
+                ("table".to_string(), "posts".to_string()),
+                ("table".to_string(), "users".to_string()),
+                ("index".to_string(), "users_email".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    /// Ups and downs are both kept in the history, including ones that failed.
+    fn test_history() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let table = MigrationTable::default();
+        conn.execute_batch(&conn.init_up_sql(&table)).unwrap();
+        let migration = MigrationBuilder::new()
+            .compound_name("2020-01-02-030405_test")
+            .up_sql("CREATE TABLE t (id INTEGER);")
+            .down_sql("DROP TABLE t;")
+            .build()
+            .unwrap();
+        let mut broken = migration.clone();
+        broken.down_sql = Some("SELECT 1;\n\nDROP TABLE missing;".into());
+
+        conn.run_up_migration(&table, &migration).unwrap();
+        assert!(matches!(
+            conn.run_down_migration(&table, &broken),
+            Err(Error::MigrationFailed {
+                step: Step::Down,
+                statement_index: Some(1),
+                line: Some(3),
+                ..
+            })
+        ));
+        conn.run_down_migration(&table, &migration).unwrap();
+
+        let history = conn.load_history(&table).unwrap();
+        let steps: Vec<_> = history
+            .iter()
+            .map(|x| (x.direction, x.outcome, x.name.as_str()))
+            .collect();
+        assert_eq!(
+            steps,
+            vec![
+                (Step::Up, Outcome::Success, "2020-01-02-030405_test"),
+                (Step::Down, Outcome::Failure, "2020-01-02-030405_test"),
+                (Step::Down, Outcome::Success, "2020-01-02-030405_test"),
+            ]
+        );
+        assert!(conn.load_migrations(&table).unwrap().is_empty());
+    }
+
+    #[test]
+    /// Migrations should run as one batch whatever the statement split makes of them, and
+    /// failures found only when a statement runs can't say which statement it was.
+    fn test_run_batch() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        let table = MigrationTable::default();
+        conn.execute_batch(&conn.init_up_sql(&table)).unwrap();
+        let migration = MigrationBuilder::new()
+            .compound_name("2020-01-02-030405_test")
+            .up_sql(
+                "-- movine:no-transaction\n\
+                 BEGIN;\n\
+                 CREATE TABLE t (start INTEGER, end INTEGER UNIQUE);\n\
+                 INSERT INTO t (start, end) VALUES (1, 2);\n\
+                 COMMIT;",
+            )
+            .down_sql("DROP TABLE t;")
+            .build()
+            .unwrap();
+        conn.run_up_migration(&table, &migration).unwrap();
+        let end: i64 = conn
+            .query_row("SELECT end FROM t", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(end, 2);
+
+        let mut broken = migration.clone();
+        broken.name = "2020-01-03-030405_broken".into();
+        broken.up_sql = Some("INSERT INTO t VALUES (3, 4);\nINSERT INTO t VALUES (5, 2);".into());
+        broken.transactional = true;
+        assert!(matches!(
+            conn.run_up_migration(&table, &broken),
+            Err(Error::MigrationFailed {
+                step: Step::Up,
+                statement_index: None,
+                line: None,
+                ..
+            })
+        ));
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM t", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    /// Only one connection should hold the lock at a time, and a lock left by one that never
+    /// released it should hold until it's broken with `unlock`.
+    fn test_lock() {
+        let table = MigrationTable::default();
+        let file = std::env::temp_dir().join(format!("movine-lock-{}.db", std::process::id()));
+        let open = || Connection::open(&file).unwrap();
+        let mut first = open();
+        let mut second = open();
+
+        first.acquire_lock(&table, None).unwrap();
+        let blocked = second.acquire_lock(&table, Some(Duration::from_millis(0)));
+        first.release_lock(&table).unwrap();
+        second
+            .acquire_lock(&table, Some(Duration::from_millis(0)))
+            .unwrap();
+        // Stands in for an instance that was killed while holding the lock.
+        drop(second);
+
+        let mut third = open();
+        let left_behind = third.acquire_lock(&table, Some(Duration::from_millis(0)));
+        third.break_lock(&table).unwrap();
+        third
+            .acquire_lock(&table, Some(Duration::from_millis(0)))
+            .unwrap();
+        third.release_lock(&table).unwrap();
+        let schema = third.dump_schema(&table).unwrap();
+        let tables: i64 = third
+            .query_row("SELECT COUNT(*) FROM sqlite_master", params![], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        drop((first, third));
+        std::fs::remove_file(&file).unwrap();
+
+        assert!(matches!(blocked, Err(Error::LockTimeout)));
+        assert!(matches!(left_behind, Err(Error::LockTimeout)));
+        assert_eq!(schema, "");
+        assert_eq!(tables, 0);
+    }
+
+    #[test]
+    /// A table in another schema shouldn't touch the default one.
+    fn test_custom_table() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("ATTACH DATABASE ':memory:' AS app;")
+            .unwrap();
+        let table = MigrationTable::new(Some("app"), "app_migrations").unwrap();
+        conn.execute_batch(&conn.init_up_sql(&table)).unwrap();
+        let migration = MigrationBuilder::new()
+            .compound_name("2020-01-02-030405_test")
+            .up_sql("CREATE TABLE t (id INTEGER);")
+            .build()
+            .unwrap();
+
+        conn.acquire_lock(&table, None).unwrap();
+        conn.upgrade_schema(&table).unwrap();
+        conn.run_up_migration(&table, &migration).unwrap();
+        conn.release_lock(&table).unwrap();
+
+        assert_eq!(conn.load_migrations(&table).unwrap().len(), 1);
+        assert_eq!(conn.load_history(&table).unwrap().len(), 1);
+        let default_tables: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM main.sqlite_master WHERE name LIKE 'movine_migrations%'",
+                params![],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(default_tables, 0);
+    }
+
+    /// Apply Movine's init migration to `conn` and return it, so it can lead the local
+    /// migrations.
+    fn init_migration(conn: &mut Connection) -> Migration {
+        let table = MigrationTable::default();
+        let init = MigrationBuilder::new()
+            .compound_name("1970-01-01-000000_movine_init")
+            .up_sql(&conn.init_up_sql(&table))
+            .down_sql(&conn.init_down_sql(&table))
+            .build()
+            .unwrap();
+        conn.run_up_migration(&table, &init).unwrap();
+        init
+    }
+
+    #[test]
+    /// An atomic `up` should roll back every migration in the plan when one fails, and refuse
+    /// plans with migrations that can't run in a transaction.
+    fn test_atomic_up() {
+        let table = MigrationTable::default();
+        let mut conn = Connection::open_in_memory().unwrap();
+        let init = init_migration(&mut conn);
+        let good = MigrationBuilder::new()
+            .compound_name("2020-01-02-030405_good")
+            .up_sql("CREATE TABLE t (id INTEGER);")
+            .down_sql("DROP TABLE t;")
+            .build()
+            .unwrap();
+        let broken = MigrationBuilder::new()
+            .compound_name("2020-01-03-030405_broken")
+            .up_sql("INSERT INTO missing VALUES (1);")
+            .build()
+            .unwrap();
+        let schema_before = conn.dump_schema(&table).unwrap();
+
+        let mut movine =
+            crate::Movine::with_migrations(&mut conn, vec![init.clone(), good.clone(), broken]);
+        movine.set_atomic(true);
+        assert!(matches!(
+            movine.up(),
+            Err(Error::MigrationFailed { step: Step::Up, .. })
+        ));
+        assert_eq!(conn.load_migrations(&table).unwrap().len(), 1);
+        assert_eq!(conn.dump_schema(&table).unwrap(), schema_before);
+
+        let no_transaction = MigrationBuilder::new()
+            .compound_name("2020-01-03-030405_no_transaction")
+            .up_sql("-- movine:no-transaction\nCREATE TABLE u (id INTEGER);")
+            .build()
+            .unwrap();
+        let mut movine =
+            crate::Movine::with_migrations(&mut conn, vec![init, good, no_transaction]);
+        movine.set_atomic(true);
+        match movine.up() {
+            Err(Error::NonTransactionalMigration(name)) => {
+                assert_eq!(name, "2020-01-03-030405_no_transaction")
+            }
+            x => panic!("expected NonTransactionalMigration, got {:?}", x),
+        }
+        assert_eq!(conn.load_migrations(&table).unwrap().len(), 1);
+        assert_eq!(conn.dump_schema(&table).unwrap(), schema_before);
+    }
+
+    #[test]
+    /// Status and plans should come from comparing the local migrations with the database,
+    /// without changing either.
+    fn test_status_and_plans() {
+        let table = MigrationTable::default();
+        let mut conn = Connection::open_in_memory().unwrap();
+        let init = init_migration(&mut conn);
+        let applied = MigrationBuilder::new()
+            .compound_name("2020-01-02-030405_applied")
+            .up_sql("CREATE TABLE t (id INTEGER);")
+            .down_sql("DROP TABLE t;")
+            .build()
+            .unwrap();
+        let pending = MigrationBuilder::new()
+            .compound_name("2020-01-03-030405_pending")
+            .up_sql("CREATE TABLE u (id INTEGER);")
+            .down_sql("DROP TABLE u;")
+            .build()
+            .unwrap();
+        conn.run_up_migration(&table, &applied).unwrap();
+
+        let local = vec![init, applied, pending];
+        let mut movine = crate::Movine::with_migrations(&mut conn, local);
+        let status: Vec<_> = movine
+            .get_status()
+            .unwrap()
+            .into_iter()
+            .map(|x| (x.name, x.state))
+            .collect();
+        assert_eq!(
+            status,
+            vec![
+                (
+                    "1970-01-01-000000_movine_init".to_string(),
+                    MigrationState::Applied
+                ),
+                (
+                    "2020-01-02-030405_applied".to_string(),
+                    MigrationState::Applied
+                ),
+                (
+                    "2020-01-03-030405_pending".to_string(),
+                    MigrationState::Pending
+                ),
+            ]
+        );
+
+        let names = |plan: OwnedPlan| -> Vec<(Step, String)> {
+            plan.into_iter().map(|(step, x)| (step, x.name)).collect()
+        };
+        assert_eq!(
+            names(movine.get_plan_up().unwrap()),
+            vec![(Step::Up, "2020-01-03-030405_pending".to_string())]
+        );
+        assert_eq!(
+            names(movine.get_plan_down().unwrap()),
+            vec![(Step::Down, "2020-01-02-030405_applied".to_string())]
+        );
+        assert_eq!(
+            names(movine.get_plan_redo().unwrap()),
+            vec![
+                (Step::Down, "2020-01-02-030405_applied".to_string()),
+                (Step::Up, "2020-01-02-030405_applied".to_string()),
+            ]
+        );
+        assert_eq!(
+            names(movine.get_plan_fix().unwrap()),
+            vec![(Step::Up, "2020-01-03-030405_pending".to_string())]
+        );
+        assert_eq!(
+            names(
+                movine
+                    .get_plan_baseline("2020-01-03-030405_pending")
+                    .unwrap()
+            ),
+            vec![(Step::Up, "2020-01-03-030405_pending".to_string())]
+        );
+
+        assert_eq!(conn.load_migrations(&table).unwrap().len(), 2);
+    }
+
+    #[test]
+    /// `up` should run migrations from a source other than the migration directory.
+    fn test_up_from_source() {
+        let table = MigrationTable::default();
+        let mut conn = Connection::open_in_memory().unwrap();
+        let init = init_migration(&mut conn);
+        let source = move || {
+            Ok(vec![
+                init.clone(),
+                MigrationBuilder::new()
+                    .compound_name("2020-01-02-030405_test")
+                    .up_sql("CREATE TABLE t (id INTEGER);")
+                    .down_sql("DROP TABLE t;")
+                    .build()?,
+            ])
+        };
+
+        let mut movine = crate::Movine::with_source(&mut conn, source);
+        movine.set_migration_dir("missing-migrations");
+        movine.up().unwrap();
+
+        assert_eq!(conn.load_migrations(&table).unwrap().len(), 2);
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM t", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 0);
+    }
+
+    #[test]
+    /// `up` should write the schema to `schema_file` only when one is set.
+    fn test_up_writes_schema_file() {
+        let file = std::env::temp_dir().join(format!("movine-schema-{}.sql", std::process::id()));
+        let mut conn = Connection::open_in_memory().unwrap();
+        let init = init_migration(&mut conn);
+        let first = MigrationBuilder::new()
+            .compound_name("2020-01-02-030405_first")
+            .up_sql("CREATE TABLE t (id INTEGER);")
+            .down_sql("DROP TABLE t;")
+            .build()
+            .unwrap();
+        let second = MigrationBuilder::new()
+            .compound_name("2020-01-03-030405_second")
+            .up_sql("CREATE INDEX t_id ON t (id);")
+            .down_sql("DROP INDEX t_id;")
+            .build()
+            .unwrap();
+
+        std::fs::write(&file, "-- untouched\n").unwrap();
+        let mut movine =
+            crate::Movine::with_migrations(&mut conn, vec![init.clone(), first.clone()]);
+        movine.up().unwrap();
+        let unset = std::fs::read_to_string(&file).unwrap();
+
+        let mut movine = crate::Movine::with_migrations(&mut conn, vec![init, first, second]);
+        movine.set_schema_file(file.to_str());
+        movine.up().unwrap();
+        let written = std::fs::read_to_string(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        assert_eq!(unset, "-- untouched\n");
+        assert_eq!(
+            written,
+            "CREATE TABLE t (id INTEGER);\n\
+             CREATE INDEX t_id ON t (id);\n"
+        );
+    }
+}
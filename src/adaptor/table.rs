@@ -0,0 +1,96 @@
+use crate::errors::{Error, Result};
+
+/// The table Movine records applied migrations in, and the schema it lives in. The history
+/// table sits alongside it with `_history` appended to the name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationTable {
+    schema: Option<String>,
+    name: String,
+}
+
+impl MigrationTable {
+    /// Names are written straight into the SQL, so they must be plain identifiers: letters,
+    /// digits and underscores, not starting with a digit.
+    pub fn new(schema: Option<&str>, name: &str) -> Result<Self> {
+        for identifier in schema.into_iter().chain(Some(name)) {
+            if !is_identifier(identifier) {
+                return Err(Error::BadTableName(identifier.to_owned()));
+            }
+        }
+        Ok(Self {
+            schema: schema.map(String::from),
+            name: name.to_owned(),
+        })
+    }
+
+    pub fn schema(&self) -> Option<&str> {
+        self.schema.as_deref()
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn history_name(&self) -> String {
+        format!("{}_history", self.name)
+    }
+
+    /// The name of the migrations table, qualified with the schema if there is one.
+    pub fn qualified_name(&self) -> String {
+        self.qualify(&self.name)
+    }
+
+    /// The name of the history table, qualified with the schema if there is one.
+    pub fn qualified_history_name(&self) -> String {
+        self.qualify(&self.history_name())
+    }
+
+    fn qualify(&self, name: &str) -> String {
+        match &self.schema {
+            Some(schema) => format!("{}.{}", schema, name),
+            None => name.to_owned(),
+        }
+    }
+}
+
+impl Default for MigrationTable {
+    fn default() -> Self {
+        Self {
+            schema: None,
+            name: "movine_migrations".into(),
+        }
+    }
+}
+
+fn is_identifier(identifier: &str) -> bool {
+    let mut chars = identifier.chars();
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Only plain identifiers are allowed, since they end up in the SQL unquoted.
+    fn test_new() {
+        let table = MigrationTable::new(Some("app_1"), "migrations").unwrap();
+        assert_eq!(table.qualified_name(), "app_1.migrations");
+        assert_eq!(table.qualified_history_name(), "app_1.migrations_history");
+        assert_eq!(
+            MigrationTable::default().qualified_name(),
+            "movine_migrations"
+        );
+
+        assert!(MigrationTable::new(None, "").is_err());
+        assert!(MigrationTable::new(None, "1migrations").is_err());
+        assert!(MigrationTable::new(None, "migrations; DROP TABLE users").is_err());
+        assert!(MigrationTable::new(Some("app.schema"), "migrations").is_err());
+        assert!(MigrationTable::new(Some("\"app\""), "migrations").is_err());
+    }
+}
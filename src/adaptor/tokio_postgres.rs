@@ -0,0 +1,347 @@
+use super::async_adaptor::{retry_lock, AsyncDbAdaptor};
+use super::postgres::{
+    failed_statement, history_entry_from_row, migration_from_row, schema_object_from_row, sql,
+    HAS_HISTORY_TABLE, HAS_METADATA_COLUMNS, INIT_DOWN_SQL, INIT_UP_SQL, LOAD_HISTORY,
+    LOAD_LEGACY_MIGRATIONS, LOAD_MIGRATIONS, LOAD_SCHEMA, LOG_DOWN_MIGRATION, LOG_HISTORY,
+    LOG_UP_MIGRATION, RELEASE_LOCK, SCHEMA_IS_CURRENT, TRY_ACQUIRE_LOCK, UPDATE_MIGRATION_DOWN_SQL,
+    UPDATE_MIGRATION_HASH, UPGRADE_SCHEMA,
+};
+use super::{migration_failed, MigrationTable, INIT_MIGRATION, MOVINE_VERSION};
+use crate::display;
+use crate::errors::{Error, Result};
+use crate::history::{HistoryEntry, Outcome};
+use crate::migration::Migration;
+use crate::plan_builder::Step;
+use crate::schema::SchemaObject;
+use crate::statements::{self, Dialect};
+use async_trait::async_trait;
+use std::time::{Duration, Instant};
+use tokio_postgres::{Client, GenericClient, Transaction};
+
+#[async_trait]
+impl AsyncDbAdaptor for Client {
+    fn init_up_sql(&self, table: &MigrationTable) -> String {
+        sql(INIT_UP_SQL, table)
+    }
+
+    fn init_down_sql(&self, table: &MigrationTable) -> String {
+        sql(INIT_DOWN_SQL, table)
+    }
+
+    async fn load_migrations(&mut self, table: &MigrationTable) -> Result<Vec<Migration>> {
+        let row = self
+            .query_one(&sql(HAS_METADATA_COLUMNS, table), &[])
+            .await?;
+        let query = if row.get(0) {
+            LOAD_MIGRATIONS
+        } else {
+            LOAD_LEGACY_MIGRATIONS
+        };
+        let rows = self.query(&sql(query, table), &[]).await?;
+        rows.iter().map(migration_from_row).collect()
+    }
+
+    async fn load_history(&mut self, table: &MigrationTable) -> Result<Vec<HistoryEntry>> {
+        let row = self.query_one(&sql(HAS_HISTORY_TABLE, table), &[]).await?;
+        if !row.get::<_, bool>(0) {
+            return Ok(Vec::new());
+        }
+        let rows = self.query(&sql(LOAD_HISTORY, table), &[]).await?;
+        rows.iter().map(history_entry_from_row).collect()
+    }
+
+    async fn run_up_migration(
+        &mut self,
+        table: &MigrationTable,
+        migration: &Migration,
+    ) -> Result<()> {
+        let result = if migration.transactional {
+            run_in_transaction(self, table, Step::Up, migration).await
+        } else {
+            up_migration(self, table, migration).await
+        };
+        if result.is_err() {
+            log_failure(self, table, Step::Up, migration).await;
+        }
+        result
+    }
+
+    async fn run_down_migration(
+        &mut self,
+        table: &MigrationTable,
+        migration: &Migration,
+    ) -> Result<()> {
+        let result = if migration.transactional {
+            run_in_transaction(self, table, Step::Down, migration).await
+        } else {
+            down_migration(self, table, migration).await
+        };
+        if result.is_err() {
+            log_failure(self, table, Step::Down, migration).await;
+        }
+        result
+    }
+
+    async fn run_atomic_migration_plan(
+        &mut self,
+        table: &MigrationTable,
+        plan: &[(Step, &Migration)],
+    ) -> Result<()> {
+        let transaction = self.transaction().await?;
+        for (step, migration) in plan {
+            display::print_step(&(*step, migration));
+            let result = match step {
+                Step::Up => up_migration(&transaction, table, migration).await,
+                Step::Down if migration.is_reversable() => {
+                    down_migration(&transaction, table, migration).await
+                }
+                Step::Down => Ok(()),
+            };
+            if let Err(e) = result {
+                // Roll back before recording the failure so the record isn't rolled back too.
+                drop(transaction);
+                log_failure(self, table, *step, migration).await;
+                return Err(e);
+            }
+        }
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn update_migration_hash(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        hash: &str,
+    ) -> Result<()> {
+        self.execute(&sql(UPDATE_MIGRATION_HASH, table), &[&name, &hash])
+            .await?;
+        Ok(())
+    }
+
+    async fn update_migration_down_sql(
+        &mut self,
+        table: &MigrationTable,
+        name: &str,
+        down_sql: &str,
+    ) -> Result<()> {
+        self.execute(&sql(UPDATE_MIGRATION_DOWN_SQL, table), &[&name, &down_sql])
+            .await?;
+        Ok(())
+    }
+
+    async fn baseline_migration(
+        &mut self,
+        table: &MigrationTable,
+        migration: &Migration,
+    ) -> Result<()> {
+        let transaction = self.transaction().await?;
+        log_up_migration(&transaction, table, migration, None).await?;
+        transaction.commit().await?;
+        Ok(())
+    }
+
+    async fn upgrade_schema(&mut self, table: &MigrationTable) -> Result<()> {
+        upgrade_tables(self, table).await
+    }
+
+    async fn acquire_lock(
+        &mut self,
+        table: &MigrationTable,
+        timeout: Option<Duration>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let mut waiting = false;
+        loop {
+            let row = self.query_one(&sql(TRY_ACQUIRE_LOCK, table), &[]).await?;
+            if row.get(0) {
+                return Ok(());
+            }
+            retry_lock(timeout, start, &mut waiting).await?;
+        }
+    }
+
+    async fn release_lock(&mut self, table: &MigrationTable) -> Result<()> {
+        self.execute(&sql(RELEASE_LOCK, table), &[]).await?;
+        Ok(())
+    }
+
+    async fn load_schema(&mut self, table: &MigrationTable) -> Result<Vec<SchemaObject>> {
+        let rows = self.query(&sql(LOAD_SCHEMA, table), &[]).await?;
+        Ok(rows.iter().map(schema_object_from_row).collect())
+    }
+}
+
+/// `GenericClient` can't run a batch of statements, so cover the two clients we use ourselves.
+#[async_trait]
+trait BatchExecute: GenericClient {
+    async fn run_batch(&self, sql: &str) -> Result<()>;
+}
+
+#[async_trait]
+impl BatchExecute for Client {
+    async fn run_batch(&self, sql: &str) -> Result<()> {
+        Ok(self.batch_execute(sql).await?)
+    }
+}
+
+#[async_trait]
+impl BatchExecute for Transaction<'_> {
+    async fn run_batch(&self, sql: &str) -> Result<()> {
+        Ok(self.batch_execute(sql).await?)
+    }
+}
+
+/// Run a migration's `sql` as one batch. If it fails, the error says which statement failed when
+/// Postgres reports where.
+async fn run_sql<C: BatchExecute + Sync>(
+    client: &C,
+    migration: &Migration,
+    step: Step,
+    sql: &str,
+) -> Result<()> {
+    let error = match client.run_batch(sql).await {
+        Ok(()) => return Ok(()),
+        Err(e) => e,
+    };
+    let statements = statements::split(sql, Dialect::Postgres);
+    let failed = failed_statement(sql, &statements, &error).map(|i| (i, &statements[i]));
+    Err(migration_failed(migration, step, failed, error))
+}
+
+async fn run_in_transaction(
+    client: &mut Client,
+    table: &MigrationTable,
+    step: Step,
+    migration: &Migration,
+) -> Result<()> {
+    let transaction = client.transaction().await?;
+    match step {
+        Step::Up => up_migration(&transaction, table, migration).await?,
+        Step::Down => down_migration(&transaction, table, migration).await?,
+    }
+    transaction.commit().await?;
+    Ok(())
+}
+
+async fn upgrade_tables<C: BatchExecute + Sync>(client: &C, table: &MigrationTable) -> Result<()> {
+    let row = client
+        .query_one(&sql(SCHEMA_IS_CURRENT, table), &[])
+        .await?;
+    if !row.get::<_, bool>(0) {
+        info!("Upgrading the Movine tables");
+        client.run_batch(&sql(UPGRADE_SCHEMA, table)).await?;
+    }
+    Ok(())
+}
+
+async fn up_migration<C: BatchExecute + Sync>(
+    client: &C,
+    table: &MigrationTable,
+    migration: &Migration,
+) -> Result<()> {
+    let name = &migration.name;
+    let up_sql = migration.up_sql.as_ref().ok_or(Error::BadMigration)?;
+
+    let start = Instant::now();
+    run_sql(client, migration, Step::Up, up_sql).await?;
+    let duration_ms = start.elapsed().as_millis() as i64;
+    if name == INIT_MIGRATION {
+        // Init migrations written by older versions of Movine create older tables, which need
+        // upgrading before the migration can be recorded.
+        upgrade_tables(client, table).await?;
+    }
+    log_up_migration(client, table, migration, Some(duration_ms)).await
+}
+
+async fn log_up_migration<C: GenericClient + Sync>(
+    client: &C,
+    table: &MigrationTable,
+    migration: &Migration,
+    duration_ms: Option<i64>,
+) -> Result<()> {
+    let name = &migration.name;
+    let hash = migration.hash.as_ref().ok_or(Error::BadMigration)?;
+    let empty_string = "".to_string();
+    let down_sql = migration.down_sql.as_ref().unwrap_or(&empty_string);
+
+    client
+        .execute(
+            &sql(LOG_UP_MIGRATION, table),
+            &[
+                &name,
+                &hash,
+                &down_sql,
+                &migration.applied_by,
+                &duration_ms,
+                &MOVINE_VERSION,
+            ],
+        )
+        .await?;
+    log_history(
+        client,
+        table,
+        Step::Up,
+        migration,
+        Outcome::Success,
+        duration_ms,
+    )
+    .await
+}
+
+async fn down_migration<C: BatchExecute + Sync>(
+    client: &C,
+    table: &MigrationTable,
+    migration: &Migration,
+) -> Result<()> {
+    let name = &migration.name;
+    let down_sql = migration.down_sql.as_ref().ok_or(Error::BadMigration)?;
+
+    let start = Instant::now();
+    run_sql(client, migration, Step::Down, down_sql).await?;
+    let duration_ms = start.elapsed().as_millis() as i64;
+    client
+        .execute(&sql(LOG_DOWN_MIGRATION, table), &[&name])
+        .await?;
+    log_history(
+        client,
+        table,
+        Step::Down,
+        migration,
+        Outcome::Success,
+        Some(duration_ms),
+    )
+    .await
+}
+
+async fn log_history<C: GenericClient + Sync>(
+    client: &C,
+    table: &MigrationTable,
+    step: Step,
+    migration: &Migration,
+    outcome: Outcome,
+    duration_ms: Option<i64>,
+) -> Result<()> {
+    client
+        .execute(
+            &sql(LOG_HISTORY, table),
+            &[
+                &migration.name,
+                &step.as_str(),
+                &migration.hash,
+                &migration.applied_by,
+                &outcome.as_str(),
+                &duration_ms,
+                &MOVINE_VERSION,
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Recording the failure is best effort, since there may not be a history table yet.
+async fn log_failure(client: &Client, table: &MigrationTable, step: Step, migration: &Migration) {
+    if let Err(e) = log_history(client, table, step, migration, Outcome::Failure, None).await {
+        debug!("Unable to record the failure in the history: {}", e);
+    }
+}
@@ -0,0 +1,678 @@
+use crate::adaptor::{AsyncDbAdaptor, MigrationTable};
+use crate::display::{self, OutputFormat};
+use crate::errors::Result;
+use crate::history::{HistoryEntry, HistoryFilter};
+use crate::match_maker::MigrationStatus;
+use crate::migration::Migration;
+use crate::migration_source::MigrationSource;
+use crate::plan_builder::{OwnedPlan, Step};
+use crate::schema::{self, SchemaDrift};
+use crate::shared::{self, assign_actor, Acceptance, PlanOptions};
+use std::future::Future;
+use std::pin::Pin;
+use std::time::Duration;
+
+type BuildPlan = fn(&PlanOptions, &[Migration], &[Migration]) -> Result<OwnedPlan>;
+type LockedFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// The async counterpart of [`Movine`](crate::Movine), for use inside an existing runtime.
+///
+/// ```no_run
+/// use movine::AsyncMovine;
+/// use movine::errors::Error;
+///
+/// async fn migrate(client: &mut tokio_postgres::Client) -> Result<(), Error> {
+///     let mut movine = AsyncMovine::new(client);
+///     movine.up().await?;
+///     Ok(())
+/// }
+/// ```
+pub struct AsyncMovine<T> {
+    adaptor: T,
+    migration_dir: String,
+    source: Option<Box<dyn MigrationSource + Send + Sync>>,
+    plan: PlanOptions,
+    show_plan: bool,
+    lock_timeout: Option<Duration>,
+    atomic: bool,
+    format: OutputFormat,
+    actor: Option<String>,
+    table_name: String,
+    schema: Option<String>,
+    single_file: bool,
+    schema_file: Option<String>,
+}
+
+impl<T: AsyncDbAdaptor> AsyncMovine<T> {
+    pub fn new(adaptor: T) -> Self {
+        Self {
+            adaptor,
+            migration_dir: "./migrations".into(),
+            source: None,
+            plan: PlanOptions::default(),
+            show_plan: false,
+            lock_timeout: None,
+            atomic: false,
+            format: OutputFormat::Text,
+            actor: None,
+            table_name: MigrationTable::default().name().into(),
+            schema: None,
+            single_file: false,
+            schema_file: None,
+        }
+    }
+
+    /// Use the given migrations, such as those from [`embed_migrations!`](crate::embed_migrations),
+    /// instead of reading the migration directory.
+    pub fn with_migrations(adaptor: T, migrations: Vec<Migration>) -> Self {
+        Self::with_source(adaptor, migrations)
+    }
+
+    /// Load local migrations from `source` instead of the migration directory.
+    pub fn with_source<S>(adaptor: T, source: S) -> Self
+    where
+        S: MigrationSource + Send + Sync + 'static,
+    {
+        let mut movine = Self::new(adaptor);
+        movine.set_migration_source(source);
+        movine
+    }
+
+    pub fn set_migration_dir(&mut self, migration_dir: &str) -> &mut Self {
+        self.migration_dir = migration_dir.into();
+        self
+    }
+
+    /// Load local migrations from `source` instead of the migration directory.
+    pub fn set_migration_source<S>(&mut self, source: S) -> &mut Self
+    where
+        S: MigrationSource + Send + Sync + 'static,
+    {
+        self.source = Some(Box::new(source));
+        self
+    }
+
+    pub fn set_number(&mut self, number: Option<usize>) -> &mut Self {
+        self.plan.number = number;
+        self
+    }
+
+    /// Migrate up to and including, or down to but excluding, the named migration.
+    pub fn set_target(&mut self, target: Option<&str>) -> &mut Self {
+        self.plan.target = target.map(String::from);
+        self
+    }
+
+    pub fn set_show_plan(&mut self, show_plan: bool) -> &mut Self {
+        self.show_plan = show_plan;
+        self
+    }
+
+    pub fn set_ignore_divergent(&mut self, ignore_divergent: bool) -> &mut Self {
+        self.plan.ignore_divergent = ignore_divergent;
+        self
+    }
+
+    pub fn set_ignore_unreversable(&mut self, ignore_unreversable: bool) -> &mut Self {
+        self.plan.ignore_unreversable = ignore_unreversable;
+        self
+    }
+
+    pub fn set_strict(&mut self, strict: bool) -> &mut Self {
+        self.plan.strict = strict;
+        self
+    }
+
+    /// How long to wait for another Movine instance to finish running migrations before giving
+    /// up. Waits forever if `None`.
+    pub fn set_lock_timeout(&mut self, lock_timeout: Option<Duration>) -> &mut Self {
+        self.lock_timeout = lock_timeout;
+        self
+    }
+
+    /// Run the whole plan in a single transaction instead of one transaction per migration.
+    pub fn set_atomic(&mut self, atomic: bool) -> &mut Self {
+        self.atomic = atomic;
+        self
+    }
+
+    /// Format used when printing the status or a migration plan. With JSON, progress such as
+    /// each step as it runs goes to stderr, for the whole process, so that stdout is only JSON.
+    pub fn set_output_format(&mut self, format: OutputFormat) -> &mut Self {
+        display::set_output_format(format);
+        self.format = format;
+        self
+    }
+
+    /// Who to record as having applied migrations. Defaults to the database user where the
+    /// database has one.
+    pub fn set_actor(&mut self, actor: Option<&str>) -> &mut Self {
+        self.actor = actor.map(String::from);
+        self
+    }
+
+    /// Name of the table applied migrations are recorded in. Defaults to `movine_migrations`.
+    pub fn set_table_name(&mut self, table_name: &str) -> &mut Self {
+        self.table_name = table_name.into();
+        self
+    }
+
+    /// Schema the migrations table lives in. Defaults to the connection's current schema.
+    pub fn set_schema(&mut self, schema: Option<&str>) -> &mut Self {
+        self.schema = schema.map(String::from);
+        self
+    }
+
+    /// Write the init migration as a single `<name>.sql` file rather than a directory.
+    pub fn set_single_file(&mut self, single_file: bool) -> &mut Self {
+        self.single_file = single_file;
+        self
+    }
+
+    /// After a successful `up`, write a dump of the database schema to `schema_file` so that it
+    /// can be committed alongside the migrations.
+    pub fn set_schema_file(&mut self, schema_file: Option<&str>) -> &mut Self {
+        self.schema_file = schema_file.map(String::from);
+        self
+    }
+
+    pub async fn initialize(&mut self) -> Result<()> {
+        self.with_lock(|movine| Box::pin(movine.run_init())).await
+    }
+
+    async fn run_init(&mut self) -> Result<()> {
+        if self.source.is_none() {
+            let table = self.table()?;
+            shared::write_init_migration(
+                &self.migration_dir,
+                self.single_file,
+                &self.adaptor.init_up_sql(&table),
+                &self.adaptor.init_down_sql(&table),
+            )?;
+        }
+
+        let local_migrations = self.load_local_migrations()?;
+        let plan = shared::plan_init(&local_migrations)?;
+        self.run_plan(plan).await
+    }
+
+    pub async fn status(&mut self) -> Result<()> {
+        let status = self.get_status().await?;
+        shared::print_status(self.format, &status);
+        Ok(())
+    }
+
+    /// Get the state of every local and database migration, oldest first.
+    pub async fn get_status(&mut self) -> Result<Vec<MigrationStatus>> {
+        let (local_migrations, db_migrations) = self.load_migrations().await?;
+        shared::status(&local_migrations, &db_migrations)
+    }
+
+    pub async fn history(&mut self, filter: &HistoryFilter) -> Result<()> {
+        let history = self.get_history(filter).await?;
+        shared::print_history(self.format, &history);
+        Ok(())
+    }
+
+    /// Get every up and down step recorded in the database that matches `filter`, oldest first.
+    pub async fn get_history(&mut self, filter: &HistoryFilter) -> Result<Vec<HistoryEntry>> {
+        let table = self.table()?;
+        let history = self.adaptor.load_history(&table).await?;
+        Ok(history.into_iter().filter(|x| filter.matches(x)).collect())
+    }
+
+    pub async fn up(&mut self) -> Result<()> {
+        self.execute(PlanOptions::up).await?;
+        self.write_schema_file().await
+    }
+
+    pub async fn down(&mut self) -> Result<()> {
+        self.execute(PlanOptions::down).await
+    }
+
+    pub async fn fix(&mut self) -> Result<()> {
+        self.execute(PlanOptions::fix).await
+    }
+
+    pub async fn redo(&mut self) -> Result<()> {
+        self.execute(PlanOptions::redo).await
+    }
+
+    /// Record every pending local migration up to and including `target` as applied, without
+    /// running its SQL. Use this after `init` to start using Movine on a database that already
+    /// has its schema.
+    pub async fn baseline(&mut self, target: &str) -> Result<()> {
+        if self.show_plan {
+            let plan = self.get_plan_baseline(target).await?;
+            shared::print_baseline_plan(self.format, &plan);
+            return Ok(());
+        }
+
+        let target = target.to_owned();
+        self.with_lock(move |movine| Box::pin(async move { movine.run_baseline(&target).await }))
+            .await
+    }
+
+    /// Accept intentional edits to the applied migration `name` without running anything. Shows
+    /// how it differs from what the database recorded and then, if `confirm` returns true,
+    /// records the local hash (and down SQL if `update_down_sql` is set) so it is no longer
+    /// variant. The lock is only taken once `confirm` returns, and nothing is recorded if the
+    /// migration changed in the database in the meantime.
+    pub async fn accept<F>(&mut self, name: &str, update_down_sql: bool, confirm: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<bool>,
+    {
+        let (local_migrations, db_migrations) = self.load_migrations().await?;
+        let acceptance = shared::accept_changes(
+            &local_migrations,
+            &db_migrations,
+            name,
+            update_down_sql,
+            confirm,
+        )?;
+        let acceptance = match acceptance {
+            Some(acceptance) => acceptance,
+            None => return Ok(()),
+        };
+
+        self.with_lock(move |movine| Box::pin(movine.run_accept(acceptance)))
+            .await
+    }
+
+    /// Check that each pending migration can be rolled back, by applying the recorded migrations
+    /// to `scratch`, which must be an empty database, and then running each pending one up, down
+    /// and then up again there. Fails with the migration's name if its down SQL errors or leaves
+    /// the schema different to how it was before it ran. Migrations without down SQL are skipped.
+    /// The live database is only read from.
+    pub async fn verify_reversibility<S: AsyncDbAdaptor>(&mut self, mut scratch: S) -> Result<()> {
+        let (local_migrations, db_migrations) = self.load_migrations().await?;
+        let plan = self.plan.up(&local_migrations, &db_migrations)?;
+        let table = self.table()?;
+        shared::check_scratch_empty(&scratch.load_schema(&table).await?)?;
+        for migration in shared::drift_migrations(&local_migrations, &db_migrations)? {
+            scratch.run_up_migration(&table, migration).await?;
+        }
+
+        for (_, migration) in &plan {
+            if !migration.is_reversable() {
+                display::print_skipped(migration);
+                scratch.run_up_migration(&table, migration).await?;
+                continue;
+            }
+
+            let before = scratch.dump_schema(&table).await?;
+            for step in [Step::Up, Step::Down, Step::Up] {
+                display::print_step(&(step, migration));
+                let result = match step {
+                    Step::Up => scratch.run_up_migration(&table, migration).await,
+                    Step::Down => scratch.run_down_migration(&table, migration).await,
+                };
+                if let Err(e) = &result {
+                    display::print_migration_failure(&[(step, migration)], e);
+                }
+                result?;
+
+                if step == Step::Down {
+                    let after = scratch.dump_schema(&table).await?;
+                    shared::check_restored(migration, &before, &after)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Compare the live schema with the one its recorded migrations produce when applied to
+    /// `scratch`, which should be an empty database. Fails if they differ.
+    pub async fn drift<S: AsyncDbAdaptor>(&mut self, scratch: S) -> Result<()> {
+        let drift = self.get_drift(scratch).await?;
+        shared::report_drift(&drift)
+    }
+
+    /// Get every difference between the live schema and the one its recorded migrations produce
+    /// when applied to `scratch`, which should be an empty database. The live database is only
+    /// read from.
+    pub async fn get_drift<S: AsyncDbAdaptor>(
+        &mut self,
+        mut scratch: S,
+    ) -> Result<Vec<SchemaDrift>> {
+        let (local_migrations, db_migrations) = self.load_migrations().await?;
+        let table = self.table()?;
+        shared::check_scratch_empty(&scratch.load_schema(&table).await?)?;
+        for migration in shared::drift_migrations(&local_migrations, &db_migrations)? {
+            scratch.run_up_migration(&table, migration).await?;
+        }
+
+        let expected = scratch.load_schema(&table).await?;
+        let live = self.adaptor.load_schema(&table).await?;
+        Ok(schema::compare(&expected, &live))
+    }
+
+    /// Get the migrations `baseline` would record as applied, without recording them.
+    pub async fn get_plan_baseline(&mut self, target: &str) -> Result<OwnedPlan> {
+        let (local_migrations, db_migrations) = self.load_migrations().await?;
+        shared::plan_baseline(&local_migrations, &db_migrations, target)
+    }
+
+    /// Get the plan `up` would run with the current settings, without running it.
+    pub async fn get_plan_up(&mut self) -> Result<OwnedPlan> {
+        self.get_plan(PlanOptions::up).await
+    }
+
+    /// Get the plan `down` would run with the current settings, without running it.
+    pub async fn get_plan_down(&mut self) -> Result<OwnedPlan> {
+        self.get_plan(PlanOptions::down).await
+    }
+
+    /// Get the plan `fix` would run, without running it.
+    pub async fn get_plan_fix(&mut self) -> Result<OwnedPlan> {
+        self.get_plan(PlanOptions::fix).await
+    }
+
+    /// Get the plan `redo` would run with the current settings, without running it.
+    pub async fn get_plan_redo(&mut self) -> Result<OwnedPlan> {
+        self.get_plan(PlanOptions::redo).await
+    }
+
+    async fn get_plan(&mut self, build_plan: BuildPlan) -> Result<OwnedPlan> {
+        let (local_migrations, db_migrations) = self.load_migrations().await?;
+        build_plan(&self.plan, &local_migrations, &db_migrations)
+    }
+
+    /// Build a plan with `build_plan` and then either show or run it. Runs hold the migration
+    /// lock and upgrade any legacy tables and hashes before planning.
+    async fn execute(&mut self, build_plan: BuildPlan) -> Result<()> {
+        if self.show_plan {
+            let plan = self.get_plan(build_plan).await?;
+            shared::print_plan(self.format, &plan);
+            return Ok(());
+        }
+
+        self.with_lock(|movine| Box::pin(movine.run_locked(build_plan)))
+            .await
+    }
+
+    /// Run `f` while holding the database's migration lock.
+    async fn with_lock<F>(&mut self, f: F) -> Result<()>
+    where
+        F: for<'a> FnOnce(&'a mut Self) -> LockedFuture<'a>,
+    {
+        let table = self.table()?;
+        self.adaptor.acquire_lock(&table, self.lock_timeout).await?;
+        let result = f(self).await;
+        let released = self.adaptor.release_lock(&table).await;
+        result.and(released)
+    }
+
+    async fn run_accept(&mut self, acceptance: Acceptance) -> Result<()> {
+        self.upgrade_legacy().await?;
+        let (_, db_migrations) = self.load_migrations().await?;
+        acceptance.check_unchanged(&db_migrations)?;
+
+        let table = self.table()?;
+        if let Some(down_sql) = &acceptance.down_sql {
+            self.adaptor
+                .update_migration_down_sql(&table, &acceptance.name, down_sql)
+                .await?;
+        }
+        self.adaptor
+            .update_migration_hash(&table, &acceptance.name, &acceptance.hash)
+            .await
+    }
+
+    async fn run_baseline(&mut self, target: &str) -> Result<()> {
+        self.upgrade_legacy().await?;
+        let mut plan = self.get_plan_baseline(target).await?;
+        assign_actor(&mut plan, self.actor.as_deref());
+        let table = self.table()?;
+        for (_, migration) in &plan {
+            display::print_baseline(migration);
+            self.adaptor.baseline_migration(&table, migration).await?;
+        }
+        Ok(())
+    }
+
+    async fn run_locked(&mut self, build_plan: BuildPlan) -> Result<()> {
+        self.upgrade_legacy().await?;
+        let plan = self.get_plan(build_plan).await?;
+        self.run_plan(plan).await
+    }
+
+    async fn run_plan(&mut self, mut plan: OwnedPlan) -> Result<()> {
+        let table = self.table()?;
+        assign_actor(&mut plan, self.actor.as_deref());
+        let plan: Vec<_> = plan.iter().map(|(step, x)| (*step, x)).collect();
+        let result = if self.atomic {
+            shared::check_atomic(&plan)?;
+            self.adaptor.run_atomic_migration_plan(&table, &plan).await
+        } else {
+            self.adaptor.run_migration_plan(&table, &plan).await
+        };
+        if let Err(e) = &result {
+            display::print_migration_failure(&plan, e);
+        }
+        result
+    }
+
+    async fn write_schema_file(&mut self) -> Result<()> {
+        let schema_file = match &self.schema_file {
+            Some(schema_file) if !self.show_plan => schema_file.clone(),
+            _ => return Ok(()),
+        };
+        let table = self.table()?;
+        let schema = self.adaptor.dump_schema(&table).await?;
+        shared::write_schema_file(&schema_file, &schema)
+    }
+
+    fn table(&self) -> Result<MigrationTable> {
+        MigrationTable::new(self.schema.as_deref(), &self.table_name)
+    }
+
+    fn load_local_migrations(&self) -> Result<Vec<Migration>> {
+        shared::load_local_migrations(&self.source, &self.migration_dir)
+    }
+
+    async fn load_migrations(&mut self) -> Result<(Vec<Migration>, Vec<Migration>)> {
+        let local_migrations = self.load_local_migrations()?;
+        let table = self.table()?;
+        let db_migrations = self.adaptor.load_migrations(&table).await?;
+        Ok((local_migrations, db_migrations))
+    }
+
+    /// Upgrade tables created by older versions of Movine, and rewrite the hashes they recorded
+    /// once we've confirmed the local migration still has the same content. Only run while
+    /// holding the lock, before writing to the migrations table.
+    async fn upgrade_legacy(&mut self) -> Result<()> {
+        let table = self.table()?;
+        self.adaptor.upgrade_schema(&table).await?;
+        let (local_migrations, db_migrations) = self.load_migrations().await?;
+        for (name, hash) in shared::legacy_hash_upgrades(&local_migrations, &db_migrations) {
+            debug!("Upgrading legacy hash for {}", name);
+            self.adaptor
+                .update_migration_hash(&table, name, hash)
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::migration::MigrationBuilder;
+    use crate::schema::SchemaObject;
+    use async_trait::async_trait;
+
+    /// Records applied migrations in memory, along with every step run and lock change.
+    #[derive(Default)]
+    struct FakeAdaptor {
+        applied: Vec<Migration>,
+        locked: bool,
+        log: Vec<String>,
+    }
+
+    #[async_trait]
+    impl AsyncDbAdaptor for FakeAdaptor {
+        fn init_up_sql(&self, _table: &MigrationTable) -> String {
+            String::new()
+        }
+
+        fn init_down_sql(&self, _table: &MigrationTable) -> String {
+            String::new()
+        }
+
+        async fn load_migrations(&mut self, _table: &MigrationTable) -> Result<Vec<Migration>> {
+            Ok(self.applied.clone())
+        }
+
+        async fn load_history(&mut self, _table: &MigrationTable) -> Result<Vec<HistoryEntry>> {
+            Ok(Vec::new())
+        }
+
+        async fn run_up_migration(
+            &mut self,
+            _table: &MigrationTable,
+            migration: &Migration,
+        ) -> Result<()> {
+            assert!(self.locked);
+            self.log.push(format!("up {}", migration.name));
+            self.applied.push(migration.clone());
+            Ok(())
+        }
+
+        async fn run_down_migration(
+            &mut self,
+            _table: &MigrationTable,
+            migration: &Migration,
+        ) -> Result<()> {
+            assert!(self.locked);
+            self.log.push(format!("down {}", migration.name));
+            self.applied.retain(|x| x.name != migration.name);
+            Ok(())
+        }
+
+        async fn update_migration_hash(
+            &mut self,
+            _table: &MigrationTable,
+            _name: &str,
+            _hash: &str,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        async fn update_migration_down_sql(
+            &mut self,
+            _table: &MigrationTable,
+            _name: &str,
+            _down_sql: &str,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        async fn baseline_migration(
+            &mut self,
+            _table: &MigrationTable,
+            migration: &Migration,
+        ) -> Result<()> {
+            self.applied.push(migration.clone());
+            Ok(())
+        }
+
+        async fn upgrade_schema(&mut self, _table: &MigrationTable) -> Result<()> {
+            Ok(())
+        }
+
+        async fn acquire_lock(
+            &mut self,
+            _table: &MigrationTable,
+            _timeout: Option<Duration>,
+        ) -> Result<()> {
+            assert!(!self.locked);
+            self.locked = true;
+            self.log.push("lock".into());
+            Ok(())
+        }
+
+        async fn release_lock(&mut self, _table: &MigrationTable) -> Result<()> {
+            self.locked = false;
+            self.log.push("release".into());
+            Ok(())
+        }
+
+        async fn load_schema(&mut self, _table: &MigrationTable) -> Result<Vec<SchemaObject>> {
+            Ok(Vec::new())
+        }
+    }
+
+    fn migrations() -> Vec<Migration> {
+        ["2020-01-01-000000_first", "2020-01-02-000000_second"]
+            .iter()
+            .map(|name| {
+                MigrationBuilder::new()
+                    .compound_name(name)
+                    .up_sql("up")
+                    .down_sql("down")
+                    .build()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    /// Plans should be built from the local migrations and those the adaptor has recorded.
+    fn test_get_plan() {
+        let mut adaptor = FakeAdaptor::default();
+        adaptor.applied.push(migrations().remove(0));
+        let mut movine = AsyncMovine::with_migrations(&mut adaptor, migrations());
+
+        let plan = block_on(movine.get_plan_up()).unwrap();
+        let plan: Vec<_> = plan.iter().map(|(s, x)| (*s, x.name.as_str())).collect();
+        assert_eq!(plan, [(Step::Up, "2020-01-02-000000_second")]);
+
+        let plan = block_on(movine.get_plan_down()).unwrap();
+        let plan: Vec<_> = plan.iter().map(|(s, x)| (*s, x.name.as_str())).collect();
+        assert_eq!(plan, [(Step::Down, "2020-01-01-000000_first")]);
+    }
+
+    #[test]
+    /// Running a plan should apply each step while holding the lock, and release it afterwards.
+    fn test_execute() {
+        let mut adaptor = FakeAdaptor::default();
+        let mut movine = AsyncMovine::with_migrations(&mut adaptor, migrations());
+        block_on(movine.up()).unwrap();
+        block_on(movine.set_number(Some(1)).down()).unwrap();
+
+        assert!(!adaptor.locked);
+        assert_eq!(
+            adaptor.log,
+            [
+                "lock",
+                "up 2020-01-01-000000_first",
+                "up 2020-01-02-000000_second",
+                "release",
+                "lock",
+                "down 2020-01-02-000000_second",
+                "release",
+            ]
+        );
+        let applied: Vec<_> = adaptor.applied.iter().map(|x| x.name.as_str()).collect();
+        assert_eq!(applied, ["2020-01-01-000000_first"]);
+    }
+
+    #[test]
+    /// Showing the plan should neither take the lock nor run anything.
+    fn test_show_plan() {
+        let mut adaptor = FakeAdaptor::default();
+        let mut movine = AsyncMovine::with_migrations(&mut adaptor, migrations());
+        block_on(movine.set_show_plan(true).up()).unwrap();
+
+        assert!(adaptor.log.is_empty());
+        assert!(adaptor.applied.is_empty());
+    }
+}
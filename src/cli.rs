@@ -1,11 +1,60 @@
+use chrono::prelude::*;
+use movine::OutputFormat;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "movine", about = "the simple migration manager")]
+pub struct Cli {
+    #[structopt(flatten)]
+    pub global: GlobalOpt,
+
+    #[structopt(subcommand)]
+    pub command: Opt,
+}
+
+/// Options that apply to every subcommand.
+#[derive(Debug, StructOpt)]
+pub struct GlobalOpt {
+    #[structopt(long = "env", env = "MOVINE_ENV", global = true)]
+    /// Use the settings of this profile from the config file, such as `dev` or `prod`.
+    pub env: Option<String>,
+
+    #[structopt(long = "config", env = "MOVINE_CONFIG", global = true)]
+    /// Path to the config file. Defaults to `movine.toml`.
+    pub config: Option<String>,
+
+    #[structopt(long = "migrations-dir", env = "MOVINE_MIGRATIONS_DIR", global = true)]
+    /// Directory the migrations are kept in. Defaults to `migration_dir` from the config file, or
+    /// `./migrations`.
+    pub migrations_dir: Option<String>,
+
+    #[structopt(
+        short = "C",
+        long = "directory",
+        env = "MOVINE_DIRECTORY",
+        global = true,
+        parse(from_os_str)
+    )]
+    /// Run as if Movine was started in this directory. Other paths are relative to it.
+    pub directory: Option<PathBuf>,
+}
+
+impl GlobalOpt {
+    pub fn config_file(&self) -> &str {
+        self.config.as_deref().unwrap_or("movine.toml")
+    }
+}
+
+#[derive(Debug, StructOpt)]
 pub enum Opt {
     #[structopt(name = "status")]
     /// Get the status of migrations (applied, unapplied, mismatched).
     Status {
+        #[structopt(long = "format", default_value = "text")]
+        /// Output format for the status or plan: `text` or `json`.
+        format: OutputFormat,
+
         #[structopt(short = "v", long = "verbose")]
         /// Run with verbose logging
         debug: bool,
@@ -18,6 +67,10 @@ pub enum Opt {
         /// Number of up or down migrations to run.
         number: Option<usize>,
 
+        #[structopt(short = "t", long = "to")]
+        /// Run pending migrations up to and including this one.
+        target: Option<String>,
+
         #[structopt(short = "p", long = "plan")]
         /// Do a dry run and show the migration plan.
         show_plan: bool,
@@ -26,6 +79,22 @@ pub enum Opt {
         /// Error out on out-of-order pending migrations.
         strict: bool,
 
+        #[structopt(short = "a", long = "atomic")]
+        /// Run the whole plan in a single transaction.
+        atomic: bool,
+
+        #[structopt(long = "actor", env = "MOVINE_ACTOR")]
+        /// Who to record as applying the migrations. Defaults to the database user.
+        actor: Option<String>,
+
+        #[structopt(long = "lock-timeout")]
+        /// Seconds to wait for another Movine instance to finish running migrations.
+        lock_timeout: Option<u64>,
+
+        #[structopt(long = "format", default_value = "text")]
+        /// Output format for the status or plan: `text` or `json`.
+        format: OutputFormat,
+
         #[structopt(short = "v", long = "verbose")]
         /// Run with verbose logging
         debug: bool,
@@ -38,6 +107,10 @@ pub enum Opt {
         /// Number of up or down migrations to run.
         number: Option<usize>,
 
+        #[structopt(short = "t", long = "to")]
+        /// Rollback every migration after this one, leaving it applied.
+        target: Option<String>,
+
         #[structopt(short = "p", long = "plan")]
         /// Do a dry run and show the migration plan.
         show_plan: bool,
@@ -50,6 +123,22 @@ pub enum Opt {
         /// Ignore any unreversable migrations.
         ignore_unreversable: bool,
 
+        #[structopt(short = "a", long = "atomic")]
+        /// Run the whole plan in a single transaction.
+        atomic: bool,
+
+        #[structopt(long = "actor", env = "MOVINE_ACTOR")]
+        /// Who to record as rolling back the migrations. Defaults to the database user.
+        actor: Option<String>,
+
+        #[structopt(long = "lock-timeout")]
+        /// Seconds to wait for another Movine instance to finish running migrations.
+        lock_timeout: Option<u64>,
+
+        #[structopt(long = "format", default_value = "text")]
+        /// Output format for the status or plan: `text` or `json`.
+        format: OutputFormat,
+
         #[structopt(short = "v", long = "verbose")]
         /// Run with verbose logging
         debug: bool,
@@ -62,6 +151,22 @@ pub enum Opt {
         /// Do a dry run and show the migration plan.
         show_plan: bool,
 
+        #[structopt(short = "a", long = "atomic")]
+        /// Run the whole plan in a single transaction.
+        atomic: bool,
+
+        #[structopt(long = "actor", env = "MOVINE_ACTOR")]
+        /// Who to record as applying the migrations. Defaults to the database user.
+        actor: Option<String>,
+
+        #[structopt(long = "lock-timeout")]
+        /// Seconds to wait for another Movine instance to finish running migrations.
+        lock_timeout: Option<u64>,
+
+        #[structopt(long = "format", default_value = "text")]
+        /// Output format for the status or plan: `text` or `json`.
+        format: OutputFormat,
+
         #[structopt(short = "v", long = "verbose")]
         /// Run with verbose logging
         debug: bool,
@@ -86,18 +191,54 @@ pub enum Opt {
         /// Ignore any unreversable migrations.
         ignore_unreversable: bool,
 
+        #[structopt(short = "a", long = "atomic")]
+        /// Run the whole plan in a single transaction.
+        atomic: bool,
+
+        #[structopt(long = "actor", env = "MOVINE_ACTOR")]
+        /// Who to record as applying the migrations. Defaults to the database user.
+        actor: Option<String>,
+
+        #[structopt(long = "lock-timeout")]
+        /// Seconds to wait for another Movine instance to finish running migrations.
+        lock_timeout: Option<u64>,
+
+        #[structopt(long = "format", default_value = "text")]
+        /// Output format for the status or plan: `text` or `json`.
+        format: OutputFormat,
+
         #[structopt(short = "v", long = "verbose")]
         /// Run with verbose logging
         debug: bool,
     },
 
     #[structopt(name = "custom")]
-    /// [unimplemented]
+    /// Run a custom plan of steps, e.g. `up:<migration>` or `down:<migration>`.
     Custom {
         #[structopt(short = "p", long = "plan")]
         /// Do a dry run and show the migration plan.
         show_plan: bool,
 
+        #[structopt(short = "f", long = "file", parse(from_os_str))]
+        /// Read the plan from a file with one step per line.
+        file: Option<PathBuf>,
+
+        #[structopt(short = "a", long = "atomic")]
+        /// Run the whole plan in a single transaction.
+        atomic: bool,
+
+        #[structopt(long = "actor", env = "MOVINE_ACTOR")]
+        /// Who to record as applying the migrations. Defaults to the database user.
+        actor: Option<String>,
+
+        #[structopt(long = "lock-timeout")]
+        /// Seconds to wait for another Movine instance to finish running migrations.
+        lock_timeout: Option<u64>,
+
+        #[structopt(long = "format", default_value = "text")]
+        /// Output format for the status or plan: `text` or `json`.
+        format: OutputFormat,
+
         #[structopt(short = "v", long = "verbose")]
         /// Run with verbose logging
         debug: bool,
@@ -105,9 +246,131 @@ pub enum Opt {
         plan: Vec<String>,
     },
 
+    #[structopt(name = "baseline")]
+    /// Record every pending migration up to the target as applied, without running it.
+    Baseline {
+        #[structopt(short = "t", long = "to")]
+        /// The last migration to record as applied.
+        target: String,
+
+        #[structopt(short = "p", long = "plan")]
+        /// Do a dry run and show the migrations that would be recorded.
+        show_plan: bool,
+
+        #[structopt(long = "actor", env = "MOVINE_ACTOR")]
+        /// Who to record as applying the migrations. Defaults to the database user.
+        actor: Option<String>,
+
+        #[structopt(long = "lock-timeout")]
+        /// Seconds to wait for another Movine instance to finish running migrations.
+        lock_timeout: Option<u64>,
+
+        #[structopt(long = "format", default_value = "text")]
+        /// Output format for the plan: `text` or `json`.
+        format: OutputFormat,
+
+        #[structopt(short = "v", long = "verbose")]
+        /// Run with verbose logging
+        debug: bool,
+    },
+
+    #[structopt(name = "accept")]
+    /// Accept edits to an applied migration by recording its new hash, without running it.
+    Accept {
+        /// The migration to accept.
+        name: String,
+
+        #[structopt(short = "d", long = "down-sql")]
+        /// Also record the new down.sql, used to roll the migration back if it is deleted.
+        down_sql: bool,
+
+        #[structopt(short = "y", long = "yes")]
+        /// Don't ask for confirmation.
+        yes: bool,
+
+        #[structopt(long = "lock-timeout")]
+        /// Seconds to wait for another Movine instance to finish running migrations.
+        lock_timeout: Option<u64>,
+
+        #[structopt(short = "v", long = "verbose")]
+        /// Run with verbose logging
+        debug: bool,
+    },
+
+    #[structopt(name = "verify")]
+    /// Check that pending migrations can be rolled back by running each one up, down and up again
+    /// in a scratch database.
+    Verify {
+        #[structopt(long = "scratch", env = "MOVINE_SCRATCH")]
+        /// URL of an empty database to verify the migrations in, or a file when using SQLite.
+        /// Defaults to an in-memory database when using SQLite.
+        scratch: Option<String>,
+
+        #[structopt(short = "n", long = "number")]
+        /// Number of pending migrations to verify.
+        number: Option<usize>,
+
+        #[structopt(short = "t", long = "to")]
+        /// Verify pending migrations up to and including this one.
+        target: Option<String>,
+
+        #[structopt(short = "v", long = "verbose")]
+        /// Run with verbose logging
+        debug: bool,
+    },
+
+    #[structopt(name = "drift")]
+    /// Check that the database schema matches the one its applied migrations produce.
+    Drift {
+        #[structopt(long = "scratch", env = "MOVINE_SCRATCH")]
+        /// URL of an empty database to apply the migrations to, or a file when using SQLite.
+        /// Defaults to an in-memory database when using SQLite.
+        scratch: Option<String>,
+
+        #[structopt(short = "v", long = "verbose")]
+        /// Run with verbose logging
+        debug: bool,
+    },
+
+    #[structopt(name = "unlock")]
+    /// Release the migration lock left behind by a Movine instance that didn't finish.
+    Unlock {
+        #[structopt(short = "v", long = "verbose")]
+        /// Run with verbose logging
+        debug: bool,
+    },
+
+    #[structopt(name = "history")]
+    /// List every migration that has been run up or down, oldest first.
+    History {
+        #[structopt(long = "name")]
+        /// Only show migrations whose name contains this.
+        name: Option<String>,
+
+        #[structopt(long = "since", parse(try_from_str = parse_since))]
+        /// Only show steps run on or after this date (YYYY-MM-DD or RFC 3339).
+        since: Option<DateTime<Utc>>,
+
+        #[structopt(long = "until", parse(try_from_str = parse_until))]
+        /// Only show steps run before this time, or on or before this date (YYYY-MM-DD or RFC 3339).
+        until: Option<DateTime<Utc>>,
+
+        #[structopt(long = "format", default_value = "text")]
+        /// Output format for the history: `text` or `json`.
+        format: OutputFormat,
+
+        #[structopt(short = "v", long = "verbose")]
+        /// Run with verbose logging
+        debug: bool,
+    },
+
     #[structopt(name = "generate")]
     /// Generate a migration with a given name.
     Generate {
+        #[structopt(short = "s", long = "single-file")]
+        /// Write a single `<name>.sql` file with up and down sections instead of a directory.
+        single_file: bool,
+
         #[structopt(short = "v", long = "verbose")]
         /// Run with verbose logging
         debug: bool,
@@ -118,8 +381,36 @@ pub enum Opt {
     #[structopt(name = "init")]
     /// Initialize the database and the local migration directory.
     Init {
+        #[structopt(long = "actor", env = "MOVINE_ACTOR")]
+        /// Who to record as applying the migrations. Defaults to the database user.
+        actor: Option<String>,
+
+        #[structopt(long = "lock-timeout")]
+        /// Seconds to wait for another Movine instance to finish running migrations.
+        lock_timeout: Option<u64>,
+
         #[structopt(short = "v", long = "verbose")]
         /// Run with verbose logging
         debug: bool,
     },
 }
+
+fn parse_since(s: &str) -> Result<DateTime<Utc>, String> {
+    parse_date(s, 0)
+}
+
+/// A bare date includes the whole of that day.
+fn parse_until(s: &str) -> Result<DateTime<Utc>, String> {
+    parse_date(s, 1)
+}
+
+/// Parse an RFC 3339 time, or a `YYYY-MM-DD` date as midnight UTC `days_after` days later.
+fn parse_date(s: &str, days_after: i64) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let date = NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map_err(|_| format!("expected YYYY-MM-DD or an RFC 3339 time, got `{}`", s))?;
+    let midnight = date.and_time(NaiveTime::MIN) + chrono::Duration::days(days_after);
+    Ok(Utc.from_utc_datetime(&midnight))
+}
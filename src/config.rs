@@ -8,6 +8,7 @@ use postgres_native_tls::MakeTlsConnector;
 #[cfg(feature = "with-rustls")]
 use rustls::ClientConfig;
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::convert::TryInto;
 #[cfg(feature = "with-native-tls")]
 use std::fs;
@@ -16,53 +17,128 @@ use std::io::Read;
 #[cfg(feature = "with-rustls")]
 use tokio_postgres_rustls::MakeRustlsConnect;
 
+mod mysql_params;
 mod postgres_params;
 mod sqlite_params;
 
+pub use self::mysql_params::MysqlParams;
+use self::mysql_params::RawMysqlParams;
 pub use self::postgres_params::PostgresParams;
 use self::postgres_params::RawPostgresParams;
 use sqlite_params::RawSqliteParams;
 pub use sqlite_params::SqliteParams;
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Config {
     pub postgres: Option<PostgresParams>,
     pub sqlite: Option<SqliteParams>,
+    pub mysql: Option<MysqlParams>,
     pub database_url: Option<String>,
-}
-
-impl Default for Config {
-    fn default() -> Self {
-        Config {
-            postgres: None,
-            sqlite: None,
-            database_url: None,
-        }
-    }
+    /// Name of the migrations table, from `table` or `MOVINE_TABLE`.
+    pub table: Option<String>,
+    /// Schema of the migrations table, from `schema` or `MOVINE_SCHEMA`.
+    pub schema: Option<String>,
+    /// Whether to generate single-file migrations, from `single_file`.
+    pub single_file: bool,
+    /// Where `up` writes a dump of the schema, from `schema_file`.
+    pub schema_file: Option<String>,
+    /// Directory migrations are kept in, from `migration_dir` or `MOVINE_MIGRATIONS_DIR`.
+    pub migration_dir: Option<String>,
 }
 
 impl Config {
     pub fn load(file: &str) -> Result<Self> {
-        let raw_config = RawConfig::load_file(file);
-        let pg_env_params = RawPostgresParams::load_from_env();
-        let sqlite_env_params = RawSqliteParams::load_from_env();
-        let database_url = std::env::var("DATABASE_URL");
+        Self::load_profile(file, None)
+    }
+
+    /// Load `file`, with the settings of `[profiles.<profile>]` taking the place of the
+    /// top-level ones. Fails if the file or the profile can't be found, even if `DATABASE_URL`
+    /// is set, rather than quietly connecting without the profile. A selected profile's
+    /// connection also takes precedence over `DATABASE_URL`.
+    pub fn load_profile(file: &str, profile: Option<&str>) -> Result<Self> {
+        Self::load_profile_from_env(file, profile, &std::env::vars().collect())
+    }
+
+    /// `load_profile`, reading environment variables from `env` instead of the process's.
+    fn load_profile_from_env(
+        file: &str,
+        profile: Option<&str>,
+        env: &HashMap<String, String>,
+    ) -> Result<Self> {
+        let raw_config = match profile {
+            Some(profile) => {
+                let raw_config = RawConfig::load_file(file).map_err(|e| match e {
+                    Error::IoError(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        Error::ProfileConfigNotFound {
+                            profile: profile.into(),
+                            file: file.into(),
+                        }
+                    }
+                    e => e,
+                })?;
+                Ok(raw_config.select_profile(profile)?)
+            }
+            None => RawConfig::load_file(file),
+        };
+        let (file_table, file_schema, single_file, schema_file, file_migration_dir) =
+            match &raw_config {
+                Ok(raw_config) => (
+                    raw_config.table.clone(),
+                    raw_config.schema.clone(),
+                    raw_config.single_file.unwrap_or(false),
+                    raw_config.schema_file.clone(),
+                    raw_config.migration_dir.clone(),
+                ),
+                Err(_) => (None, None, false, None, None),
+            };
+        let table = env.get("MOVINE_TABLE").cloned().or(file_table);
+        let schema = env.get("MOVINE_SCHEMA").cloned().or(file_schema);
+        let migration_dir = env
+            .get("MOVINE_MIGRATIONS_DIR")
+            .cloned()
+            .or(file_migration_dir);
+
+        Ok(Self {
+            table,
+            schema,
+            single_file,
+            schema_file,
+            migration_dir,
+            ..Self::load_connection(raw_config, profile.is_some(), env)?
+        })
+    }
+
+    /// `DATABASE_URL` is used ahead of the config file, unless a profile was selected, in which
+    /// case it's only used if the profile has no connection of its own.
+    fn load_connection(
+        raw_config: Result<RawConfig>,
+        has_profile: bool,
+        env: &HashMap<String, String>,
+    ) -> Result<Self> {
+        let pg_env_params = RawPostgresParams::load_from_env(env);
+        let sqlite_env_params = RawSqliteParams::load_from_env(env);
+        let mysql_env_params = RawMysqlParams::load_from_env(env);
+        let database_url = env.get("DATABASE_URL");
 
         debug!(
             "Config information loaded:
             file: {:?}
             pg_env: {:?}
             sqlite_env: {:?}
+            mysql_env: {:?}
             database_url: {:?}",
-            &raw_config, &pg_env_params, &sqlite_env_params, &database_url
+            &raw_config, &pg_env_params, &sqlite_env_params, &mysql_env_params, &database_url
         );
 
-        if let Ok(database_url) = database_url {
-            debug!("Using database_url provided.");
-            return Ok(Config {
-                database_url: Some(database_url),
-                ..Self::default()
-            });
+        let url_config = database_url.map(|database_url| Config {
+            database_url: Some(database_url.clone()),
+            ..Self::default()
+        });
+        if !has_profile {
+            if let Some(url_config) = url_config {
+                debug!("Using database_url provided.");
+                return Ok(url_config);
+            }
         }
 
         let raw_config = match raw_config {
@@ -103,8 +179,30 @@ impl Config {
                     ..Self::default()
                 })
             }
-            _ => match (pg_env_params, sqlite_env_params) {
-                (Ok(pg_env_params), _) if pg_env_params.is_any() => {
+            Some(RawConfig {
+                mysql: Some(mysql_params),
+                ..
+            }) => {
+                debug!("Using mysql config-file params provided.");
+                let all_params = [Ok(mysql_params), mysql_env_params];
+                let params: Vec<_> = all_params.iter().filter_map(|x| x.as_ref().ok()).collect();
+                let params = (&params[..]).try_into()?;
+                Ok(Self {
+                    mysql: Some(params),
+                    ..Self::default()
+                })
+            }
+            _ => match (
+                url_config,
+                pg_env_params,
+                sqlite_env_params,
+                mysql_env_params,
+            ) {
+                (Some(url_config), _, _, _) => {
+                    debug!("Using database_url provided.");
+                    Ok(url_config)
+                }
+                (_, Ok(pg_env_params), _, _) if pg_env_params.is_any() => {
                     debug!("Using postgres env vars provided.");
                     let params = [&pg_env_params];
                     let params = (&params[..]).try_into()?;
@@ -113,7 +211,7 @@ impl Config {
                         ..Self::default()
                     })
                 }
-                (_, Ok(sqlite_env_params)) if sqlite_env_params.is_any() => {
+                (_, _, Ok(sqlite_env_params), _) if sqlite_env_params.is_any() => {
                     debug!("Using sqlite env vars provided.");
                     let params = [&sqlite_env_params];
                     let params = (&params[..]).try_into()?;
@@ -122,6 +220,15 @@ impl Config {
                         ..Self::default()
                     })
                 }
+                (_, _, _, Ok(mysql_env_params)) if mysql_env_params.is_any() => {
+                    debug!("Using mysql env vars provided.");
+                    let params = [&mysql_env_params];
+                    let params = (&params[..]).try_into()?;
+                    Ok(Self {
+                        mysql: Some(params),
+                        ..Self::default()
+                    })
+                }
                 _ => Err(Error::ConfigNotFound),
             },
         }
@@ -130,7 +237,7 @@ impl Config {
     pub fn into_pg_conn_from_url(self) -> Result<postgres::Client> {
         if let Some(ref url) = self.database_url {
             if url.starts_with("postgres") {
-                let conn = postgres::Client::connect(&url, postgres::NoTls)?;
+                let conn = postgres::Client::connect(url, postgres::NoTls)?;
                 Ok(conn)
             } else {
                 Err(Error::AdaptorNotFound)
@@ -180,8 +287,33 @@ impl Config {
         }
     }
 
+    #[cfg(feature = "mysql")]
+    pub fn into_mysql_conn(self) -> Result<mysql::Conn> {
+        if let Some(ref url) = self.database_url {
+            let opts = mysql::Opts::from_url(url).map_err(mysql::Error::from)?;
+            let conn = mysql::Conn::new(opts)?;
+            Ok(conn)
+        } else if let Some(ref params) = self.mysql {
+            let opts = mysql::OptsBuilder::new()
+                .user(Some(&params.user))
+                .pass(params.password.as_ref())
+                .ip_or_hostname(Some(&params.host))
+                .tcp_port(params.port)
+                .db_name(Some(&params.database));
+            let conn = mysql::Conn::new(opts)?;
+            Ok(conn)
+        } else {
+            Err(Error::AdaptorNotFound)
+        }
+    }
+
     pub fn into_db_adaptor(self) -> Result<Box<dyn DbAdaptor>> {
         match self {
+            #[cfg(feature = "mysql")]
+            Config {
+                database_url: Some(ref url),
+                ..
+            } if url.starts_with("mysql") => Ok(Box::new(self.into_mysql_conn()?)),
             Config {
                 database_url: Some(_),
                 ..
@@ -192,6 +324,8 @@ impl Config {
             Config {
                 sqlite: Some(_), ..
             } => Ok(Box::new(self.into_sqlite_conn()?)),
+            #[cfg(feature = "mysql")]
+            Config { mysql: Some(_), .. } => Ok(Box::new(self.into_mysql_conn()?)),
             _ => Err(Error::AdaptorNotFound),
         }
     }
@@ -201,6 +335,14 @@ impl Config {
 pub struct RawConfig {
     pub postgres: Option<RawPostgresParams>,
     pub sqlite: Option<RawSqliteParams>,
+    pub mysql: Option<RawMysqlParams>,
+    pub table: Option<String>,
+    pub schema: Option<String>,
+    pub single_file: Option<bool>,
+    pub schema_file: Option<String>,
+    pub migration_dir: Option<String>,
+    #[serde(default)]
+    pub profiles: HashMap<String, RawProfile>,
 }
 
 impl RawConfig {
@@ -211,6 +353,35 @@ impl RawConfig {
         let config = toml::from_str(&config)?;
         Ok(config)
     }
+
+    /// Apply the named profile. Its connection sections replace the top-level ones if it has
+    /// any, and its other settings replace the top-level ones it sets.
+    pub fn select_profile(mut self, name: &str) -> Result<RawConfig> {
+        let profile = self
+            .profiles
+            .remove(name)
+            .ok_or_else(|| Error::ProfileNotFound(name.into()))?;
+        if profile.postgres.is_some() || profile.sqlite.is_some() || profile.mysql.is_some() {
+            self.postgres = profile.postgres;
+            self.sqlite = profile.sqlite;
+            self.mysql = profile.mysql;
+        }
+        self.table = profile.table.or(self.table);
+        self.schema = profile.schema.or(self.schema);
+        self.migration_dir = profile.migration_dir.or(self.migration_dir);
+        Ok(self)
+    }
+}
+
+/// A `[profiles.<name>]` section, such as `[profiles.prod]` with `[profiles.prod.postgres]`.
+#[derive(Debug, Deserialize)]
+pub struct RawProfile {
+    pub postgres: Option<RawPostgresParams>,
+    pub sqlite: Option<RawSqliteParams>,
+    pub mysql: Option<RawMysqlParams>,
+    pub table: Option<String>,
+    pub schema: Option<String>,
+    pub migration_dir: Option<String>,
 }
 
 #[cfg(feature = "with-native-tls")]
@@ -231,7 +402,7 @@ fn build_tls_connection(url: &str, conf: &postgres_params::SslConfig) -> Result<
 
     let connector = builder.build()?;
     let tls = MakeTlsConnector::new(connector);
-    Ok(postgres::Client::connect(&url, tls)?)
+    Ok(postgres::Client::connect(url, tls)?)
 }
 
 #[cfg(feature = "with-rustls")]
@@ -266,3 +437,105 @@ fn build_tls_connection(url: &str, conf: &postgres_params::SslConfig) -> Result<
     let tls = MakeRustlsConnect::new(config);
     Ok(postgres::Client::connect(&url, tls)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const CONFIG: &str = r#"
+        table = "app_migrations"
+        schema = "app"
+        migration_dir = "db/migrations"
+
+        [sqlite]
+        file = "app.db"
+
+        [profiles.dev]
+        table = "dev_migrations"
+        migration_dir = "dev/migrations"
+
+        [profiles.prod.postgres]
+        database = "app"
+    "#;
+
+    #[test]
+    /// A profile's connection should replace the top-level one, and its settings should fall back
+    /// to the top-level ones.
+    fn test_select_profile() {
+        let raw_config: RawConfig = toml::from_str(CONFIG).unwrap();
+        let dev = raw_config.select_profile("dev").unwrap();
+        assert_eq!(dev.sqlite.unwrap().file.as_deref(), Some("app.db"));
+        assert_eq!(dev.table.as_deref(), Some("dev_migrations"));
+        assert_eq!(dev.schema.as_deref(), Some("app"));
+        assert_eq!(dev.migration_dir.as_deref(), Some("dev/migrations"));
+
+        let raw_config: RawConfig = toml::from_str(CONFIG).unwrap();
+        let prod = raw_config.select_profile("prod").unwrap();
+        assert!(prod.sqlite.is_none());
+        assert_eq!(prod.postgres.unwrap().database.as_deref(), Some("app"));
+        assert_eq!(prod.table.as_deref(), Some("app_migrations"));
+        assert_eq!(prod.migration_dir.as_deref(), Some("db/migrations"));
+
+        let raw_config: RawConfig = toml::from_str(CONFIG).unwrap();
+        assert!(matches!(
+            raw_config.select_profile("staging"),
+            Err(Error::ProfileNotFound(name)) if name == "staging"
+        ));
+    }
+
+    #[test]
+    /// A profile that can't be found should be an error, rather than falling back to the
+    /// top-level settings or `DATABASE_URL`.
+    fn test_missing_profile() {
+        let dir = std::env::temp_dir().join(format!("movine-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("movine.toml");
+        fs::write(&file, CONFIG).unwrap();
+        let file = file.to_str().unwrap();
+        let missing = dir.join("missing.toml");
+        let missing = missing.to_str().unwrap();
+        let env = database_url_env();
+
+        let typo = Config::load_profile_from_env(file, Some("porod"), &env);
+        let no_file = Config::load_profile_from_env(missing, Some("dev"), &env);
+        let no_profile = Config::load_profile_from_env(missing, None, &env);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(typo, Err(Error::ProfileNotFound(name)) if name == "porod"));
+        assert!(matches!(
+            no_file,
+            Err(Error::ProfileConfigNotFound { profile, .. }) if profile == "dev"
+        ));
+        assert!(no_profile.unwrap().database_url.is_some());
+    }
+
+    #[test]
+    /// A selected profile's connection should be used over `DATABASE_URL`, which should still
+    /// be used over the top-level connection when no profile is selected.
+    fn test_profile_over_database_url() {
+        let dir = std::env::temp_dir().join(format!("movine-url-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("movine.toml");
+        fs::write(&file, CONFIG).unwrap();
+        let file = file.to_str().unwrap();
+        let env = database_url_env();
+
+        let dev = Config::load_profile_from_env(file, Some("dev"), &env);
+        let top_level = Config::load_profile_from_env(file, None, &env);
+        fs::remove_dir_all(&dir).unwrap();
+
+        let dev = dev.unwrap();
+        assert!(dev.database_url.is_none());
+        assert_eq!(dev.sqlite.unwrap().file, "app.db");
+        let top_level = top_level.unwrap();
+        assert!(top_level.database_url.is_some());
+        assert!(top_level.sqlite.is_none());
+    }
+
+    fn database_url_env() -> HashMap<String, String> {
+        let mut env = HashMap::new();
+        env.insert("DATABASE_URL".into(), "postgres://localhost/app".into());
+        env
+    }
+}
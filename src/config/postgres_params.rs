@@ -1,5 +1,6 @@
 use crate::errors::{Error, Result};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::convert::TryFrom;
 
 #[derive(Debug, Clone)]
@@ -88,8 +89,8 @@ pub struct RawPostgresParams {
 }
 
 impl RawPostgresParams {
-    pub fn load_from_env() -> Result<Self> {
-        let params = envy::prefixed("PG").from_env()?;
+    pub fn load_from_env(env: &HashMap<String, String>) -> Result<Self> {
+        let params = envy::prefixed("PG").from_iter(env.clone())?;
         Ok(params)
     }
 
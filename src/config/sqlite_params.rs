@@ -1,5 +1,6 @@
 use crate::errors::{Error, Result};
 use serde::Deserialize; //::{params, Connection, Result};
+use std::collections::HashMap;
 use std::convert::TryFrom;
 
 #[derive(Debug)]
@@ -25,14 +26,14 @@ impl TryFrom<&[&RawSqliteParams]> for SqliteParams {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct RawSqliteParams {
     pub file: Option<String>,
 }
 
 impl RawSqliteParams {
-    pub fn load_from_env() -> Result<Self> {
-        let params = envy::prefixed("SQLITE_").from_env()?;
+    pub fn load_from_env(env: &HashMap<String, String>) -> Result<Self> {
+        let params = envy::prefixed("SQLITE_").from_iter(env.clone())?;
         Ok(params)
     }
 
@@ -40,9 +41,3 @@ impl RawSqliteParams {
         self.file.is_some()
     }
 }
-
-impl Default for RawSqliteParams {
-    fn default() -> Self {
-        Self { file: None }
-    }
-}
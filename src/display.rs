@@ -1,37 +1,97 @@
-use crate::match_maker::Matching;
+use crate::diff;
+use crate::errors::Error;
+use crate::history::{HistoryEntry, Outcome};
+use crate::match_maker::{MigrationState, MigrationStatus};
 use crate::migration::Migration;
 use crate::plan_builder::Step;
-use ansi_term::Color;
+use crate::schema::SchemaDrift;
+use ansi_term::{Color, Style};
+use serde::Serialize;
 use std::io::{self, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 const LIGHT_RED: u8 = 9;
 
-pub fn print_status(matchings: &[Matching]) {
+/// Whether progress, such as each step as it runs, goes to stderr rather than stdout. Set when
+/// printing JSON, so that stdout holds nothing but the JSON.
+static PROGRESS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+macro_rules! progress {
+    ($($arg:tt)*) => {
+        if PROGRESS_TO_STDERR.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!(
+                "unknown output format `{}`, expected `text` or `json`",
+                s
+            )),
+        }
+    }
+}
+
+/// Print progress for `format`: to stderr for JSON, and otherwise to stdout.
+pub fn set_output_format(format: OutputFormat) {
+    PROGRESS_TO_STDERR.store(format == OutputFormat::Json, Ordering::Relaxed);
+}
+
+pub fn print_status(statuses: &[MigrationStatus]) {
     let stdout = io::stdout();
     let mut handle = stdout.lock();
 
-    use Matching::*;
-    for matching in matchings.iter().rev() {
-        let reversable_str = if matching.is_reversable() {
+    use MigrationState::*;
+    for status in statuses.iter().rev() {
+        let reversable_str = if status.reversable {
             "".to_owned()
         } else {
             Color::Red.paint(" [unreversable]").to_string()
         };
 
-        let (color, status) = match matching {
+        let (color, state) = match status.state {
             // Add spaces in front to make them all the same length
-            Applied(_) => (Color::Green, "  Applied"),
-            Divergent(_) => (Color::Red, "Divergent"),
-            Pending(_) => (Color::Yellow, "  Pending"),
-            Variant(_, _) => (Color::Fixed(LIGHT_RED), "  Variant"),
+            Applied => (Color::Green, "  Applied"),
+            Divergent => (Color::Red, "Divergent"),
+            Pending => (Color::Yellow, "  Pending"),
+            Variant => (Color::Fixed(LIGHT_RED), "  Variant"),
+        };
+
+        let applied_str = match status.applied_at {
+            Some(applied_at) => Style::new()
+                .dimmed()
+                .paint(format!(
+                    " (applied {})",
+                    applied_at.format("%Y-%m-%d %H:%M:%S UTC")
+                ))
+                .to_string(),
+            None => "".to_owned(),
         };
 
         writeln!(
             handle,
-            "{status}{reversable} - {name}",
-            name = matching.get_name(),
-            status = color.paint(status),
+            "{status}{reversable} - {name}{applied}",
+            name = status.name,
+            status = color.paint(state),
             reversable = reversable_str,
+            applied = applied_str,
         )
         .unwrap();
     }
@@ -52,16 +112,287 @@ pub fn print_step((step, migration): &(Step, &Migration)) {
             Down => "Down",
         };
 
-        println!(
+        progress!(
             "{step} - {name}",
             name = migration.name,
             step = Color::Green.paint(step),
         );
     } else {
-        println!(
+        progress!(
             "{unreversable} - {name}",
             name = migration.name,
             unreversable = Color::Red.paint("Unreversable migration"),
         );
     }
 }
+
+/// If `error` is a failed migration from `plan`, print the SQL around the failing statement.
+pub fn print_migration_failure(plan: &[(Step, &Migration)], error: &Error) {
+    let (name, step, line) = match error {
+        Error::MigrationFailed {
+            name,
+            step,
+            line: Some(line),
+            ..
+        } => (name, step, *line),
+        _ => return,
+    };
+    let sql = plan
+        .iter()
+        .find(|(x, migration)| x == step && &migration.name == name)
+        .and_then(|(step, migration)| match step {
+            Step::Up => migration.up_sql.as_deref(),
+            Step::Down => migration.down_sql.as_deref(),
+        });
+    let sql = match sql {
+        Some(sql) => sql,
+        None => return,
+    };
+
+    let first = line.saturating_sub(2).max(1);
+    let width = (line + 2).to_string().len();
+    for (number, text) in sql
+        .lines()
+        .enumerate()
+        .skip(first - 1)
+        .take(line + 3 - first)
+    {
+        let number = number + 1;
+        let gutter = format!("{:>width$} | ", number, width = width);
+        if number == line {
+            eprintln!("{}{}", Color::Red.paint(gutter), text);
+        } else {
+            eprintln!("{}{}", Style::new().dimmed().paint(gutter), text);
+        }
+    }
+}
+
+pub fn print_baseline(migration: &Migration) {
+    progress!(
+        "{step} - {name}",
+        name = migration.name,
+        step = Color::Green.paint("Baseline"),
+    );
+}
+
+/// Show that `verify` couldn't check a migration because it has no down SQL.
+pub fn print_skipped(migration: &Migration) {
+    progress!(
+        "{step} - {name} (no down.sql)",
+        name = migration.name,
+        step = Color::Yellow.paint("Skipped"),
+    );
+}
+
+/// Show how a migration changed between being applied (`db`) and now (`local`).
+pub fn print_accept(db: &Migration, local: &Migration) {
+    println!("{} - {}", Color::Yellow.paint("Accept"), local.name);
+    println!(
+        "  hash: {} -> {}",
+        db.hash.as_deref().unwrap_or(""),
+        local.hash.as_deref().unwrap_or("")
+    );
+
+    let diff = diff::unified_diff(
+        db.down_sql.as_deref().unwrap_or(""),
+        local.down_sql.as_deref().unwrap_or(""),
+        "down.sql (database)",
+        "down.sql (local)",
+    );
+    if diff.is_empty() {
+        println!("  down.sql is unchanged");
+    }
+    print_diff(&diff);
+    println!(
+        "{}",
+        Style::new()
+            .dimmed()
+            .paint("  The database doesn't keep up.sql, so changes to it only show in the hash.")
+    );
+}
+
+/// List how the live schema has drifted, with a diff for each object that has changed.
+pub fn print_drift(drift: &[SchemaDrift]) {
+    if drift.is_empty() {
+        println!("The schema matches its migrations");
+    }
+    for x in drift {
+        let (label, object) = match x {
+            SchemaDrift::Missing(object) => (Color::Red.paint("   Missing"), object),
+            SchemaDrift::Unexpected(object) => (Color::Yellow.paint("Unexpected"), object),
+            SchemaDrift::Changed { live, .. } => (Color::Cyan.paint("   Changed"), live),
+        };
+        println!("{} - {} {}", label, object.kind, object.name);
+        if let SchemaDrift::Changed { expected, live } = x {
+            print_diff(&diff::unified_diff(
+                &expected.sql,
+                &live.sql,
+                "migrations",
+                "database",
+            ));
+        }
+    }
+}
+
+fn print_diff(diff: &str) {
+    for line in diff.lines() {
+        if line.starts_with("+++") || line.starts_with("---") {
+            println!("{}", Style::new().bold().paint(line));
+        } else if line.starts_with('+') {
+            println!("{}", Color::Green.paint(line));
+        } else if line.starts_with('-') {
+            println!("{}", Color::Red.paint(line));
+        } else if line.starts_with("@@") {
+            println!("{}", Color::Cyan.paint(line));
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+pub fn print_status_json(statuses: &[MigrationStatus]) {
+    println!("{}", status_json(statuses));
+}
+
+/// The statuses as a JSON array, newest first like the text output.
+fn status_json(statuses: &[MigrationStatus]) -> String {
+    let statuses: Vec<_> = statuses.iter().rev().collect();
+    serde_json::to_string(&statuses).unwrap()
+}
+
+pub fn print_plan_json(plan: &[(Step, &Migration)]) {
+    println!("{}", plan_json(plan));
+}
+
+#[derive(Serialize)]
+struct PlanStep<'a> {
+    direction: Step,
+    name: &'a str,
+}
+
+fn plan_json(plan: &[(Step, &Migration)]) -> String {
+    let steps: Vec<_> = plan
+        .iter()
+        .map(|(step, migration)| PlanStep {
+            direction: *step,
+            name: &migration.name,
+        })
+        .collect();
+    serde_json::to_string(&steps).unwrap()
+}
+
+pub fn print_history(entries: &[HistoryEntry]) {
+    let stdout = io::stdout();
+    let mut handle = stdout.lock();
+
+    for entry in entries {
+        let recorded_at = match entry.recorded_at {
+            Some(recorded_at) => recorded_at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+            None => "unknown time".to_owned(),
+        };
+
+        let direction = match entry.direction {
+            // Add spaces in front to make them all the same length
+            Step::Up => "  Up",
+            Step::Down => "Down",
+        };
+
+        let (color, outcome) = match entry.outcome {
+            Outcome::Success => (Color::Green, "succeeded"),
+            Outcome::Failure => (Color::Red, "   failed"),
+        };
+
+        let mut details = Vec::new();
+        if let Some(actor) = &entry.actor {
+            details.push(format!("by {}", actor));
+        }
+        if let Some(duration_ms) = entry.duration_ms {
+            details.push(format!("{} ms", duration_ms));
+        }
+        let details_str = if details.is_empty() {
+            "".to_owned()
+        } else {
+            Style::new()
+                .dimmed()
+                .paint(format!(" ({})", details.join(", ")))
+                .to_string()
+        };
+
+        writeln!(
+            handle,
+            "{recorded_at} {direction} {outcome} - {name}{details}",
+            recorded_at = Style::new().dimmed().paint(recorded_at),
+            direction = direction,
+            outcome = color.paint(outcome),
+            name = entry.name,
+            details = details_str,
+        )
+        .unwrap();
+    }
+}
+
+pub fn print_history_json(entries: &[HistoryEntry]) {
+    println!("{}", serde_json::to_string(entries).unwrap());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::match_maker::MigrationState;
+    use crate::migration::MigrationBuilder;
+    use serde_json::{json, Value};
+
+    const NAME: &str = "2020-01-01-000000_\"quoted\"\\\n\t\u{1}";
+
+    #[test]
+    /// Status JSON should parse back, whatever characters the names contain.
+    fn test_status_json() {
+        let status = MigrationStatus {
+            name: NAME.into(),
+            state: MigrationState::Pending,
+            reversable: true,
+            local_hash: Some("sha256:1234".into()),
+            db_hash: None,
+            applied_at: None,
+            applied_by: None,
+            duration_ms: None,
+            movine_version: None,
+        };
+        let applied = MigrationStatus {
+            name: "2019-01-01-000000_applied".into(),
+            state: MigrationState::Applied,
+            duration_ms: Some(12),
+            ..status.clone()
+        };
+
+        let parsed: Value = serde_json::from_str(&status_json(&[applied, status])).unwrap();
+        assert_eq!(parsed[0]["name"], NAME);
+        assert_eq!(parsed[0]["state"], "pending");
+        assert_eq!(parsed[0]["db_hash"], Value::Null);
+        assert_eq!(parsed[1]["state"], "applied");
+        assert_eq!(parsed[1]["duration_ms"], 12);
+    }
+
+    #[test]
+    /// Plan JSON should parse back, whatever characters the names contain.
+    fn test_plan_json() {
+        let migration = MigrationBuilder::new()
+            .compound_name(NAME)
+            .up_sql("SELECT 1;")
+            .build()
+            .unwrap();
+
+        let parsed: Value = serde_json::from_str(&plan_json(&[
+            (Step::Up, &migration),
+            (Step::Down, &migration),
+        ]))
+        .unwrap();
+        assert_eq!(
+            parsed,
+            json!([
+                {"direction": "up", "name": NAME},
+                {"direction": "down", "name": NAME},
+            ])
+        );
+    }
+}
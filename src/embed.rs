@@ -0,0 +1,150 @@
+//! Bake migrations into the binary so they can be run without the migrations directory.
+//!
+//! Call [`embed_migrations`] from your crate's `build.rs`:
+//!
+//! ```no_run
+//! movine::embed::embed_migrations("migrations").unwrap();
+//! ```
+//!
+//! And then load them with the [`embed_migrations!`](crate::embed_migrations) macro, using the
+//! same directory:
+//!
+//! ```ignore
+//! let migrations = movine::embed_migrations!("migrations");
+//! let mut movine = movine::Movine::with_migrations(&mut conn, migrations);
+//! movine.up()?;
+//! ```
+use crate::errors::{Error, Result};
+use crate::file_handler::{FileHandler, MigrationFiles};
+use crate::migration::{Migration, MigrationBuilder};
+use std::env;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Name, up SQL and down SQL of a migration as written by [`embed_migrations`].
+pub type EmbeddedMigration = (&'static str, &'static str, Option<&'static str>);
+
+/// Include the migrations written by [`embed::embed_migrations`](crate::embed::embed_migrations)
+/// for the given directory, as a `Vec<Migration>`.
+#[macro_export]
+macro_rules! embed_migrations {
+    ($dir:literal) => {
+        $crate::embed::load(include!(concat!(
+            env!("OUT_DIR"),
+            "/movine_migrations/",
+            $dir,
+            ".rs"
+        )))
+    };
+}
+
+/// Write a Rust source file to `OUT_DIR` which includes the SQL of every migration in
+/// `migration_dir`, and tell Cargo to rerun the build script when migrations are added or
+/// removed. Must be called from a build script.
+pub fn embed_migrations(migration_dir: &str) -> Result<()> {
+    let out_dir = env::var_os("OUT_DIR").ok_or(Error::OutDirNotSet)?;
+    let out_file = output_path(Path::new(&out_dir), migration_dir)?;
+
+    let mut files = FileHandler::new(migration_dir).migration_files()?;
+    files.sort_by(|a, b| a.name().cmp(b.name()));
+
+    let mut source = String::from("&[\n");
+    for file in files {
+        let entry = match &file {
+            MigrationFiles::Directory {
+                name,
+                path,
+                up_path,
+                down_path,
+            } => {
+                let up_path = match fs::canonicalize(up_path) {
+                    Ok(path) => path,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                        return Err(Error::MalformedMigration {
+                            path: path.clone(),
+                            reason: "missing up.sql".into(),
+                        });
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+                let down = match fs::canonicalize(down_path) {
+                    Ok(path) => format!("Some(include_str!({:?}))", path.display().to_string()),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => "None".into(),
+                    Err(e) => return Err(e.into()),
+                };
+                format!(
+                    "({:?}, include_str!({:?}), {})",
+                    name,
+                    up_path.display().to_string(),
+                    down
+                )
+            }
+            // The file has to be split up here, so Cargo won't notice it changing on its own.
+            MigrationFiles::SingleFile { name, path } => {
+                let (up_sql, down_sql) = file.read()?;
+                println!("cargo:rerun-if-changed={}", path.display());
+                format!("({:?}, {:?}, {:?})", name, up_sql, down_sql)
+            }
+        };
+        source.push_str(&format!("    {},\n", entry));
+    }
+    source.push(']');
+
+    if let Some(parent) = out_file.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&out_file, source)?;
+    println!("cargo:rerun-if-changed={}", migration_dir);
+    Ok(())
+}
+
+/// Build migrations from the output of [`embed_migrations`]. Used by the
+/// [`embed_migrations!`](crate::embed_migrations) macro.
+#[doc(hidden)]
+pub fn load(migrations: &[EmbeddedMigration]) -> Vec<Migration> {
+    migrations
+        .iter()
+        .map(|(name, up_sql, down_sql)| {
+            let mut builder = MigrationBuilder::new();
+            builder.compound_name(name).up_sql(up_sql);
+            if let Some(down_sql) = down_sql {
+                builder.down_sql(down_sql);
+            }
+            builder
+                .build()
+                .expect("migrations with a compound name always build")
+        })
+        .collect()
+}
+
+/// Where the generated file for `migration_dir` lives. The macro can only build this path by
+/// concatenating literals, so the directory must stay inside `OUT_DIR`.
+fn output_path(out_dir: &Path, migration_dir: &str) -> Result<PathBuf> {
+    let dir = Path::new(migration_dir);
+    let is_nested = dir
+        .components()
+        .all(|x| matches!(x, Component::Normal(_) | Component::CurDir));
+    if !is_nested || dir.file_name().is_none() {
+        return Err(Error::BadMigrationDir(migration_dir.into()));
+    }
+    Ok(out_dir.join(format!("movine_migrations/{}.rs", migration_dir)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// The generated file must stay inside OUT_DIR.
+    fn test_output_path() {
+        let out_dir = Path::new("/out");
+        assert_eq!(
+            output_path(out_dir, "migrations").unwrap(),
+            Path::new("/out/movine_migrations/migrations.rs")
+        );
+        assert!(output_path(out_dir, "db/migrations").is_ok());
+        assert!(output_path(out_dir, "../migrations").is_err());
+        assert!(output_path(out_dir, "/migrations").is_err());
+        assert!(output_path(out_dir, ".").is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::plan_builder::Step;
 use libsqlite3_sys::Error as SqliteLibError;
 use libsqlite3_sys::ErrorCode as SqliteLibErrorCode;
 use postgres::error::Error as PostgresError;
@@ -5,12 +6,18 @@ use rusqlite::Error as SqliteError;
 use std::error::Error as StdError;
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 use toml::de::Error as TomlError;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 pub enum Error {
     ConfigNotFound,
+    ProfileNotFound(String),
+    ProfileConfigNotFound {
+        profile: String,
+        file: String,
+    },
     PgParamError {
         user: bool,
         password: bool,
@@ -21,17 +28,62 @@ pub enum Error {
     SqliteParamError {
         file: bool,
     },
+    MysqlParamError {
+        user: bool,
+        password: bool,
+        database: bool,
+        host: bool,
+        port: bool,
+    },
     BadMigration,
     Unknown,
     AdaptorNotFound,
     MigrationDirNotFound,
+    BadMigrationDir(String),
+    MalformedMigration {
+        path: PathBuf,
+        reason: String,
+    },
+    OutDirNotSet,
     DirtyMigrations,
     DivergentMigration,
     UnrollbackableMigration,
+    BadPlanStep(String),
+    MigrationNotFound(String),
+    MigrationAlreadyApplied(String),
+    MigrationNotApplied(String),
+    TargetNotFound(String),
+    DivergentTarget(String),
+    LockTimeout,
+    AtomicUnsupported,
+    NonTransactionalMigration(String),
+    AcceptConflict(String),
+    BadHistoryEntry(String),
+    BadTableName(String),
+    /// A migration failed. `statement_index` counts from 0 and `line` from 1, both within the
+    /// migration's up or down SQL, and are only set if the database said where it failed.
+    MigrationFailed {
+        name: String,
+        step: Step,
+        statement_index: Option<usize>,
+        line: Option<usize>,
+        source: Box<Error>,
+    },
+    /// Rolling a migration back left the schema different to how it was before it ran.
+    SchemaNotRestored {
+        name: String,
+        diff: String,
+    },
+    /// The live schema differs from the one its recorded migrations produce, in this many objects.
+    SchemaDrifted(usize),
+    ScratchDatabaseNotSet,
+    ScratchDatabaseNotEmpty,
     IoError(io::Error),
     TomlError(TomlError),
     PgError(PostgresError),
     SqliteError(SqliteError),
+    #[cfg(feature = "mysql")]
+    MysqlError(mysql::Error),
     Envy(envy::Error),
     #[cfg(feature = "with-native-tls")]
     NativeTlsError(native_tls::Error),
@@ -48,16 +100,50 @@ impl fmt::Debug for Error {
         use Error::*;
         match self {
             ConfigNotFound => write!(f, "`movine.toml` config file not found and no environment variables were found."),
+            ProfileNotFound(name) => write!(f, "Profile `{}` not found in the config file. Add it as `[profiles.{}]`.", name, name),
+            ProfileConfigNotFound { profile, file } => write!(f, "Profile `{}` was selected, but the config file `{}` it's defined in wasn't found.", profile, file),
             BadMigration => write!(f, "Error parsing migrations."),
             Unknown => write!(f, "Unknown error occurred"),
             AdaptorNotFound => write!(f, "Could not find adaptor"),
             MigrationDirNotFound => write!(f, "Could not find migration directory"),
+            BadMigrationDir(dir) => write!(
+                f,
+                "Migration directory {} must be a relative path inside the crate",
+                dir
+            ),
+            MalformedMigration { path, reason } => {
+                write!(f, "Malformed migration {}: {}.", path.display(), reason)
+            }
+            OutDirNotSet => write!(
+                f,
+                "OUT_DIR is not set; migrations can only be embedded from a build script"
+            ),
             DirtyMigrations => write!(f, "More recent migrations exist in the database than the pending migrations. This is an error when run with --strict"),
             DivergentMigration => write!(f, "Divergent migration found. Run with --ignore-divergent to ignore divergent migrations."),
             UnrollbackableMigration => write!(f, "Can't rollback one of the migrations in the list. Consider changing your parameters or adding a `down.sql` migration."),
+            BadPlanStep(step) => write!(f, "Unable to parse plan step `{}`. Steps should look like `up:<migration>` or `down:<migration>`.", step),
+            MigrationNotFound(name) => write!(f, "Migration `{}` could not be found.", name),
+            MigrationAlreadyApplied(name) => write!(f, "Migration `{}` is already applied.", name),
+            MigrationNotApplied(name) => write!(f, "Migration `{}` is not applied.", name),
+            TargetNotFound(name) => write!(f, "Target migration `{}` could not be found.", name),
+            AtomicUnsupported => write!(f, "This database adaptor can't run a plan atomically."),
+            NonTransactionalMigration(name) => write!(f, "Migration `{}` can't be run in a transaction, so the plan can't be run atomically.", name),
+            AcceptConflict(name) => write!(f, "Migration `{}` changed in the database while waiting for confirmation, so nothing was accepted. Run `accept` again to review it.", name),
+            BadHistoryEntry(value) => write!(f, "Unexpected value `{}` in the migration history.", value),
+            BadTableName(name) => write!(f, "`{}` is not a valid table or schema name. Use only letters, digits and underscores.", name),
+            MigrationFailed { name, step, statement_index: Some(index), line: Some(line), source } => write!(f, "Migration `{}` failed at statement {} (line {} of its {} SQL): {}", name, index + 1, line, step.as_str(), source),
+            MigrationFailed { name, step, source, .. } => write!(f, "Migration `{}` failed running its {} SQL: {}", name, step.as_str(), source),
+            SchemaNotRestored { name, diff } => write!(f, "Rolling back migration `{}` didn't restore the schema it started with:\n{}", name, diff.trim_end()),
+            SchemaDrifted(count) => write!(f, "The database schema has drifted from its migrations: {} object(s) differ.", count),
+            ScratchDatabaseNotSet => write!(f, "This command needs an empty scratch database to apply the migrations to. Pass its URL with --scratch."),
+            ScratchDatabaseNotEmpty => write!(f, "The scratch database isn't empty. Migrations are applied to it, so pass the URL of an empty database with --scratch."),
+            LockTimeout => write!(f, "Timed out waiting for the migration lock. Another Movine instance may be running migrations."),
+            DivergentTarget(name) => write!(f, "Target migration `{}` is divergent. Run `fix` before migrating to it.", name),
             IoError(e) => write!(f, "IO Error: {}", e),
             TomlError(e) => write!(f, "Unable to read config file: {}", e),
             PgError(e) => write!(f, "Error in Postgres: {}", e),
+            #[cfg(feature = "mysql")]
+            MysqlError(e) => write!(f, "Error in MySQL: {}", e),
             SqliteError(e) => {
                 match e {
                     rusqlite::Error::SqliteFailure(SqliteLibError { code: SqliteLibErrorCode::APIMisuse, .. }, _) => {
@@ -83,6 +169,12 @@ impl fmt::Debug for Error {
                 write!(f, "Unable to load Postgres params. Please ensure you have the following defined:\nUser: {}\nPassword: {}\nDatabase: {}\nHost: {}\nPort: {}",
                     user, password, database, host, port)
             }
+            MysqlParamError {
+                user, password, database, host, port
+            } => {
+                write!(f, "Unable to load MySQL params. Please ensure you have the following defined:\nUser: {}\nPassword: {}\nDatabase: {}\nHost: {}\nPort: {}",
+                    user, password, database, host, port)
+            }
         }
     }
 }
@@ -117,6 +209,13 @@ impl From<SqliteError> for Error {
     }
 }
 
+#[cfg(feature = "mysql")]
+impl From<mysql::Error> for Error {
+    fn from(error: mysql::Error) -> Self {
+        Error::MysqlError(error)
+    }
+}
+
 impl From<envy::Error> for Error {
     fn from(error: envy::Error) -> Self {
         Error::Envy(error)
@@ -138,4 +237,11 @@ impl From<rustls::TLSError> for Error {
 }
 
 // Implements std::Error for ease of use outside of Movine
-impl StdError for Error {}
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::MigrationFailed { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
@@ -1,10 +1,12 @@
 use crate::errors::{Error, Result};
 use crate::migration::{Migration, MigrationBuilder};
 use std::fs;
-use std::fs::File;
-use std::io::Read;
+use std::fs::{File, OpenOptions};
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+const UP_MARKER: &str = "-- +movine up";
+const DOWN_MARKER: &str = "-- +movine down";
 
 pub struct FileHandler {
     migration_dir: PathBuf,
@@ -20,7 +22,7 @@ impl FileHandler {
     pub fn create_migration_directory(&self) -> Result<()> {
         let exists = self.migration_dir.exists();
         if !exists {
-            fs::create_dir(&self.migration_dir)?;
+            fs::create_dir_all(&self.migration_dir)?;
         }
         Ok(())
     }
@@ -45,7 +47,24 @@ impl FileHandler {
         Ok(())
     }
 
+    /// Write the migration as a single `<name>.sql` file with its up and down SQL separated by
+    /// section markers.
+    pub fn write_single_file_migration(&self, migration: &Migration) -> Result<()> {
+        let path = self.migration_dir.join(format!("{}.sql", migration.name));
+        let mut file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.write_all(render_single_file(migration).as_bytes())?;
+        Ok(())
+    }
+
     pub fn load_local_migrations(&self) -> Result<Vec<Migration>> {
+        self.migration_files()?
+            .iter()
+            .map(MigrationFiles::load)
+            .collect()
+    }
+
+    /// Find the files making up each migration without reading them.
+    pub fn migration_files(&self) -> Result<Vec<MigrationFiles>> {
         let directory = match fs::read_dir(&self.migration_dir) {
             Ok(dir) => dir,
             Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
@@ -59,29 +78,224 @@ impl FileHandler {
 
         for entry in directory {
             let entry = entry?;
-            let compound_name: String = entry.file_name().into_string().unwrap();
-
-            let mut up_path = entry.path();
-            let mut down_path = entry.path();
-            up_path.push("up.sql");
-            down_path.push("down.sql");
-
-            let mut file = File::open(up_path)?;
-            let mut up_sql = String::new();
-            file.read_to_string(&mut up_sql)?;
-
-            let mut file = File::open(down_path)?;
-            let mut down_sql = String::new();
-            file.read_to_string(&mut down_sql)?;
-
-            let migration = MigrationBuilder::new()
-                .compound_name(&compound_name)
-                .up_sql(&up_sql)
-                .down_sql(&down_sql)
-                .build()?;
-            migrations.push(migration);
+            let path = entry.path();
+            let name = entry.file_name();
+
+            if name.to_string_lossy().starts_with('.') {
+                warn!("Skipping hidden entry {}", path.display());
+                continue;
+            }
+            let is_single_file = path.is_file() && path.extension().is_some_and(|x| x == "sql");
+            if !is_single_file && !path.is_dir() {
+                warn!("Skipping {}, which is not a migration", path.display());
+                continue;
+            }
+
+            let name = match path.file_stem().and_then(|x| x.to_str()) {
+                Some(name) if is_single_file => name.to_owned(),
+                _ => name.into_string().map_err(|_| Error::MalformedMigration {
+                    path: path.clone(),
+                    reason: "its name is not valid UTF-8".into(),
+                })?,
+            };
+            if is_single_file {
+                migrations.push(MigrationFiles::SingleFile { name, path });
+            } else {
+                migrations.push(MigrationFiles::Directory {
+                    name,
+                    up_path: path.join("up.sql"),
+                    down_path: path.join("down.sql"),
+                    path,
+                });
+            }
         }
 
         Ok(migrations)
     }
 }
+
+/// Where a local migration's SQL lives: either `<name>/up.sql` and an optional `<name>/down.sql`,
+/// or a single `<name>.sql` file with `-- +movine Up` and `-- +movine Down` sections.
+pub enum MigrationFiles {
+    Directory {
+        name: String,
+        path: PathBuf,
+        up_path: PathBuf,
+        down_path: PathBuf,
+    },
+    SingleFile {
+        name: String,
+        path: PathBuf,
+    },
+}
+
+impl MigrationFiles {
+    pub fn name(&self) -> &str {
+        match self {
+            MigrationFiles::Directory { name, .. } => name,
+            MigrationFiles::SingleFile { name, .. } => name,
+        }
+    }
+
+    /// Read the up and down SQL. The down SQL is `None` if the migration has no `down.sql`.
+    pub fn read(&self) -> Result<(String, Option<String>)> {
+        match self {
+            MigrationFiles::Directory {
+                path,
+                up_path,
+                down_path,
+                ..
+            } => {
+                let up_sql = read_file(up_path)?.ok_or_else(|| Error::MalformedMigration {
+                    path: path.clone(),
+                    reason: "missing up.sql".into(),
+                })?;
+                Ok((up_sql, read_file(down_path)?))
+            }
+            MigrationFiles::SingleFile { path, .. } => {
+                let sql = fs::read_to_string(path)?;
+                let (up_sql, down_sql) =
+                    parse_single_file(&sql).map_err(|reason| Error::MalformedMigration {
+                        path: path.clone(),
+                        reason: reason.into(),
+                    })?;
+                Ok((up_sql, Some(down_sql)))
+            }
+        }
+    }
+
+    pub fn load(&self) -> Result<Migration> {
+        let (up_sql, down_sql) = self.read()?;
+        let mut builder = MigrationBuilder::new();
+        builder.compound_name(self.name()).up_sql(&up_sql);
+        if let Some(down_sql) = down_sql {
+            builder.down_sql(&down_sql);
+        }
+        builder.build()
+    }
+}
+
+/// Read a file, or `None` if it doesn't exist.
+fn read_file(path: &Path) -> Result<Option<String>> {
+    match fs::read_to_string(path) {
+        Ok(sql) => Ok(Some(sql)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn render_single_file(migration: &Migration) -> String {
+    let mut sql = String::from("-- +movine Up\n");
+    if let Some(up_sql) = &migration.up_sql {
+        sql.push_str(up_sql);
+        if !up_sql.is_empty() && !up_sql.ends_with('\n') {
+            sql.push('\n');
+        }
+    }
+    sql.push_str("\n-- +movine Down\n");
+    if let Some(down_sql) = &migration.down_sql {
+        sql.push_str(down_sql);
+    }
+    sql
+}
+
+/// Split a single-file migration into its up and down SQL. Only comments may come before the
+/// `-- +movine Up` marker, and a migration without a `-- +movine Down` section is unreversable.
+fn parse_single_file(sql: &str) -> std::result::Result<(String, String), &'static str> {
+    let mut up_sql: Option<String> = None;
+    let mut down_sql: Option<String> = None;
+
+    for line in sql.split_inclusive('\n') {
+        let marker = line.trim().to_lowercase();
+        if marker == UP_MARKER {
+            if up_sql.is_some() || down_sql.is_some() {
+                return Err("the `-- +movine Up` marker must appear once, before the down section");
+            }
+            up_sql = Some(String::new());
+        } else if marker == DOWN_MARKER {
+            if up_sql.is_none() || down_sql.is_some() {
+                return Err("the `-- +movine Down` marker must appear once, after the up section");
+            }
+            down_sql = Some(String::new());
+        } else if let Some(down_sql) = &mut down_sql {
+            down_sql.push_str(line);
+        } else if let Some(up_sql) = &mut up_sql {
+            up_sql.push_str(line);
+        } else if !(marker.is_empty() || marker.starts_with("--")) {
+            return Err("found SQL before the `-- +movine Up` marker");
+        }
+    }
+
+    let up_sql = up_sql.ok_or("missing a `-- +movine Up` marker")?;
+    Ok((up_sql, down_sql.unwrap_or_default()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// The markers should split the file into up and down SQL, ignoring comments before them.
+    fn test_parse_single_file() {
+        let sql = "-- Adds the users table\n\
+                   -- +movine Up\n\
+                   CREATE TABLE users ();\n\
+                   \n\
+                   -- +Movine Down\n\
+                   DROP TABLE users;\n";
+        assert_eq!(
+            parse_single_file(sql).unwrap(),
+            (
+                "CREATE TABLE users ();\n\n".to_owned(),
+                "DROP TABLE users;\n".to_owned()
+            )
+        );
+
+        let sql = "-- +movine Up\nCREATE TABLE users ();\n";
+        assert_eq!(
+            parse_single_file(sql).unwrap(),
+            ("CREATE TABLE users ();\n".to_owned(), "".to_owned())
+        );
+
+        assert!(parse_single_file("CREATE TABLE users ();").is_err());
+        assert!(parse_single_file("DROP TABLE users;\n-- +movine Up\n").is_err());
+        assert!(parse_single_file("-- +movine Down\n-- +movine Up\n").is_err());
+        assert!(parse_single_file("-- +movine Up\n-- +movine Up\n").is_err());
+    }
+
+    #[test]
+    /// Writing a migration out as a single file and reading it back should keep its SQL.
+    fn test_render_single_file() {
+        let migration = MigrationBuilder::new()
+            .compound_name("2020-01-02-030405_test")
+            .up_sql("CREATE TABLE users ();\n")
+            .down_sql("DROP TABLE users;\n")
+            .build()
+            .unwrap();
+        let (up_sql, down_sql) = parse_single_file(&render_single_file(&migration)).unwrap();
+        assert_eq!(up_sql.trim(), "CREATE TABLE users ();");
+        assert_eq!(down_sql, "DROP TABLE users;\n");
+    }
+
+    #[test]
+    /// Stray files and hidden entries are skipped, and a missing down.sql is unreversable.
+    fn test_migration_files() {
+        let dir = std::env::temp_dir().join(format!("movine-test-{}", std::process::id()));
+        let migration = dir.join("2020-01-02-030405_test");
+        fs::create_dir_all(&migration).unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(migration.join("up.sql"), "CREATE TABLE users ();").unwrap();
+        fs::write(dir.join("README.md"), "# Migrations").unwrap();
+        fs::write(dir.join(".DS_Store"), "").unwrap();
+
+        let file_handler = FileHandler::new(dir.to_str().unwrap());
+        let migrations = file_handler.load_local_migrations().unwrap();
+        assert_eq!(migrations.len(), 1);
+        assert!(!migrations[0].is_reversable());
+
+        fs::create_dir(dir.join("2020-01-02-030406_empty")).unwrap();
+        let result = file_handler.load_local_migrations();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(result, Err(Error::MalformedMigration { .. })));
+    }
+}
@@ -0,0 +1,122 @@
+use crate::errors::{Error, Result};
+use crate::plan_builder::Step;
+use chrono::prelude::*;
+use serde::Serialize;
+
+/// Whether a step recorded in the history succeeded.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Success => "success",
+            Outcome::Failure => "failure",
+        }
+    }
+
+    pub(crate) fn parse(outcome: &str) -> Result<Self> {
+        match outcome {
+            "success" => Ok(Outcome::Success),
+            "failure" => Ok(Outcome::Failure),
+            _ => Err(Error::BadHistoryEntry(outcome.to_owned())),
+        }
+    }
+}
+
+/// A single up or down step recorded in the migration history.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HistoryEntry {
+    pub name: String,
+    pub direction: Step,
+    pub hash: Option<String>,
+    pub actor: Option<String>,
+    pub outcome: Outcome,
+    pub recorded_at: Option<DateTime<Utc>>,
+    pub duration_ms: Option<i64>,
+    pub movine_version: Option<String>,
+}
+
+/// Which history entries to return. Every entry matches the default filter.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HistoryFilter {
+    /// Only entries for migrations whose name contains this.
+    pub name: Option<String>,
+    /// Only entries recorded at or after this time.
+    pub since: Option<DateTime<Utc>>,
+    /// Only entries recorded before this time.
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl HistoryFilter {
+    pub fn matches(&self, entry: &HistoryEntry) -> bool {
+        let name_matches = self
+            .name
+            .as_ref()
+            .is_none_or(|name| entry.name.contains(name.as_str()));
+        let since_matches = self
+            .since
+            .is_none_or(|since| entry.recorded_at.is_some_and(|x| x >= since));
+        let until_matches = self
+            .until
+            .is_none_or(|until| entry.recorded_at.is_some_and(|x| x < until));
+        name_matches && since_matches && until_matches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, recorded_at: DateTime<Utc>) -> HistoryEntry {
+        HistoryEntry {
+            name: name.to_owned(),
+            direction: Step::Up,
+            hash: None,
+            actor: None,
+            outcome: Outcome::Success,
+            recorded_at: Some(recorded_at),
+            duration_ms: None,
+            movine_version: None,
+        }
+    }
+
+    #[test]
+    /// Filters should match on part of the name and a half-open date range.
+    fn test_filter() {
+        let jan = Utc.with_ymd_and_hms(2020, 1, 1, 0, 0, 0).unwrap();
+        let feb = Utc.with_ymd_and_hms(2020, 2, 1, 0, 0, 0).unwrap();
+        let entry = entry("2020-01-01-000000_add_users", jan);
+
+        assert!(HistoryFilter::default().matches(&entry));
+
+        let filter = HistoryFilter {
+            name: Some("add_users".to_owned()),
+            ..HistoryFilter::default()
+        };
+        assert!(filter.matches(&entry));
+
+        let filter = HistoryFilter {
+            name: Some("drop_users".to_owned()),
+            ..HistoryFilter::default()
+        };
+        assert!(!filter.matches(&entry));
+
+        let filter = HistoryFilter {
+            since: Some(jan),
+            until: Some(feb),
+            ..HistoryFilter::default()
+        };
+        assert!(filter.matches(&entry));
+
+        let filter = HistoryFilter {
+            until: Some(jan),
+            ..HistoryFilter::default()
+        };
+        assert!(!filter.matches(&entry));
+    }
+}
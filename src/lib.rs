@@ -36,29 +36,56 @@ use chrono::prelude::*;
 extern crate log;
 
 pub mod adaptor;
+#[cfg(feature = "with-tokio-postgres")]
+mod async_movine;
 pub mod config;
+mod diff;
 mod display;
+pub mod embed;
 pub mod errors;
 mod file_handler;
+mod history;
 mod match_maker;
 mod migration;
+mod migration_source;
 mod plan_builder;
+mod schema;
+mod shared;
+mod statements;
 
-pub use adaptor::DbAdaptor;
+#[cfg(feature = "with-tokio-postgres")]
+pub use adaptor::AsyncDbAdaptor;
+pub use adaptor::{DbAdaptor, MigrationTable};
+#[cfg(feature = "with-tokio-postgres")]
+pub use async_movine::AsyncMovine;
 pub use config::Config;
-use errors::{Error, Result};
-use file_handler::FileHandler;
-use migration::MigrationBuilder;
+pub use display::OutputFormat;
+use errors::Result;
+pub use file_handler::FileHandler;
+pub use history::{HistoryEntry, HistoryFilter, Outcome};
+pub use match_maker::{MigrationState, MigrationStatus};
+pub use migration::{Migration, MigrationBuilder};
+pub use migration_source::MigrationSource;
 use plan_builder::PlanBuilder;
+pub use plan_builder::{OwnedPlan, Step};
+pub use schema::{SchemaDrift, SchemaObject};
+use shared::{assign_actor, to_owned_plan, PlanOptions};
+use std::time::Duration;
 
 pub struct Movine<T> {
     adaptor: T,
     migration_dir: String,
-    number: Option<usize>,
+    source: Option<Box<dyn MigrationSource + Send + Sync>>,
+    plan: PlanOptions,
     show_plan: bool,
-    ignore_divergent: bool,
-    ignore_unreversable: bool,
-    strict: bool,
+    lock_timeout: Option<Duration>,
+    atomic: bool,
+    format: OutputFormat,
+    actor: Option<String>,
+    table_name: String,
+    schema: Option<String>,
+    single_file: bool,
+    schema_file: Option<String>,
 }
 
 impl<T: DbAdaptor> Movine<T> {
@@ -66,21 +93,58 @@ impl<T: DbAdaptor> Movine<T> {
         Self {
             adaptor,
             migration_dir: "./migrations".into(),
-            number: None,
+            source: None,
+            plan: PlanOptions::default(),
             show_plan: false,
-            ignore_divergent: false,
-            ignore_unreversable: false,
-            strict: false,
+            lock_timeout: None,
+            atomic: false,
+            format: OutputFormat::Text,
+            actor: None,
+            table_name: MigrationTable::default().name().into(),
+            schema: None,
+            single_file: false,
+            schema_file: None,
         }
     }
 
+    /// Use the given migrations, such as those from [`embed_migrations!`], instead of reading the
+    /// migration directory. `generate` still writes to the migration directory.
+    pub fn with_migrations(adaptor: T, migrations: Vec<Migration>) -> Self {
+        Self::with_source(adaptor, migrations)
+    }
+
+    /// Load local migrations from `source` instead of the migration directory.
+    pub fn with_source<S>(adaptor: T, source: S) -> Self
+    where
+        S: MigrationSource + Send + Sync + 'static,
+    {
+        let mut movine = Self::new(adaptor);
+        movine.set_migration_source(source);
+        movine
+    }
+
     pub fn set_migration_dir(&mut self, migration_dir: &str) -> &mut Self {
         self.migration_dir = migration_dir.into();
         self
     }
 
+    /// Load local migrations from `source` instead of the migration directory.
+    pub fn set_migration_source<S>(&mut self, source: S) -> &mut Self
+    where
+        S: MigrationSource + Send + Sync + 'static,
+    {
+        self.source = Some(Box::new(source));
+        self
+    }
+
     pub fn set_number(&mut self, number: Option<usize>) -> &mut Self {
-        self.number = number;
+        self.plan.number = number;
+        self
+    }
+
+    /// Migrate up to and including, or down to but excluding, the named migration.
+    pub fn set_target(&mut self, target: Option<&str>) -> &mut Self {
+        self.plan.target = target.map(String::from);
         self
     }
 
@@ -90,49 +154,90 @@ impl<T: DbAdaptor> Movine<T> {
     }
 
     pub fn set_ignore_divergent(&mut self, ignore_divergent: bool) -> &mut Self {
-        self.ignore_divergent = ignore_divergent;
+        self.plan.ignore_divergent = ignore_divergent;
         self
     }
 
     pub fn set_ignore_unreversable(&mut self, ignore_unreversable: bool) -> &mut Self {
-        self.ignore_unreversable = ignore_unreversable;
+        self.plan.ignore_unreversable = ignore_unreversable;
         self
     }
 
     pub fn set_strict(&mut self, strict: bool) -> &mut Self {
-        self.strict = strict;
+        self.plan.strict = strict;
         self
     }
 
-    pub fn initialize(&mut self) -> Result<()> {
-        let file_handler = FileHandler::new(&self.migration_dir);
-        file_handler.create_migration_directory()?;
-        let up_sql = self.adaptor.init_up_sql();
-        let down_sql = self.adaptor.init_down_sql();
-
-        let init_migration = MigrationBuilder::new()
-            .name(&"movine_init")
-            .date(Utc.timestamp_opt(0, 0).unwrap())
-            .up_sql(&up_sql)
-            .down_sql(&down_sql)
-            .build()?;
+    /// How long to wait for another Movine instance to finish running migrations before giving
+    /// up. Waits forever if `None`.
+    pub fn set_lock_timeout(&mut self, lock_timeout: Option<Duration>) -> &mut Self {
+        self.lock_timeout = lock_timeout;
+        self
+    }
 
-        match file_handler.write_migration(&init_migration) {
-            Ok(_) => {}
-            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
-            x => x?,
-        }
+    /// Run the whole plan in a single transaction instead of one transaction per migration.
+    pub fn set_atomic(&mut self, atomic: bool) -> &mut Self {
+        self.atomic = atomic;
+        self
+    }
+
+    /// Format used when printing the status or a migration plan. With JSON, progress such as
+    /// each step as it runs goes to stderr, for the whole process, so that stdout is only JSON.
+    pub fn set_output_format(&mut self, format: OutputFormat) -> &mut Self {
+        display::set_output_format(format);
+        self.format = format;
+        self
+    }
+
+    /// Who to record as having applied migrations. Defaults to the database user where the
+    /// database has one.
+    pub fn set_actor(&mut self, actor: Option<&str>) -> &mut Self {
+        self.actor = actor.map(String::from);
+        self
+    }
+
+    /// Name of the table applied migrations are recorded in. Defaults to `movine_migrations`.
+    pub fn set_table_name(&mut self, table_name: &str) -> &mut Self {
+        self.table_name = table_name.into();
+        self
+    }
+
+    /// Schema the migrations table lives in. Defaults to the connection's current schema.
+    pub fn set_schema(&mut self, schema: Option<&str>) -> &mut Self {
+        self.schema = schema.map(String::from);
+        self
+    }
+
+    /// Write new migrations as a single `<name>.sql` file with `-- +movine Up` and
+    /// `-- +movine Down` sections, rather than a directory with `up.sql` and `down.sql`.
+    pub fn set_single_file(&mut self, single_file: bool) -> &mut Self {
+        self.single_file = single_file;
+        self
+    }
+
+    /// After a successful `up`, write a dump of the database schema to `schema_file` so that it
+    /// can be committed alongside the migrations.
+    pub fn set_schema_file(&mut self, schema_file: Option<&str>) -> &mut Self {
+        self.schema_file = schema_file.map(String::from);
+        self
+    }
+
+    pub fn initialize(&mut self) -> Result<()> {
+        self.with_lock(|movine| {
+            if movine.source.is_none() {
+                let table = movine.table()?;
+                shared::write_init_migration(
+                    &movine.migration_dir,
+                    movine.single_file,
+                    &movine.adaptor.init_up_sql(&table),
+                    &movine.adaptor.init_down_sql(&table),
+                )?;
+            }
 
-        // Can't just call to `up` function since we are unable to get
-        // database migrations until we run this migration.
-        let local_migrations = file_handler.load_local_migrations()?;
-        let db_migrations = Vec::new();
-        let plan = PlanBuilder::new()
-            .local_migrations(&local_migrations)
-            .db_migrations(&db_migrations)
-            .count(Some(1)) // Just want to run a single migration (the init one)
-            .up()?;
-        self.adaptor.run_migration_plan(&plan)
+            let local_migrations = movine.load_local_migrations()?;
+            let plan = shared::plan_init(&local_migrations)?;
+            movine.run_plan(plan)
+        })
     }
 
     pub fn generate(&mut self, name: &str) -> Result<()> {
@@ -141,100 +246,320 @@ impl<T: DbAdaptor> Movine<T> {
             .name(name)
             .date(Utc::now())
             .build()?;
-        file_handler.write_migration(&new_migration)
+        shared::write_migration(&file_handler, &new_migration, self.single_file)
     }
 
     pub fn status(&mut self) -> Result<()> {
-        let file_handler = FileHandler::new(&self.migration_dir);
-        let local_migrations = file_handler.load_local_migrations()?;
-        let db_migrations = self.adaptor.load_migrations()?;
+        let status = self.get_status()?;
+        shared::print_status(self.format, &status);
+        Ok(())
+    }
 
-        let status = PlanBuilder::new()
-            .local_migrations(&local_migrations)
-            .db_migrations(&db_migrations)
-            .status()?;
+    /// Get the state of every local and database migration, oldest first.
+    pub fn get_status(&mut self) -> Result<Vec<MigrationStatus>> {
+        let (local_migrations, db_migrations) = self.load_migrations()?;
+        shared::status(&local_migrations, &db_migrations)
+    }
 
-        display::print_status(&status);
+    pub fn history(&mut self, filter: &HistoryFilter) -> Result<()> {
+        let history = self.get_history(filter)?;
+        shared::print_history(self.format, &history);
         Ok(())
     }
 
+    /// Get every up and down step recorded in the database that matches `filter`, oldest first.
+    pub fn get_history(&mut self, filter: &HistoryFilter) -> Result<Vec<HistoryEntry>> {
+        let table = self.table()?;
+        let history = self.adaptor.load_history(&table)?;
+        Ok(history.into_iter().filter(|x| filter.matches(x)).collect())
+    }
+
     pub fn up(&mut self) -> Result<()> {
-        let file_handler = FileHandler::new(&self.migration_dir);
-        let local_migrations = file_handler.load_local_migrations()?;
-        let db_migrations = self.adaptor.load_migrations()?;
+        self.execute(Self::get_plan_up)?;
+        self.write_schema_file()
+    }
 
-        let plan = PlanBuilder::new()
-            .local_migrations(&local_migrations)
-            .db_migrations(&db_migrations)
-            .count(self.number)
-            .set_strict(self.strict)
-            .up()?;
+    pub fn down(&mut self) -> Result<()> {
+        self.execute(Self::get_plan_down)
+    }
 
-        if self.show_plan {
-            display::print_plan(&plan);
-            Ok(())
-        } else {
-            self.adaptor.run_migration_plan(&plan)
-        }
+    pub fn fix(&mut self) -> Result<()> {
+        self.execute(Self::get_plan_fix)
     }
 
-    pub fn down(&mut self) -> Result<()> {
-        let file_handler = FileHandler::new(&self.migration_dir);
-        let local_migrations = file_handler.load_local_migrations()?;
-        let db_migrations = self.adaptor.load_migrations()?;
+    pub fn redo(&mut self) -> Result<()> {
+        self.execute(Self::get_plan_redo)
+    }
+
+    /// Run an explicit list of steps such as `up:<migration>` or `down:<migration>`. Blank entries
+    /// and entries starting with `#` are ignored so plan files can be passed in line by line.
+    pub fn custom(&mut self, steps: &[String]) -> Result<()> {
+        let steps = steps
+            .iter()
+            .map(|x| x.trim())
+            .filter(|x| !x.is_empty() && !x.starts_with('#'))
+            .map(plan_builder::parse_step)
+            .collect::<Result<Vec<_>>>()?;
 
-        let plan = PlanBuilder::new()
-            .local_migrations(&local_migrations)
-            .db_migrations(&db_migrations)
-            .count(self.number)
-            .set_ignore_divergent(self.ignore_divergent)
-            .set_ignore_unreversable(self.ignore_unreversable)
-            .down()?;
+        self.execute(|movine| {
+            let (local_migrations, db_migrations) = movine.load_migrations()?;
 
+            let plan = PlanBuilder::new()
+                .local_migrations(&local_migrations)
+                .db_migrations(&db_migrations)
+                .custom(&steps)?;
+
+            Ok(to_owned_plan(&plan))
+        })
+    }
+
+    /// Record every pending local migration up to and including `target` as applied, without
+    /// running its SQL. Use this after `init` to start using Movine on a database that already
+    /// has its schema.
+    pub fn baseline(&mut self, target: &str) -> Result<()> {
         if self.show_plan {
-            display::print_plan(&plan);
+            let plan = self.get_plan_baseline(target)?;
+            shared::print_baseline_plan(self.format, &plan);
+            return Ok(());
+        }
+
+        self.with_lock(|movine| {
+            movine.upgrade_legacy()?;
+            let mut plan = movine.get_plan_baseline(target)?;
+            assign_actor(&mut plan, movine.actor.as_deref());
+            let table = movine.table()?;
+            for (_, migration) in &plan {
+                display::print_baseline(migration);
+                movine.adaptor.baseline_migration(&table, migration)?;
+            }
             Ok(())
-        } else {
-            self.adaptor.run_migration_plan(&plan)
+        })
+    }
+
+    /// Accept intentional edits to the applied migration `name` without running anything. Shows
+    /// how it differs from what the database recorded and then, if `confirm` returns true,
+    /// records the local hash (and down SQL if `update_down_sql` is set) so it is no longer
+    /// variant. The lock is only taken once `confirm` returns, and nothing is recorded if the
+    /// migration changed in the database in the meantime.
+    pub fn accept<F>(&mut self, name: &str, update_down_sql: bool, confirm: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<bool>,
+    {
+        let (local_migrations, db_migrations) = self.load_migrations()?;
+        let acceptance = shared::accept_changes(
+            &local_migrations,
+            &db_migrations,
+            name,
+            update_down_sql,
+            confirm,
+        )?;
+        let acceptance = match acceptance {
+            Some(acceptance) => acceptance,
+            None => return Ok(()),
+        };
+
+        self.with_lock(|movine| {
+            movine.upgrade_legacy()?;
+            let (_, db_migrations) = movine.load_migrations()?;
+            acceptance.check_unchanged(&db_migrations)?;
+
+            let table = movine.table()?;
+            if let Some(down_sql) = &acceptance.down_sql {
+                movine
+                    .adaptor
+                    .update_migration_down_sql(&table, name, down_sql)?;
+            }
+            movine
+                .adaptor
+                .update_migration_hash(&table, name, &acceptance.hash)
+        })
+    }
+
+    /// Check that each pending migration can be rolled back, by applying the recorded migrations
+    /// to `scratch`, which must be an empty database, and then running each pending one up, down
+    /// and then up again there. Fails with the migration's name if its down SQL errors or leaves
+    /// the schema different to how it was before it ran. Migrations without down SQL are skipped.
+    /// The live database is only read from.
+    pub fn verify_reversibility<S: DbAdaptor>(&mut self, mut scratch: S) -> Result<()> {
+        let (local_migrations, db_migrations) = self.load_migrations()?;
+        let plan = self.plan.up(&local_migrations, &db_migrations)?;
+        let table = self.table()?;
+        shared::check_scratch_empty(&scratch.load_schema(&table)?)?;
+        for migration in shared::drift_migrations(&local_migrations, &db_migrations)? {
+            scratch.run_up_migration(&table, migration)?;
         }
+
+        for (_, migration) in &plan {
+            if !migration.is_reversable() {
+                display::print_skipped(migration);
+                scratch.run_up_migration(&table, migration)?;
+                continue;
+            }
+
+            let before = scratch.dump_schema(&table)?;
+            for step in [Step::Up, Step::Down, Step::Up] {
+                display::print_step(&(step, migration));
+                let result = match step {
+                    Step::Up => scratch.run_up_migration(&table, migration),
+                    Step::Down => scratch.run_down_migration(&table, migration),
+                };
+                if let Err(e) = &result {
+                    display::print_migration_failure(&[(step, migration)], e);
+                }
+                result?;
+
+                if step == Step::Down {
+                    let after = scratch.dump_schema(&table)?;
+                    shared::check_restored(migration, &before, &after)?;
+                }
+            }
+        }
+        Ok(())
     }
 
-    pub fn fix(&mut self) -> Result<()> {
-        let file_handler = FileHandler::new(&self.migration_dir);
-        let local_migrations = file_handler.load_local_migrations()?;
-        let db_migrations = self.adaptor.load_migrations()?;
+    /// Compare the live schema with the one its recorded migrations produce when applied to
+    /// `scratch`, which should be an empty database. Fails if they differ.
+    pub fn drift<S: DbAdaptor>(&mut self, scratch: S) -> Result<()> {
+        let drift = self.get_drift(scratch)?;
+        shared::report_drift(&drift)
+    }
+
+    /// Get every difference between the live schema and the one its recorded migrations produce
+    /// when applied to `scratch`, which should be an empty database. The live database is only
+    /// read from.
+    pub fn get_drift<S: DbAdaptor>(&mut self, mut scratch: S) -> Result<Vec<SchemaDrift>> {
+        let (local_migrations, db_migrations) = self.load_migrations()?;
+        let table = self.table()?;
+        shared::check_scratch_empty(&scratch.load_schema(&table)?)?;
+        for migration in shared::drift_migrations(&local_migrations, &db_migrations)? {
+            scratch.run_up_migration(&table, migration)?;
+        }
+
+        let expected = scratch.load_schema(&table)?;
+        let live = self.adaptor.load_schema(&table)?;
+        Ok(schema::compare(&expected, &live))
+    }
+
+    /// Release the migration lock left behind by an instance that stopped without releasing it.
+    /// Only do this once you're sure no other instance is running migrations.
+    pub fn unlock(&mut self) -> Result<()> {
+        let table = self.table()?;
+        self.adaptor.break_lock(&table)
+    }
 
-        let plan = PlanBuilder::new()
-            .local_migrations(&local_migrations)
-            .db_migrations(&db_migrations)
-            .fix()?;
+    /// Get the migrations `baseline` would record as applied, without recording them.
+    pub fn get_plan_baseline(&mut self, target: &str) -> Result<OwnedPlan> {
+        let (local_migrations, db_migrations) = self.load_migrations()?;
+        shared::plan_baseline(&local_migrations, &db_migrations, target)
+    }
+
+    /// Get the plan `up` would run with the current settings, without running it.
+    pub fn get_plan_up(&mut self) -> Result<OwnedPlan> {
+        let (local_migrations, db_migrations) = self.load_migrations()?;
+        self.plan.up(&local_migrations, &db_migrations)
+    }
+
+    /// Get the plan `down` would run with the current settings, without running it.
+    pub fn get_plan_down(&mut self) -> Result<OwnedPlan> {
+        let (local_migrations, db_migrations) = self.load_migrations()?;
+        self.plan.down(&local_migrations, &db_migrations)
+    }
+
+    /// Get the plan `fix` would run, without running it.
+    pub fn get_plan_fix(&mut self) -> Result<OwnedPlan> {
+        let (local_migrations, db_migrations) = self.load_migrations()?;
+        self.plan.fix(&local_migrations, &db_migrations)
+    }
 
+    /// Get the plan `redo` would run with the current settings, without running it.
+    pub fn get_plan_redo(&mut self) -> Result<OwnedPlan> {
+        let (local_migrations, db_migrations) = self.load_migrations()?;
+        self.plan.redo(&local_migrations, &db_migrations)
+    }
+
+    /// Build a plan with `get_plan` and then either show or run it. Runs hold the migration lock
+    /// and upgrade any legacy tables and hashes before planning.
+    fn execute<F>(&mut self, get_plan: F) -> Result<()>
+    where
+        F: FnOnce(&mut Self) -> Result<OwnedPlan>,
+    {
         if self.show_plan {
-            display::print_plan(&plan);
-            Ok(())
+            let plan = get_plan(self)?;
+            shared::print_plan(self.format, &plan);
+            return Ok(());
+        }
+
+        self.with_lock(|movine| {
+            movine.upgrade_legacy()?;
+            let plan = get_plan(movine)?;
+            movine.run_plan(plan)
+        })
+    }
+
+    fn run_plan(&mut self, mut plan: OwnedPlan) -> Result<()> {
+        let table = self.table()?;
+        assign_actor(&mut plan, self.actor.as_deref());
+        let plan: Vec<_> = plan.iter().map(|(step, x)| (*step, x)).collect();
+        let result = if self.atomic {
+            shared::check_atomic(&plan)?;
+            self.adaptor.run_atomic_migration_plan(&table, &plan)
         } else {
-            self.adaptor.run_migration_plan(&plan)
+            self.adaptor.run_migration_plan(&table, &plan)
+        };
+        if let Err(e) = &result {
+            display::print_migration_failure(&plan, e);
         }
+        result
     }
 
-    pub fn redo(&mut self) -> Result<()> {
-        let file_handler = FileHandler::new(&self.migration_dir);
-        let local_migrations = file_handler.load_local_migrations()?;
-        let db_migrations = self.adaptor.load_migrations()?;
+    /// Run `f` while holding the database's migration lock.
+    fn with_lock<F>(&mut self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut Self) -> Result<()>,
+    {
+        let table = self.table()?;
+        self.adaptor.acquire_lock(&table, self.lock_timeout)?;
+        let result = f(self);
+        let released = self.adaptor.release_lock(&table);
+        result.and(released)
+    }
 
-        let plan = PlanBuilder::new()
-            .local_migrations(&local_migrations)
-            .db_migrations(&db_migrations)
-            .count(self.number)
-            .set_ignore_divergent(self.ignore_divergent)
-            .set_ignore_unreversable(self.ignore_unreversable)
-            .redo()?;
+    fn write_schema_file(&mut self) -> Result<()> {
+        let schema_file = match &self.schema_file {
+            Some(schema_file) if !self.show_plan => schema_file.clone(),
+            _ => return Ok(()),
+        };
+        let table = self.table()?;
+        let schema = self.adaptor.dump_schema(&table)?;
+        shared::write_schema_file(&schema_file, &schema)
+    }
 
-        if self.show_plan {
-            display::print_plan(&plan);
-            Ok(())
-        } else {
-            self.adaptor.run_migration_plan(&plan)
+    fn table(&self) -> Result<MigrationTable> {
+        MigrationTable::new(self.schema.as_deref(), &self.table_name)
+    }
+
+    fn load_local_migrations(&self) -> Result<Vec<Migration>> {
+        shared::load_local_migrations(&self.source, &self.migration_dir)
+    }
+
+    fn load_migrations(&mut self) -> Result<(Vec<Migration>, Vec<Migration>)> {
+        let local_migrations = self.load_local_migrations()?;
+        let table = self.table()?;
+        let db_migrations = self.adaptor.load_migrations(&table)?;
+        Ok((local_migrations, db_migrations))
+    }
+
+    /// Upgrade tables created by older versions of Movine, and rewrite the hashes they recorded
+    /// once we've confirmed the local migration still has the same content. Only run while
+    /// holding the lock, before writing to the migrations table.
+    fn upgrade_legacy(&mut self) -> Result<()> {
+        let table = self.table()?;
+        self.adaptor.upgrade_schema(&table)?;
+        let (local_migrations, db_migrations) = self.load_migrations()?;
+        for (name, hash) in shared::legacy_hash_upgrades(&local_migrations, &db_migrations) {
+            debug!("Upgrading legacy hash for {}", name);
+            self.adaptor.update_migration_hash(&table, name, hash)?;
         }
+        Ok(())
     }
 }
@@ -1,49 +1,103 @@
-use movine::config::Config;
-use movine::errors::Result;
+use movine::config::{Config, SqliteParams};
+use movine::errors::{Error, Result};
 use movine::DbAdaptor;
+use movine::HistoryFilter;
 use movine::Movine;
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Duration;
 use structopt::StructOpt;
 
 mod cli;
-use cli::Opt;
+use cli::{Cli, GlobalOpt, Opt};
 
 fn main() -> Result<()> {
-    match Opt::from_args() {
-        Opt::Init { debug } => {
-            let mut movine = setup(debug)?;
-            movine.initialize()
+    enter_directory(env::args_os())?;
+    let Cli { global, command } = Cli::from_args();
+    let global = &global;
+    match command {
+        Opt::Init {
+            actor,
+            lock_timeout,
+            debug,
+        } => {
+            let mut movine = setup(global, debug)?;
+            movine
+                .set_lock_timeout(lock_timeout.map(Duration::from_secs))
+                .set_actor(actor.as_deref())
+                .initialize()
         }
-        Opt::Generate { name, debug } => {
-            let mut movine = setup(debug)?;
+        Opt::Generate {
+            name,
+            single_file,
+            debug,
+        } => {
+            let mut movine = setup(global, debug)?;
+            if single_file {
+                movine.set_single_file(true);
+            }
             movine.generate(&name)
         }
-        Opt::Status { debug } => {
-            let mut movine = setup(debug)?;
-            movine.status()
+        Opt::Status { format, debug } => {
+            let mut movine = setup(global, debug)?;
+            movine.set_output_format(format).status()
+        }
+        Opt::History {
+            name,
+            since,
+            until,
+            format,
+            debug,
+        } => {
+            let mut movine = setup(global, debug)?;
+            let filter = HistoryFilter { name, since, until };
+            movine.set_output_format(format).history(&filter)
         }
         Opt::Up {
             number,
+            target,
             show_plan,
             debug,
             strict,
+            atomic,
+            actor,
+            lock_timeout,
+            format,
         } => {
-            let mut movine = setup(debug)?;
+            let mut movine = setup(global, debug)?;
             movine
+                .set_lock_timeout(lock_timeout.map(Duration::from_secs))
+                .set_actor(actor.as_deref())
+                .set_atomic(atomic)
+                .set_output_format(format)
                 .set_number(number)
+                .set_target(target.as_deref())
                 .set_strict(strict)
                 .set_show_plan(show_plan)
                 .up()
         }
         Opt::Down {
             number,
+            target,
             show_plan,
             ignore_divergent,
             ignore_unreversable,
+            atomic,
+            actor,
+            lock_timeout,
+            format,
             debug,
         } => {
-            let mut movine = setup(debug)?;
+            let mut movine = setup(global, debug)?;
             movine
+                .set_lock_timeout(lock_timeout.map(Duration::from_secs))
+                .set_actor(actor.as_deref())
+                .set_atomic(atomic)
+                .set_output_format(format)
                 .set_number(number)
+                .set_target(target.as_deref())
                 .set_show_plan(show_plan)
                 .set_ignore_divergent(ignore_divergent)
                 .set_ignore_unreversable(ignore_unreversable)
@@ -54,26 +108,176 @@ fn main() -> Result<()> {
             show_plan,
             ignore_divergent,
             ignore_unreversable,
+            atomic,
+            actor,
+            lock_timeout,
+            format,
             debug,
         } => {
-            let mut movine = setup(debug)?;
+            let mut movine = setup(global, debug)?;
             movine
+                .set_lock_timeout(lock_timeout.map(Duration::from_secs))
+                .set_actor(actor.as_deref())
+                .set_atomic(atomic)
+                .set_output_format(format)
                 .set_number(number)
                 .set_ignore_divergent(ignore_divergent)
                 .set_ignore_unreversable(ignore_unreversable)
                 .set_show_plan(show_plan)
                 .redo()
         }
-        Opt::Fix { show_plan, debug } => {
-            let mut movine = setup(debug)?;
-            movine.set_show_plan(show_plan).fix()
+        Opt::Fix {
+            show_plan,
+            atomic,
+            actor,
+            lock_timeout,
+            format,
+            debug,
+        } => {
+            let mut movine = setup(global, debug)?;
+            movine
+                .set_lock_timeout(lock_timeout.map(Duration::from_secs))
+                .set_actor(actor.as_deref())
+                .set_atomic(atomic)
+                .set_output_format(format)
+                .set_show_plan(show_plan)
+                .fix()
+        }
+        Opt::Custom {
+            show_plan,
+            file,
+            atomic,
+            actor,
+            lock_timeout,
+            format,
+            debug,
+            mut plan,
+        } => {
+            if let Some(file) = file {
+                let contents = std::fs::read_to_string(file)?;
+                plan.extend(contents.lines().map(String::from));
+            }
+            let mut movine = setup(global, debug)?;
+            movine
+                .set_lock_timeout(lock_timeout.map(Duration::from_secs))
+                .set_actor(actor.as_deref())
+                .set_atomic(atomic)
+                .set_output_format(format)
+                .set_show_plan(show_plan)
+                .custom(&plan)
+        }
+        Opt::Baseline {
+            target,
+            show_plan,
+            actor,
+            lock_timeout,
+            format,
+            debug,
+        } => {
+            let mut movine = setup(global, debug)?;
+            movine
+                .set_lock_timeout(lock_timeout.map(Duration::from_secs))
+                .set_actor(actor.as_deref())
+                .set_output_format(format)
+                .set_show_plan(show_plan)
+                .baseline(&target)
+        }
+        Opt::Accept {
+            name,
+            down_sql,
+            yes,
+            lock_timeout,
+            debug,
+        } => {
+            let mut movine = setup(global, debug)?;
+            movine
+                .set_lock_timeout(lock_timeout.map(Duration::from_secs))
+                .accept(&name, down_sql, || {
+                    Ok(yes || confirm("Accept these changes?")?)
+                })
+        }
+        Opt::Verify {
+            scratch,
+            number,
+            target,
+            debug,
+        } => {
+            let mut movine = setup(global, debug)?;
+            let scratch = scratch_adaptor(global, scratch)?;
+            movine
+                .set_number(number)
+                .set_target(target.as_deref())
+                .verify_reversibility(scratch)
+        }
+        Opt::Drift { scratch, debug } => {
+            let mut movine = setup(global, debug)?;
+            let scratch = scratch_adaptor(global, scratch)?;
+            movine.drift(scratch)
+        }
+        Opt::Unlock { debug } => {
+            let mut movine = setup(global, debug)?;
+            movine.unlock()
         }
-        _ => unimplemented!(),
     }
 }
 
-fn setup(debug: bool) -> Result<Movine<Box<dyn DbAdaptor>>> {
+/// Ask a yes or no question on the terminal, defaulting to no.
+fn confirm(question: &str) -> Result<bool> {
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes" | "Yes"))
+}
+
+/// Connect to the scratch database `drift` applies migrations to, of the same kind as the one
+/// being checked.
+fn scratch_adaptor(global: &GlobalOpt, scratch: Option<String>) -> Result<Box<dyn DbAdaptor>> {
+    let config = Config::load_profile(global.config_file(), global.env.as_deref())?;
+    let scratch = match (config.sqlite, scratch) {
+        (Some(_), scratch) => Config {
+            sqlite: Some(SqliteParams {
+                file: scratch.unwrap_or_else(|| ":memory:".into()),
+            }),
+            ..Config::default()
+        },
+        (None, Some(url)) => Config {
+            database_url: Some(url),
+            ..Config::default()
+        },
+        (None, None) => return Err(Error::ScratchDatabaseNotSet),
+    };
+    scratch.into_db_adaptor()
+}
+
+/// Move into the directory given by `-C` or `MOVINE_DIRECTORY`, and then load its `.env`. The
+/// arguments are parsed once beforehand just to find the directory, so that `.env` can still set
+/// the other options.
+fn enter_directory<I>(args: I) -> Result<()>
+where
+    I: IntoIterator,
+    I::Item: Into<OsString> + Clone,
+{
+    if let Some(directory) = find_directory(args) {
+        env::set_current_dir(directory)?;
+    }
     dotenv::dotenv().ok();
+    Ok(())
+}
+
+/// The directory given by `-C` or `MOVINE_DIRECTORY`, if any. `None` if the arguments don't
+/// parse, since the parse that follows reports why.
+fn find_directory<I>(args: I) -> Option<PathBuf>
+where
+    I: IntoIterator,
+    I::Item: Into<OsString> + Clone,
+{
+    Cli::from_iter_safe(args)
+        .ok()
+        .and_then(|x| x.global.directory)
+}
+
+fn setup(global: &GlobalOpt, debug: bool) -> Result<Movine<Box<dyn DbAdaptor>>> {
     env_logger::builder()
         .filter_level(if debug {
             log::LevelFilter::Debug
@@ -82,8 +286,45 @@ fn setup(debug: bool) -> Result<Movine<Box<dyn DbAdaptor>>> {
         })
         .init();
 
-    let config = Config::load(&"movine.toml")?;
+    let config = Config::load_profile(global.config_file(), global.env.as_deref())?;
+    let table = config.table.clone();
+    let schema = config.schema.clone();
+    let single_file = config.single_file;
+    let schema_file = config.schema_file.clone();
+    let migration_dir = global
+        .migrations_dir
+        .clone()
+        .or_else(|| config.migration_dir.clone());
     let adaptor = config.into_db_adaptor()?;
-    let movine = Movine::new(adaptor);
+    let mut movine = Movine::new(adaptor);
+    if let Some(table) = &table {
+        movine.set_table_name(table);
+    }
+    if let Some(migration_dir) = &migration_dir {
+        movine.set_migration_dir(migration_dir);
+    }
+    movine
+        .set_schema(schema.as_deref())
+        .set_single_file(single_file)
+        .set_schema_file(schema_file.as_deref());
     Ok(movine)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// `-C` should be found wherever it is among the arguments, and unparseable arguments should
+    /// be left for the main parse to report.
+    fn test_find_directory() {
+        let expected = Some(PathBuf::from("db"));
+        assert_eq!(find_directory(&["movine", "-C", "db", "status"]), expected);
+        assert_eq!(find_directory(&["movine", "status", "-C", "db"]), expected);
+        assert_eq!(
+            find_directory(&["movine", "status", "--directory=db"]),
+            expected
+        );
+        assert_eq!(find_directory(&["movine", "-C", "db", "bogus"]), None);
+    }
+}
@@ -1,4 +1,6 @@
 use crate::migration::Migration;
+use chrono::prelude::*;
+use serde::Serialize;
 use std::cmp::Ordering;
 use std::collections::HashMap;
 
@@ -15,8 +17,8 @@ pub fn find_matches<'a>(
     for m in db_migrations {
         let m_name: &str = m.name.as_ref();
         if let Some((_, loc_m)) = local_cmp.remove_entry(m_name) {
-            if loc_m.hash == m.hash {
-                matches.push(Matching::Applied(loc_m));
+            if loc_m.hash_matches(m) {
+                matches.push(Matching::Applied(loc_m, m));
             } else {
                 matches.push(Matching::Variant(loc_m, m));
             }
@@ -34,7 +36,7 @@ pub fn find_matches<'a>(
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Matching<'a> {
-    Applied(&'a Migration),
+    Applied(&'a Migration, &'a Migration),
     Divergent(&'a Migration),
     Pending(&'a Migration),
     Variant(&'a Migration, &'a Migration),
@@ -44,15 +46,15 @@ impl<'a> Matching<'a> {
     pub fn get_name(&self) -> &'a str {
         use Matching::*;
         match self {
-            Applied(x) | Divergent(x) | Pending(x) => &x.name,
-            Variant(x, _) => &x.name,
+            Divergent(x) | Pending(x) => &x.name,
+            Applied(x, _) | Variant(x, _) => &x.name,
         }
     }
 
     pub fn get_best_down_migration(&self) -> &'a Migration {
         use Matching::*;
         match self {
-            Applied(x) | Pending(x) | Divergent(x) => x,
+            Applied(x, _) | Pending(x) | Divergent(x) => x,
             Variant(x, y) => {
                 if x.down_sql.is_some() {
                     x
@@ -66,30 +68,100 @@ impl<'a> Matching<'a> {
     pub fn get_local_migration(&self) -> Option<&'a Migration> {
         use Matching::*;
         match self {
-            Applied(x) | Pending(x) | Variant(x, _) => Some(x),
+            Applied(x, _) | Pending(x) | Variant(x, _) => Some(x),
             Divergent(_) => None,
         }
     }
 
+    pub fn get_db_migration(&self) -> Option<&'a Migration> {
+        use Matching::*;
+        match self {
+            Applied(_, y) | Divergent(y) | Variant(_, y) => Some(y),
+            Pending(_) => None,
+        }
+    }
+
     pub fn is_reversable(&self) -> bool {
         use Matching::*;
         match self {
-            Applied(x) | Pending(x) | Divergent(x) => x.is_reversable(),
+            Applied(x, _) | Pending(x) | Divergent(x) => x.is_reversable(),
             Variant(x, y) => x.is_reversable() || y.is_reversable(),
         }
     }
 }
 
+/// The state of a migration as reported by `Movine::get_status`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MigrationState {
+    /// Found locally and applied to the database.
+    Applied,
+    /// Not found locally but applied to the database.
+    Divergent,
+    /// Found locally and not applied to the database.
+    Pending,
+    /// Found locally but a different version is applied to the database.
+    Variant,
+}
+
+impl MigrationState {
+    pub fn as_str(&self) -> &'static str {
+        use MigrationState::*;
+        match self {
+            Applied => "applied",
+            Divergent => "divergent",
+            Pending => "pending",
+            Variant => "variant",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MigrationStatus {
+    pub name: String,
+    pub state: MigrationState,
+    pub reversable: bool,
+    pub local_hash: Option<String>,
+    pub db_hash: Option<String>,
+    pub applied_at: Option<DateTime<Utc>>,
+    pub applied_by: Option<String>,
+    pub duration_ms: Option<i64>,
+    pub movine_version: Option<String>,
+}
+
+impl From<&Matching<'_>> for MigrationStatus {
+    fn from(matching: &Matching) -> Self {
+        let state = match matching {
+            Matching::Applied(_, _) => MigrationState::Applied,
+            Matching::Divergent(_) => MigrationState::Divergent,
+            Matching::Pending(_) => MigrationState::Pending,
+            Matching::Variant(_, _) => MigrationState::Variant,
+        };
+        let db_migration = matching.get_db_migration();
+        Self {
+            name: matching.get_name().to_owned(),
+            state,
+            reversable: matching.is_reversable(),
+            local_hash: matching.get_local_migration().and_then(|x| x.hash.clone()),
+            db_hash: db_migration.and_then(|x| x.hash.clone()),
+            applied_at: db_migration.and_then(|x| x.applied_at),
+            applied_by: db_migration.and_then(|x| x.applied_by.clone()),
+            duration_ms: db_migration.and_then(|x| x.duration_ms),
+            movine_version: db_migration.and_then(|x| x.movine_version.clone()),
+        }
+    }
+}
+
 impl Ord for Matching<'_> {
     fn cmp(&self, other: &Self) -> Ordering {
         let self_name = self.get_name();
         let other_name = other.get_name();
-        self_name.cmp(&other_name)
+        self_name.cmp(other_name)
     }
 }
 
 impl PartialOrd for Matching<'_> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(&other))
+        Some(self.cmp(other))
     }
 }
@@ -1,14 +1,30 @@
 use crate::errors::{Error, Result};
 use chrono::prelude::*;
+use sha2::{Digest, Sha256};
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
 
-#[derive(Debug, Eq, PartialEq)]
+const HASH_PREFIX: &str = "sha256:";
+const NO_TRANSACTION_HEADER: &str = "-- movine:no-transaction";
+
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Migration {
     pub name: String,
     pub up_sql: Option<String>,
     pub down_sql: Option<String>,
     pub hash: Option<String>,
+    /// Whether the migration is run inside a transaction. Set to false by starting `up.sql` or
+    /// `down.sql` with a `-- movine:no-transaction` comment.
+    pub transactional: bool,
+    /// When the migration was applied. Only set for migrations loaded from the database.
+    pub applied_at: Option<DateTime<Utc>>,
+    /// Who applied the migration: the configured actor, or otherwise the database user. Movine
+    /// fills this in with the actor on local migrations it's about to run.
+    pub applied_by: Option<String>,
+    /// How long the up migration took to run.
+    pub duration_ms: Option<i64>,
+    /// Version of Movine that applied the migration.
+    pub movine_version: Option<String>,
 }
 
 impl Migration {
@@ -20,8 +36,54 @@ impl Migration {
             false
         }
     }
+
+    /// Whether `other` (usually loaded from the database) was recorded from the same SQL as this
+    /// migration. Hashes written by older versions of Movine are checked against the legacy hasher.
+    pub fn hash_matches(&self, other: &Migration) -> bool {
+        match &other.hash {
+            Some(hash) if is_legacy_hash(hash) && self.up_sql.is_some() => {
+                self.legacy_hash() == *hash
+            }
+            hash => self.hash == *hash,
+        }
+    }
+
+    /// The hash older versions of Movine computed with `DefaultHasher`, which is not stable
+    /// across Rust releases. Only used to recognise and upgrade existing hashes.
+    pub fn legacy_hash(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.up_sql.hash(&mut hasher);
+        self.down_sql.hash(&mut hasher);
+        format!("{:x}", hasher.finish())
+    }
+}
+
+/// Look for the no-transaction header among the comments at the top of the SQL.
+fn has_no_transaction_header(sql: Option<&str>) -> bool {
+    sql.is_some_and(|sql| {
+        sql.lines()
+            .map(str::trim)
+            .take_while(|line| line.is_empty() || line.starts_with("--"))
+            .any(|line| line == NO_TRANSACTION_HEADER)
+    })
+}
+
+pub fn is_legacy_hash(hash: &str) -> bool {
+    !hash.starts_with(HASH_PREFIX)
+}
+
+fn content_hash(up_sql: Option<&str>, down_sql: Option<&str>) -> String {
+    let mut hasher = Sha256::new();
+    for sql in [up_sql, down_sql].iter() {
+        let sql = sql.unwrap_or("");
+        // Length-prefix each section so moving SQL between up and down changes the hash.
+        hasher.update((sql.len() as u64).to_le_bytes());
+        hasher.update(sql.as_bytes());
+    }
+    format!("{}{:x}", HASH_PREFIX, hasher.finalize())
 }
 
+#[derive(Default)]
 pub struct MigrationBuilder {
     compound_name: Option<String>,
     name: Option<String>,
@@ -29,18 +91,15 @@ pub struct MigrationBuilder {
     up_sql: Option<String>,
     down_sql: Option<String>,
     hash: Option<String>,
+    applied_at: Option<DateTime<Utc>>,
+    applied_by: Option<String>,
+    duration_ms: Option<i64>,
+    movine_version: Option<String>,
 }
 
 impl MigrationBuilder {
     pub fn new() -> Self {
-        Self {
-            compound_name: None,
-            name: None,
-            date: None,
-            up_sql: None,
-            down_sql: None,
-            hash: None,
-        }
+        Self::default()
     }
 
     pub fn compound_name<'a>(&'a mut self, compound_name: &str) -> &'a mut Self {
@@ -73,6 +132,26 @@ impl MigrationBuilder {
         self
     }
 
+    pub fn applied_at(&mut self, applied_at: DateTime<Utc>) -> &mut Self {
+        self.applied_at = Some(applied_at);
+        self
+    }
+
+    pub fn applied_by<'a>(&'a mut self, applied_by: &str) -> &'a mut Self {
+        self.applied_by = Some(applied_by.to_owned());
+        self
+    }
+
+    pub fn duration_ms(&mut self, duration_ms: i64) -> &mut Self {
+        self.duration_ms = Some(duration_ms);
+        self
+    }
+
+    pub fn movine_version<'a>(&'a mut self, movine_version: &str) -> &'a mut Self {
+        self.movine_version = Some(movine_version.to_owned());
+        self
+    }
+
     pub fn build(&self) -> Result<Migration> {
         // TODO: Clean up ownership a bit; we should be able to just take
         let name = if let Some(compound_name) = &self.compound_name {
@@ -86,19 +165,86 @@ impl MigrationBuilder {
 
         let hash = match (&self.up_sql, &self.down_sql, &self.hash) {
             (_, _, Some(x)) => Some(x.to_owned()),
-            (x, y, None) => {
-                let mut hasher = DefaultHasher::new();
-                x.hash(&mut hasher);
-                y.hash(&mut hasher);
-                Some(format!("{:x}", hasher.finish()))
-            }
+            (x, y, None) => Some(content_hash(x.as_deref(), y.as_deref())),
         };
 
+        let transactional = !has_no_transaction_header(self.up_sql.as_deref())
+            && !has_no_transaction_header(self.down_sql.as_deref());
+
         Ok(Migration {
             name,
             up_sql: self.up_sql.to_owned(),
             down_sql: self.down_sql.to_owned(),
             hash,
+            transactional,
+            applied_at: self.applied_at,
+            applied_by: self.applied_by.to_owned(),
+            duration_ms: self.duration_ms,
+            movine_version: self.movine_version.to_owned(),
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Hashes should be stable across builds so upgrading Rust doesn't flip migrations to Variant.
+    fn test_hash_is_stable() {
+        let migration = MigrationBuilder::new()
+            .compound_name("test")
+            .up_sql("CREATE TABLE test ();")
+            .down_sql("DROP TABLE test;")
+            .build()
+            .unwrap();
+        assert_eq!(
+            migration.hash.unwrap(),
+            "sha256:a13cb152b2013ec69255277c04a6e3768ff100a31bf6acf4bcf6a4c0bb10fde9"
+        );
+    }
+
+    #[test]
+    /// Hashes recorded by older versions of Movine should still match the same SQL.
+    fn test_legacy_hash_matches() {
+        let local = MigrationBuilder::new()
+            .compound_name("test")
+            .up_sql("CREATE TABLE test ();")
+            .down_sql("DROP TABLE test;")
+            .build()
+            .unwrap();
+        let db = MigrationBuilder::new()
+            .compound_name("test")
+            .hash(&local.legacy_hash())
+            .build()
+            .unwrap();
+        assert!(local.hash_matches(&db));
+
+        let db = MigrationBuilder::new()
+            .compound_name("test")
+            .hash("1234abcd")
+            .build()
+            .unwrap();
+        assert!(!local.hash_matches(&db));
+    }
+
+    #[test]
+    /// A no-transaction comment at the top of either file should opt the migration out.
+    fn test_no_transaction_header() {
+        let migration = MigrationBuilder::new()
+            .compound_name("test")
+            .up_sql("-- Build the index without locking the table\n-- movine:no-transaction\nCREATE INDEX CONCURRENTLY test_idx ON test (id);")
+            .down_sql("DROP INDEX test_idx;")
+            .build()
+            .unwrap();
+        assert!(!migration.transactional);
+
+        let migration = MigrationBuilder::new()
+            .compound_name("test")
+            .up_sql("CREATE TABLE test ();\n-- movine:no-transaction")
+            .down_sql("DROP TABLE test;")
+            .build()
+            .unwrap();
+        assert!(migration.transactional);
+    }
+}
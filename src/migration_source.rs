@@ -0,0 +1,30 @@
+use crate::errors::Result;
+use crate::file_handler::FileHandler;
+use crate::migration::Migration;
+
+/// Somewhere local migrations can be loaded from, such as the migration directory, migrations
+/// embedded in the binary, or anything else that can produce them.
+pub trait MigrationSource {
+    fn load(&self) -> Result<Vec<Migration>>;
+}
+
+impl MigrationSource for FileHandler {
+    fn load(&self) -> Result<Vec<Migration>> {
+        self.load_local_migrations()
+    }
+}
+
+impl MigrationSource for Vec<Migration> {
+    fn load(&self) -> Result<Vec<Migration>> {
+        Ok(self.clone())
+    }
+}
+
+impl<F> MigrationSource for F
+where
+    F: Fn() -> Result<Vec<Migration>>,
+{
+    fn load(&self) -> Result<Vec<Migration>> {
+        self()
+    }
+}
@@ -1,19 +1,49 @@
 use crate::errors::{Error, Result};
 use crate::match_maker::{self, Matching};
 use crate::migration::Migration;
+use serde::Serialize;
+use std::collections::HashSet;
 
 pub type Plan<'a> = Vec<(Step, &'a Migration)>;
 
-#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+/// A plan that owns its migrations, for handing out of the library.
+pub type OwnedPlan = Vec<(Step, Migration)>;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Step {
     Up,
     Down,
 }
 
+impl Step {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Step::Up => "up",
+            Step::Down => "down",
+        }
+    }
+}
+
+/// Parse a single step of a custom plan, such as `up:2020-01-01-000000_foo`.
+pub fn parse_step(step: &str) -> Result<(Step, &str)> {
+    let mut parts = step.splitn(2, ':');
+    let direction = match parts.next().map(|x| x.trim()) {
+        Some("up") => Step::Up,
+        Some("down") => Step::Down,
+        _ => return Err(Error::BadPlanStep(step.to_owned())),
+    };
+    match parts.next().map(|x| x.trim()) {
+        Some(name) if !name.is_empty() => Ok((direction, name)),
+        _ => Err(Error::BadPlanStep(step.to_owned())),
+    }
+}
+
 pub struct PlanBuilder<'a> {
     local_migrations: Option<&'a [Migration]>,
     db_migrations: Option<&'a [Migration]>,
     count: Option<usize>,
+    target: Option<&'a str>,
     strict: bool,
     ignore_divergent: bool,
     ignore_unreversable: bool,
@@ -25,6 +55,7 @@ impl<'a> PlanBuilder<'a> {
             local_migrations: None,
             db_migrations: None,
             count: None,
+            target: None,
             strict: false,
             ignore_divergent: false,
             ignore_unreversable: false,
@@ -46,6 +77,11 @@ impl<'a> PlanBuilder<'a> {
         self
     }
 
+    pub fn target(mut self, target: Option<&'a str>) -> Self {
+        self.target = target;
+        self
+    }
+
     pub fn set_strict(mut self, strict: bool) -> Self {
         self.strict = strict;
         self
@@ -67,6 +103,7 @@ impl<'a> PlanBuilder<'a> {
         let mut plan = Vec::new();
 
         let matches = self.get_matches()?;
+        self.check_target(&matches)?;
         for m in matches {
             match m {
                 Matching::Pending(x) => {
@@ -76,6 +113,11 @@ impl<'a> PlanBuilder<'a> {
                             continue;
                         }
                     }
+                    if let Some(target) = self.target {
+                        if x.name.as_str() > target {
+                            continue;
+                        }
+                    }
 
                     let step = (Step::Up, x);
                     plan.push(step);
@@ -99,10 +141,18 @@ impl<'a> PlanBuilder<'a> {
     pub fn down(self) -> Result<Plan<'a>> {
         let mut plan: Plan<'a> = Vec::new();
         let matches = self.get_matches()?;
+        self.check_target(&matches)?;
 
         // Note: get_matches() returns the migrations in date-order.
         // We want the most recently run, so we have to reverse the order.
         for m in matches.iter().rev() {
+            if let Some(target) = self.target {
+                // The target itself stays applied.
+                if m.get_name() <= target {
+                    break;
+                }
+            }
+
             match m {
                 Matching::Divergent(x) => {
                     if self.ignore_divergent {
@@ -111,7 +161,7 @@ impl<'a> PlanBuilder<'a> {
 
                     plan.push((Step::Down, x));
                 }
-                Matching::Applied(_) | Matching::Variant(_, _) => {
+                Matching::Applied(_, _) | Matching::Variant(_, _) => {
                     if m.is_reversable() {
                         plan.push((Step::Down, m.get_best_down_migration()));
                     } else if !self.ignore_unreversable {
@@ -121,11 +171,12 @@ impl<'a> PlanBuilder<'a> {
                 _ => {}
             }
 
+            // With a target and no count, roll back everything after the target.
             if let Some(count) = self.count {
                 if count == plan.len() {
                     break;
                 }
-            } else if plan.len() == 1 {
+            } else if self.target.is_none() && plan.len() == 1 {
                 break;
             }
         }
@@ -160,7 +211,7 @@ impl<'a> PlanBuilder<'a> {
                         return Err(Error::UnrollbackableMigration);
                     }
                 }
-                Matching::Applied(x) => {
+                Matching::Applied(x, _) => {
                     if bad_migration_found {
                         if m.is_reversable() {
                             rollback_plan_rev.push((Step::Down, x));
@@ -198,7 +249,7 @@ impl<'a> PlanBuilder<'a> {
 
                     return Err(Error::DivergentMigration);
                 }
-                Matching::Applied(_) | Matching::Variant(_, _) => {
+                Matching::Applied(_, _) | Matching::Variant(_, _) => {
                     if m.is_reversable() {
                         rollback_plan.push((Step::Down, m.get_best_down_migration()));
                         rollup_plan_rev.push((Step::Up, m.get_local_migration().unwrap()));
@@ -224,10 +275,75 @@ impl<'a> PlanBuilder<'a> {
         Ok(plan)
     }
 
+    /// Build a plan from an explicit list of steps, validating each step against the state the
+    /// database would be in after running the steps before it.
+    pub fn custom(self, steps: &[(Step, &str)]) -> Result<Plan<'a>> {
+        let matches = self.get_matches()?;
+        let mut applied: HashSet<&str> = matches
+            .iter()
+            .filter(|m| !matches!(m, Matching::Pending(_)))
+            .map(|m| m.get_name())
+            .collect();
+        let mut upped: HashSet<&str> = HashSet::new();
+        let mut plan = Vec::new();
+
+        for (step, name) in steps {
+            let m = matches
+                .iter()
+                .find(|m| m.get_name() == *name)
+                .copied()
+                .ok_or_else(|| Error::MigrationNotFound(name.to_string()))?;
+
+            match step {
+                Step::Up => {
+                    if applied.contains(m.get_name()) {
+                        return Err(Error::MigrationAlreadyApplied(name.to_string()));
+                    }
+                    let local = m
+                        .get_local_migration()
+                        .ok_or_else(|| Error::MigrationNotFound(name.to_string()))?;
+                    applied.insert(m.get_name());
+                    upped.insert(m.get_name());
+                    plan.push((Step::Up, local));
+                }
+                Step::Down => {
+                    if !applied.remove(m.get_name()) {
+                        return Err(Error::MigrationNotApplied(name.to_string()));
+                    }
+                    // If we ran it earlier in this plan, the local version is what's applied.
+                    let down = if upped.contains(m.get_name()) {
+                        m.get_local_migration().unwrap()
+                    } else {
+                        m.get_best_down_migration()
+                    };
+                    if !down.is_reversable() {
+                        return Err(Error::UnrollbackableMigration);
+                    }
+                    plan.push((Step::Down, down));
+                }
+            }
+        }
+
+        Ok(plan)
+    }
+
     pub fn status(self) -> Result<Vec<Matching<'a>>> {
         self.get_matches()
     }
 
+    fn check_target(&self, matches: &[Matching]) -> Result<()> {
+        if let Some(target) = self.target {
+            match matches.iter().find(|m| m.get_name() == target) {
+                None => return Err(Error::TargetNotFound(target.to_owned())),
+                Some(Matching::Divergent(_)) => {
+                    return Err(Error::DivergentTarget(target.to_owned()));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
     fn get_matches(&self) -> Result<Vec<Matching<'a>>> {
         if let (Some(local_migrations), Some(db_migrations)) =
             (self.local_migrations, self.db_migrations)
@@ -242,6 +358,7 @@ impl<'a> PlanBuilder<'a> {
 }
 
 #[cfg(test)]
+#[allow(clippy::needless_borrow)]
 mod tests {
     use super::*;
     use crate::migration::Migration;
@@ -254,6 +371,11 @@ mod tests {
                 up_sql: None,
                 down_sql: Some("test".to_owned()),
                 hash: None,
+                transactional: true,
+                applied_at: None,
+                applied_by: None,
+                duration_ms: None,
+                movine_version: None,
             }
         }
 
@@ -263,6 +385,11 @@ mod tests {
                 up_sql: None,
                 down_sql: None,
                 hash: Some(hash.to_string()),
+                transactional: true,
+                applied_at: None,
+                applied_by: None,
+                duration_ms: None,
+                movine_version: None,
             }
         }
     }
@@ -335,6 +462,100 @@ mod tests {
         assert_eq!(plan, [(Step::Down, &local[0])])
     }
 
+    #[test]
+    /// Up should stop after the target migration.
+    fn test_up_to_target() {
+        let local = [
+            Migration::new("test_0"),
+            Migration::new("test_1"),
+            Migration::new("test_2"),
+        ];
+        let db = [Migration::new("test_0")];
+        let plan = PlanBuilder::new()
+            .local_migrations(&local)
+            .db_migrations(&db)
+            .target(Some("test_1"))
+            .up()
+            .unwrap();
+        assert_eq!(plan, [(Step::Up, &local[1])])
+    }
+
+    #[test]
+    /// Down should rollback everything after the target migration, leaving the target applied.
+    fn test_down_to_target() {
+        let local = [
+            Migration::new("test_0"),
+            Migration::new("test_1"),
+            Migration::new("test_2"),
+        ];
+        let db = [
+            Migration::new("test_0"),
+            Migration::new("test_1"),
+            Migration::new("test_2"),
+        ];
+        let plan = PlanBuilder::new()
+            .local_migrations(&local)
+            .db_migrations(&db)
+            .target(Some("test_0"))
+            .down()
+            .unwrap();
+        assert_eq!(plan, [(Step::Down, &local[2]), (Step::Down, &local[1])])
+    }
+
+    #[test]
+    /// Down should stop at whichever of the count and the target it reaches first.
+    fn test_down_to_target_with_count() {
+        let local = [
+            Migration::new("test_0"),
+            Migration::new("test_1"),
+            Migration::new("test_2"),
+        ];
+        let db = [
+            Migration::new("test_0"),
+            Migration::new("test_1"),
+            Migration::new("test_2"),
+        ];
+        let plan = PlanBuilder::new()
+            .local_migrations(&local)
+            .db_migrations(&db)
+            .target(Some("test_0"))
+            .count(Some(1))
+            .down()
+            .unwrap();
+        assert_eq!(plan, [(Step::Down, &local[2])]);
+
+        let plan = PlanBuilder::new()
+            .local_migrations(&local)
+            .db_migrations(&db)
+            .target(Some("test_1"))
+            .count(Some(5))
+            .down()
+            .unwrap();
+        assert_eq!(plan, [(Step::Down, &local[2])])
+    }
+
+    #[test]
+    /// Targets should be known and not divergent.
+    fn test_bad_target() {
+        let local = [Migration::new("test_0")];
+        let db = [Migration::new("test_0"), Migration::new("test_1")];
+        let plan = PlanBuilder::new()
+            .local_migrations(&local)
+            .db_migrations(&db)
+            .target(Some("test_2"))
+            .up();
+        let is_correct_err = matches!(plan.err().unwrap(), Error::TargetNotFound(_));
+        assert!(is_correct_err);
+
+        let plan = PlanBuilder::new()
+            .local_migrations(&local)
+            .db_migrations(&db)
+            .target(Some("test_1"))
+            .down();
+        let is_correct_err = matches!(plan.err().unwrap(), Error::DivergentTarget(_));
+        assert!(is_correct_err);
+    }
+
     #[test]
     /// Fix should rollback all variant and divergent migrations, and then run pending migrations.
     fn test_fix_1() {
@@ -547,4 +768,75 @@ mod tests {
             ]
         )
     }
+
+    #[test]
+    /// Custom plans should run the given steps in the given order.
+    fn test_custom_1() {
+        let local = [
+            Migration::new("test_0"),
+            Migration::new("test_1"),
+            Migration::new("test_2"),
+        ];
+        let db = [Migration::new("test_0"), Migration::new("test_1")];
+        let plan = PlanBuilder::new()
+            .local_migrations(&local)
+            .db_migrations(&db)
+            .custom(&[
+                (Step::Up, "test_2"),
+                (Step::Down, "test_0"),
+                (Step::Down, "test_2"),
+            ])
+            .unwrap();
+        assert_eq!(
+            plan,
+            [
+                (Step::Up, &local[2]),
+                (Step::Down, &local[0]),
+                (Step::Down, &local[2]),
+            ]
+        )
+    }
+
+    #[test]
+    /// Custom plans should not run applied migrations up or pending migrations down.
+    fn test_custom_2() {
+        let local = [Migration::new("test_0"), Migration::new("test_1")];
+        let db = [Migration::new("test_0")];
+        let plan = PlanBuilder::new()
+            .local_migrations(&local)
+            .db_migrations(&db)
+            .custom(&[(Step::Up, "test_0")]);
+        let is_correct_err = matches!(plan.err().unwrap(), Error::MigrationAlreadyApplied(_));
+        assert!(is_correct_err);
+
+        let plan = PlanBuilder::new()
+            .local_migrations(&local)
+            .db_migrations(&db)
+            .custom(&[(Step::Down, "test_1")]);
+        let is_correct_err = matches!(plan.err().unwrap(), Error::MigrationNotApplied(_));
+        assert!(is_correct_err);
+    }
+
+    #[test]
+    /// Custom plans should refuse to roll back unreversable migrations.
+    fn test_custom_3() {
+        let local = [Migration::new("test_0")];
+        let db = [Migration::new_with_hash("test_1", "hash")];
+        let plan = PlanBuilder::new()
+            .local_migrations(&local)
+            .db_migrations(&db)
+            .custom(&[(Step::Down, "test_1")]);
+        let is_correct_err = matches!(plan.err().unwrap(), Error::UnrollbackableMigration);
+        assert!(is_correct_err);
+    }
+
+    #[test]
+    /// Custom plan steps should be parsed from `direction:name`.
+    fn test_parse_step() {
+        assert_eq!(parse_step("up:test_0").unwrap(), (Step::Up, "test_0"));
+        assert_eq!(parse_step("down: test_1").unwrap(), (Step::Down, "test_1"));
+        assert!(parse_step("sideways:test_0").is_err());
+        assert!(parse_step("up:").is_err());
+        assert!(parse_step("test_0").is_err());
+    }
 }
@@ -0,0 +1,105 @@
+//! The objects making up a database's schema, and how two schemas differ.
+
+/// A table, constraint, index, view or trigger, as read back from the database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaObject {
+    /// What sort of object this is, such as `table`, `index` or `view`.
+    pub kind: String,
+    /// Unique among objects of the same kind, qualified by schema or table where needed.
+    pub name: String,
+    /// SQL that creates the object, ending in a semicolon.
+    pub sql: String,
+}
+
+/// A way the live schema differs from the one its recorded migrations should have produced.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SchemaDrift {
+    /// Only in the schema the migrations produce.
+    Missing(SchemaObject),
+    /// Only in the live database.
+    Unexpected(SchemaObject),
+    /// In both, but defined differently.
+    Changed {
+        expected: SchemaObject,
+        live: SchemaObject,
+    },
+}
+
+/// Write out `objects` as SQL, one object after another.
+pub fn dump(objects: &[SchemaObject]) -> String {
+    objects.iter().map(|x| format!("{}\n", x.sql)).collect()
+}
+
+/// Every difference between the `expected` and `live` schemas: objects of the expected schema
+/// in order, then objects only the live one has.
+pub fn compare(expected: &[SchemaObject], live: &[SchemaObject]) -> Vec<SchemaDrift> {
+    let find = |objects: &[SchemaObject], object: &SchemaObject| {
+        objects
+            .iter()
+            .find(|x| x.kind == object.kind && x.name == object.name)
+            .cloned()
+    };
+
+    let mut drift = Vec::new();
+    for object in expected {
+        match find(live, object) {
+            None => drift.push(SchemaDrift::Missing(object.clone())),
+            Some(live) if live.sql != object.sql => drift.push(SchemaDrift::Changed {
+                expected: object.clone(),
+                live,
+            }),
+            Some(_) => {}
+        }
+    }
+    for object in live {
+        if find(expected, object).is_none() {
+            drift.push(SchemaDrift::Unexpected(object.clone()));
+        }
+    }
+    drift
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(kind: &str, name: &str, sql: &str) -> SchemaObject {
+        SchemaObject {
+            kind: kind.into(),
+            name: name.into(),
+            sql: sql.into(),
+        }
+    }
+
+    #[test]
+    /// Objects should be matched by kind and name, then compared by their SQL.
+    fn test_compare() {
+        let users = object("table", "users", "CREATE TABLE users (id INTEGER);");
+        let posts = object("table", "posts", "CREATE TABLE posts (id INTEGER);");
+        let index = object("index", "users", "CREATE INDEX users ON posts (id);");
+        let expected = vec![users.clone(), posts.clone()];
+        assert!(compare(&expected, &expected).is_empty());
+
+        let hotfixed = object(
+            "table",
+            "users",
+            "CREATE TABLE users (id INTEGER, email TEXT);",
+        );
+        let live = vec![index.clone(), hotfixed.clone()];
+        assert_eq!(
+            compare(&expected, &live),
+            vec![
+                SchemaDrift::Changed {
+                    expected: users,
+                    live: hotfixed,
+                },
+                SchemaDrift::Missing(posts),
+                SchemaDrift::Unexpected(index),
+            ]
+        );
+        assert_eq!(
+            dump(&live),
+            "CREATE INDEX users ON posts (id);\nCREATE TABLE users (id INTEGER, email TEXT);\n"
+        );
+    }
+}
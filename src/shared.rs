@@ -0,0 +1,459 @@
+//! The parts of [`Movine`](crate::Movine) and `AsyncMovine` that don't touch the database, so
+//! that the two only differ in how they call their adaptor.
+use crate::diff;
+use crate::display::{self, OutputFormat};
+use crate::errors::{Error, Result};
+use crate::file_handler::FileHandler;
+use crate::history::HistoryEntry;
+use crate::match_maker::MigrationStatus;
+use crate::migration::{self, Migration, MigrationBuilder};
+use crate::migration_source::MigrationSource;
+use crate::plan_builder::{OwnedPlan, PlanBuilder, Step};
+use crate::schema::{SchemaDrift, SchemaObject};
+use chrono::prelude::*;
+
+/// The settings that decide which migrations a plan includes.
+#[derive(Debug, Default)]
+pub struct PlanOptions {
+    pub number: Option<usize>,
+    pub target: Option<String>,
+    pub strict: bool,
+    pub ignore_divergent: bool,
+    pub ignore_unreversable: bool,
+}
+
+impl PlanOptions {
+    pub fn up(&self, local: &[Migration], db: &[Migration]) -> Result<OwnedPlan> {
+        let plan = PlanBuilder::new()
+            .local_migrations(local)
+            .db_migrations(db)
+            .count(self.number)
+            .target(self.target.as_deref())
+            .set_strict(self.strict)
+            .up()?;
+        Ok(to_owned_plan(&plan))
+    }
+
+    pub fn down(&self, local: &[Migration], db: &[Migration]) -> Result<OwnedPlan> {
+        let plan = PlanBuilder::new()
+            .local_migrations(local)
+            .db_migrations(db)
+            .count(self.number)
+            .target(self.target.as_deref())
+            .set_ignore_divergent(self.ignore_divergent)
+            .set_ignore_unreversable(self.ignore_unreversable)
+            .down()?;
+        Ok(to_owned_plan(&plan))
+    }
+
+    pub fn fix(&self, local: &[Migration], db: &[Migration]) -> Result<OwnedPlan> {
+        let plan = PlanBuilder::new()
+            .local_migrations(local)
+            .db_migrations(db)
+            .fix()?;
+        Ok(to_owned_plan(&plan))
+    }
+
+    pub fn redo(&self, local: &[Migration], db: &[Migration]) -> Result<OwnedPlan> {
+        let plan = PlanBuilder::new()
+            .local_migrations(local)
+            .db_migrations(db)
+            .count(self.number)
+            .set_ignore_divergent(self.ignore_divergent)
+            .set_ignore_unreversable(self.ignore_unreversable)
+            .redo()?;
+        Ok(to_owned_plan(&plan))
+    }
+}
+
+pub fn status(local: &[Migration], db: &[Migration]) -> Result<Vec<MigrationStatus>> {
+    let status = PlanBuilder::new()
+        .local_migrations(local)
+        .db_migrations(db)
+        .status()?;
+    Ok(status.iter().map(MigrationStatus::from).collect())
+}
+
+/// The plan `baseline` records: every pending migration up to and including `target`.
+pub fn plan_baseline(local: &[Migration], db: &[Migration], target: &str) -> Result<OwnedPlan> {
+    let plan = PlanBuilder::new()
+        .local_migrations(local)
+        .db_migrations(db)
+        .target(Some(target))
+        .up()?;
+    Ok(to_owned_plan(&plan))
+}
+
+/// The plan `init` runs. We can't get the database migrations until the init migration has
+/// created the table, so this is just the first local migration.
+pub fn plan_init(local: &[Migration]) -> Result<OwnedPlan> {
+    let plan = PlanBuilder::new()
+        .local_migrations(local)
+        .db_migrations(&[])
+        .count(Some(1))
+        .up()?;
+    Ok(to_owned_plan(&plan))
+}
+
+/// Write the init migration to the migration directory, unless it is already there.
+pub fn write_init_migration(
+    migration_dir: &str,
+    single_file: bool,
+    up_sql: &str,
+    down_sql: &str,
+) -> Result<()> {
+    let file_handler = FileHandler::new(migration_dir);
+    file_handler.create_migration_directory()?;
+    let init_migration = MigrationBuilder::new()
+        .name("movine_init")
+        .date(Utc.timestamp_opt(0, 0).unwrap())
+        .up_sql(up_sql)
+        .down_sql(down_sql)
+        .build()?;
+
+    match write_migration(&file_handler, &init_migration, single_file) {
+        Ok(_) => Ok(()),
+        Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
+        x => x,
+    }
+}
+
+pub fn write_migration(
+    file_handler: &FileHandler,
+    migration: &Migration,
+    single_file: bool,
+) -> Result<()> {
+    if single_file {
+        file_handler.write_single_file_migration(migration)
+    } else {
+        file_handler.write_migration(migration)
+    }
+}
+
+pub fn load_local_migrations(
+    source: &Option<Box<dyn MigrationSource + Send + Sync>>,
+    migration_dir: &str,
+) -> Result<Vec<Migration>> {
+    match source {
+        Some(source) => source.load(),
+        None => FileHandler::new(migration_dir).load(),
+    }
+}
+
+/// The hashes to rewrite for migrations recorded by older versions of Movine, as
+/// `(name, new hash)`. Only migrations whose local content still matches the legacy hash are
+/// included.
+pub fn legacy_hash_upgrades<'a>(
+    local_migrations: &'a [Migration],
+    db_migrations: &[Migration],
+) -> Vec<(&'a str, &'a str)> {
+    let mut upgrades = Vec::new();
+    for db_migration in db_migrations {
+        let legacy = match &db_migration.hash {
+            Some(hash) if migration::is_legacy_hash(hash) => hash,
+            _ => continue,
+        };
+        let local = local_migrations
+            .iter()
+            .find(|x| x.name == db_migration.name && x.legacy_hash() == *legacy);
+        if let Some(Migration {
+            name,
+            hash: Some(hash),
+            ..
+        }) = local
+        {
+            upgrades.push((name.as_str(), hash.as_str()));
+        }
+    }
+    upgrades
+}
+
+/// What `accept` records for a migration.
+pub struct Acceptance {
+    pub name: String,
+    pub hash: String,
+    /// The new down SQL, if it changed and should be recorded too.
+    pub down_sql: Option<String>,
+    /// The hash and down SQL the database had recorded when the change was shown.
+    recorded: (Option<String>, Option<String>),
+}
+
+impl Acceptance {
+    /// Fail if the database's record of the migration has changed since it was shown, so that
+    /// edits accepted by someone else while `confirm` was waiting aren't overwritten. Call this
+    /// while holding the lock.
+    pub fn check_unchanged(&self, db_migrations: &[Migration]) -> Result<()> {
+        let db = db_migrations
+            .iter()
+            .find(|x| x.name == self.name)
+            .ok_or_else(|| Error::MigrationNotApplied(self.name.clone()))?;
+        if (&db.hash, &db.down_sql) != (&self.recorded.0, &self.recorded.1) {
+            return Err(Error::AcceptConflict(self.name.clone()));
+        }
+        Ok(())
+    }
+}
+
+/// Work out what accepting the edits to `name` would change, show it and ask `confirm` whether
+/// to go ahead. Returns `None` if there is nothing to record. Doesn't need the lock: check the
+/// result with [`Acceptance::check_unchanged`] once holding it, before recording anything.
+pub fn accept_changes<F>(
+    local_migrations: &[Migration],
+    db_migrations: &[Migration],
+    name: &str,
+    update_down_sql: bool,
+    confirm: F,
+) -> Result<Option<Acceptance>>
+where
+    F: FnOnce() -> Result<bool>,
+{
+    let local = local_migrations
+        .iter()
+        .find(|x| x.name == name)
+        .ok_or_else(|| Error::MigrationNotFound(name.to_owned()))?;
+    let db = db_migrations
+        .iter()
+        .find(|x| x.name == name)
+        .ok_or_else(|| Error::MigrationNotApplied(name.to_owned()))?;
+
+    let local_down_sql = local.down_sql.as_deref().unwrap_or("");
+    let db_down_sql = db.down_sql.as_deref().unwrap_or("");
+    let down_sql_changed = update_down_sql && local_down_sql != db_down_sql;
+    let hash_changed = local.hash != db.hash && db.hash.as_ref() != Some(&local.legacy_hash());
+    if !hash_changed && !down_sql_changed {
+        info!("Migration `{}` already matches the database", name);
+        return Ok(None);
+    }
+
+    display::print_accept(db, local);
+    if !confirm()? {
+        info!("Nothing was changed");
+        return Ok(None);
+    }
+    Ok(Some(Acceptance {
+        name: name.to_owned(),
+        hash: local.hash.clone().ok_or(Error::BadMigration)?,
+        down_sql: Some(local_down_sql.to_owned()).filter(|_| down_sql_changed),
+        recorded: (db.hash.clone(), db.down_sql.clone()),
+    }))
+}
+
+/// The local versions of every applied migration, in the order they were applied, to apply
+/// them to a scratch database when checking for drift. Migrations applied in the same instant
+/// are taken in name order, as `up` runs them.
+pub fn drift_migrations<'a>(
+    local_migrations: &'a [Migration],
+    db_migrations: &[Migration],
+) -> Result<Vec<&'a Migration>> {
+    let mut applied: Vec<_> = db_migrations.iter().collect();
+    applied.sort_by(|a, b| (a.applied_at, &a.name).cmp(&(b.applied_at, &b.name)));
+    applied
+        .into_iter()
+        .map(|db_migration| {
+            let migration = local_migrations
+                .iter()
+                .find(|x| x.name == db_migration.name)
+                .ok_or_else(|| Error::MigrationNotFound(db_migration.name.clone()))?;
+            let is_unchanged = migration.hash == db_migration.hash
+                || db_migration.hash.as_ref() == Some(&migration.legacy_hash());
+            if !is_unchanged {
+                warn!(
+                    "Migration `{}` has changed since it was applied, so its current SQL is used",
+                    migration.name
+                );
+            }
+            Ok(migration)
+        })
+        .collect()
+}
+
+pub fn report_drift(drift: &[SchemaDrift]) -> Result<()> {
+    display::print_drift(drift);
+    if drift.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::SchemaDrifted(drift.len()))
+    }
+}
+
+/// Fail unless the scratch database, with schema `schema`, is empty, so that a live database
+/// passed by mistake is left alone.
+pub fn check_scratch_empty(schema: &[SchemaObject]) -> Result<()> {
+    if schema.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::ScratchDatabaseNotEmpty)
+    }
+}
+
+/// Fail if rolling back `migration` left the schema different to how it was before it ran.
+pub fn check_restored(migration: &Migration, before: &str, after: &str) -> Result<()> {
+    if after == before {
+        return Ok(());
+    }
+    Err(Error::SchemaNotRestored {
+        name: migration.name.clone(),
+        diff: diff::unified_diff(before, after, "before up", "after down"),
+    })
+}
+
+/// Fail if any step of the plan can't be run inside the plan's single transaction.
+pub fn check_atomic(plan: &[(Step, &Migration)]) -> Result<()> {
+    match plan.iter().find(|(_, x)| !x.transactional) {
+        Some((_, migration)) => Err(Error::NonTransactionalMigration(migration.name.clone())),
+        None => Ok(()),
+    }
+}
+
+pub fn print_status(format: OutputFormat, status: &[MigrationStatus]) {
+    match format {
+        OutputFormat::Text => display::print_status(status),
+        OutputFormat::Json => display::print_status_json(status),
+    }
+}
+
+pub fn print_history(format: OutputFormat, history: &[HistoryEntry]) {
+    match format {
+        OutputFormat::Text => display::print_history(history),
+        OutputFormat::Json => display::print_history_json(history),
+    }
+}
+
+pub fn print_plan(format: OutputFormat, plan: &OwnedPlan) {
+    let plan: Vec<_> = plan.iter().map(|(step, x)| (*step, x)).collect();
+    match format {
+        OutputFormat::Text => display::print_plan(&plan),
+        OutputFormat::Json => display::print_plan_json(&plan),
+    }
+}
+
+pub fn print_baseline_plan(format: OutputFormat, plan: &OwnedPlan) {
+    let plan: Vec<_> = plan.iter().map(|(step, x)| (*step, x)).collect();
+    match format {
+        OutputFormat::Text => plan.iter().for_each(|(_, x)| display::print_baseline(x)),
+        OutputFormat::Json => display::print_plan_json(&plan),
+    }
+}
+
+pub fn write_schema_file(schema_file: &str, schema: &str) -> Result<()> {
+    std::fs::write(schema_file, schema)?;
+    debug!("Wrote the schema to {}", schema_file);
+    Ok(())
+}
+
+/// Record `actor` as the one applying each migration in the plan.
+pub fn assign_actor(plan: &mut OwnedPlan, actor: Option<&str>) {
+    for (_, migration) in plan.iter_mut() {
+        migration.applied_by = actor.map(String::from);
+    }
+}
+
+pub fn to_owned_plan(plan: &[(Step, &Migration)]) -> OwnedPlan {
+    plan.iter()
+        .map(|(step, migration)| (*step, (*migration).clone()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn migration(name: &str, up_sql: &str) -> Migration {
+        MigrationBuilder::new()
+            .compound_name(name)
+            .up_sql(up_sql)
+            .down_sql("")
+            .build()
+            .unwrap()
+    }
+
+    fn recorded(local: &Migration, hash: &str) -> Migration {
+        MigrationBuilder::new()
+            .compound_name(&local.name)
+            .hash(hash)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    /// Only legacy hashes whose local migration is unchanged should be upgraded.
+    fn test_legacy_hash_upgrades() {
+        let local = vec![
+            migration("2020-01-01-000000_unchanged", "SELECT 1;"),
+            migration("2020-01-02-000000_edited", "SELECT 2;"),
+            migration("2020-01-03-000000_current", "SELECT 3;"),
+        ];
+        let db = vec![
+            recorded(&local[0], &local[0].legacy_hash()),
+            recorded(&local[1], "1234"),
+            recorded(&local[2], local[2].hash.as_deref().unwrap()),
+        ];
+
+        let upgrades = legacy_hash_upgrades(&local, &db);
+        assert_eq!(
+            upgrades,
+            vec![(local[0].name.as_str(), local[0].hash.as_deref().unwrap())]
+        );
+    }
+
+    #[test]
+    /// Accepting should only go ahead if the recorded migration is still the one that was shown.
+    fn test_accept_changes() {
+        let local = vec![migration("2020-01-01-000000_edited", "SELECT 2;")];
+        let mut db = vec![recorded(&local[0], "1234")];
+
+        let acceptance = accept_changes(&local, &db, &local[0].name, false, || Ok(true))
+            .unwrap()
+            .unwrap();
+        assert_eq!(Some(&acceptance.hash), local[0].hash.as_ref());
+        assert!(acceptance.check_unchanged(&db).is_ok());
+
+        db[0].hash = Some("5678".into());
+        assert!(acceptance.check_unchanged(&db).is_err());
+        assert!(acceptance.check_unchanged(&[]).is_err());
+
+        let declined = accept_changes(&local, &db, &local[0].name, false, || Ok(false));
+        assert!(declined.unwrap().is_none());
+
+        let legacy = vec![recorded(&local[0], &local[0].legacy_hash())];
+        let unchanged = accept_changes(&local, &legacy, &local[0].name, false, || Ok(true));
+        assert!(unchanged.unwrap().is_none());
+    }
+
+    #[test]
+    /// Drift replays the applied migrations in the order they were applied, falling back to
+    /// name order, and needs each of them locally.
+    fn test_drift_migrations() {
+        let local = vec![
+            migration("2020-01-01-000000_first", "SELECT 1;"),
+            migration("2020-01-02-000000_second", "SELECT 2;"),
+            migration("2020-01-03-000000_late", "SELECT 3;"),
+            migration("2020-01-04-000000_pending", "SELECT 4;"),
+        ];
+        let mut db = vec![
+            recorded(&local[2], local[2].hash.as_deref().unwrap()),
+            recorded(&local[1], local[1].hash.as_deref().unwrap()),
+            recorded(&local[0], &local[0].legacy_hash()),
+        ];
+        db[0].applied_at = Utc.with_ymd_and_hms(2020, 1, 5, 0, 0, 0).single();
+        db[1].applied_at = Utc.with_ymd_and_hms(2020, 1, 6, 0, 0, 0).single();
+        db[2].applied_at = db[1].applied_at;
+
+        let names: Vec<_> = drift_migrations(&local, &db)
+            .unwrap()
+            .iter()
+            .map(|x| x.name.as_str())
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                local[2].name.as_str(),
+                local[0].name.as_str(),
+                local[1].name.as_str(),
+            ]
+        );
+
+        let missing = vec![recorded(&local[0], "1234")];
+        assert!(drift_migrations(&local[1..], &missing).is_err());
+    }
+}
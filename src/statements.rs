@@ -0,0 +1,374 @@
+//! Split migration SQL into its statements, so that once a migration fails the statement that
+//! failed can be traced back to its line in `up.sql` or `down.sql`. Migrations are still run as
+//! a single batch, so a split that goes wrong only affects the error message.
+
+/// A single statement, without its trailing semicolon.
+#[derive(Debug, PartialEq)]
+pub struct Statement<'a> {
+    pub sql: &'a str,
+    /// The line the statement starts on, counting from 1. Comments before it aren't included.
+    pub line: usize,
+    /// Where the statement starts in the SQL, in bytes.
+    pub offset: usize,
+}
+
+/// The database the SQL is written for, since they quote strings differently.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dialect {
+    /// Adds `E'...'` escape strings and dollar-quoted strings.
+    Postgres,
+    Sqlite,
+    /// Treats a backslash in a string or quoted identifier as escaping the next character.
+    Mysql,
+}
+
+/// Split `sql` on the semicolons that end statements. Semicolons inside strings, quoted
+/// identifiers, comments, Postgres dollar-quoted bodies and `BEGIN ... END` blocks (such as
+/// SQLite trigger bodies) are left alone. Statements made up only of comments are dropped.
+pub fn split(sql: &str, dialect: Dialect) -> Vec<Statement<'_>> {
+    let backslash_escapes = dialect == Dialect::Mysql;
+    let bytes = sql.as_bytes();
+    let mut statements = Vec::new();
+    let mut scanner = Scanner {
+        bytes,
+        pos: 0,
+        line: 1,
+    };
+    // Where the current statement starts, and its line.
+    let mut start: Option<(usize, usize)> = None;
+    // How many `BEGIN` or `CASE` blocks we are inside.
+    let mut depth = 0usize;
+
+    while let Some(&c) = bytes.get(scanner.pos) {
+        if scanner.skip_whitespace_and_comments() {
+            continue;
+        }
+        let is_first_token = start.is_none();
+        if is_first_token && c != b';' {
+            start = Some((scanner.pos, scanner.line));
+        }
+
+        match c {
+            b';' if depth == 0 => {
+                if let Some((begin, line)) = start.take() {
+                    statements.push(Statement {
+                        sql: sql[begin..scanner.pos].trim_end(),
+                        line,
+                        offset: begin,
+                    });
+                }
+                scanner.pos += 1;
+            }
+            b'\'' | b'"' => scanner.skip_quoted(c, backslash_escapes),
+            b'`' => scanner.skip_quoted(c, false),
+            b'E' | b'e'
+                if dialect == Dialect::Postgres && bytes.get(scanner.pos + 1) == Some(&b'\'') =>
+            {
+                scanner.pos += 1;
+                scanner.skip_quoted(b'\'', true);
+            }
+            b'$' if dialect == Dialect::Postgres => {
+                if !scanner.skip_dollar_quoted() {
+                    scanner.pos += 1;
+                }
+            }
+            c if is_word_byte(c) && !c.is_ascii_digit() => {
+                let word = scanner.word();
+                if word.eq_ignore_ascii_case("BEGIN") {
+                    // A `BEGIN` starting a statement opens a transaction rather than a block.
+                    if !is_first_token {
+                        depth += 1;
+                    }
+                } else if word.eq_ignore_ascii_case("CASE") {
+                    depth += 1;
+                } else if word.eq_ignore_ascii_case("END") && depth > 0 {
+                    // MySQL's `IF`, `LOOP`, `WHILE` and `REPEAT` blocks never opened one. Their
+                    // keyword after `END`, like the one in `END CASE`, doesn't start a new block.
+                    let next = scanner.peek_word();
+                    let is_block_keyword = |x: &&str| next.eq_ignore_ascii_case(x);
+                    if ["IF", "LOOP", "WHILE", "REPEAT"]
+                        .iter()
+                        .any(is_block_keyword)
+                    {
+                        scanner.skip_whitespace_and_word();
+                    } else {
+                        if next.eq_ignore_ascii_case("CASE") {
+                            scanner.skip_whitespace_and_word();
+                        }
+                        depth -= 1;
+                    }
+                }
+            }
+            c if is_word_byte(c) => {
+                scanner.word();
+            }
+            _ => scanner.pos += 1,
+        }
+    }
+
+    if let Some((begin, line)) = start {
+        statements.push(Statement {
+            sql: sql[begin..].trim_end(),
+            line,
+            offset: begin,
+        });
+    }
+    statements
+}
+
+/// The index of the statement that byte `offset` of the SQL falls in. Anything after the end of
+/// a statement counts as part of it, up to the start of the next one.
+pub fn containing(statements: &[Statement], offset: usize) -> Option<usize> {
+    statements.iter().rposition(|x| x.offset <= offset)
+}
+
+struct Scanner<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    line: usize,
+}
+
+impl<'a> Scanner<'a> {
+    /// Skip over whitespace or a single comment, returning whether anything was skipped.
+    fn skip_whitespace_and_comments(&mut self) -> bool {
+        match (self.bytes[self.pos], self.bytes.get(self.pos + 1)) {
+            (b'\n', _) => {
+                self.line += 1;
+                self.pos += 1;
+            }
+            (c, _) if c.is_ascii_whitespace() => self.pos += 1,
+            (b'-', Some(b'-')) => {
+                while self.pos < self.bytes.len() && self.bytes[self.pos] != b'\n' {
+                    self.pos += 1;
+                }
+            }
+            (b'/', Some(b'*')) => {
+                // Postgres allows block comments to nest.
+                let mut depth = 0;
+                while self.pos < self.bytes.len() {
+                    if self.bytes[self.pos..].starts_with(b"/*") {
+                        depth += 1;
+                        self.pos += 2;
+                    } else if self.bytes[self.pos..].starts_with(b"*/") {
+                        depth -= 1;
+                        self.pos += 2;
+                        if depth == 0 {
+                            break;
+                        }
+                    } else {
+                        self.advance();
+                    }
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Skip a string or quoted identifier starting at the opening quote. A doubled quote is part
+    /// of the string, as is a backslash-escaped one if `backslash_escapes` is set.
+    fn skip_quoted(&mut self, quote: u8, backslash_escapes: bool) {
+        self.pos += 1;
+        while self.pos < self.bytes.len() {
+            let c = self.bytes[self.pos];
+            if backslash_escapes && c == b'\\' {
+                self.pos += 1;
+                self.advance();
+            } else if c == quote {
+                self.pos += 1;
+                if self.bytes.get(self.pos) != Some(&quote) {
+                    return;
+                }
+                self.pos += 1;
+            } else {
+                self.advance();
+            }
+        }
+    }
+
+    /// Skip a Postgres dollar-quoted string such as `$$ ... $$` or `$body$ ... $body$`, returning
+    /// false if the `$` doesn't start one.
+    fn skip_dollar_quoted(&mut self) -> bool {
+        let is_tag_start = self.pos == 0 || !is_word_byte(self.bytes[self.pos - 1]);
+        let tag_len = self.bytes[self.pos + 1..]
+            .iter()
+            .take_while(|&&x| is_word_byte(x))
+            .count();
+        let tag_end = self.pos + 1 + tag_len;
+        let is_tag = is_tag_start
+            && self.bytes.get(tag_end) == Some(&b'$')
+            && !self.bytes.get(self.pos + 1).is_some_and(u8::is_ascii_digit);
+        if !is_tag {
+            return false;
+        }
+
+        let tag = &self.bytes[self.pos..=tag_end];
+        self.pos = tag_end + 1;
+        while self.pos < self.bytes.len() {
+            if self.bytes[self.pos..].starts_with(tag) {
+                self.pos += tag.len();
+                return true;
+            }
+            self.advance();
+        }
+        true
+    }
+
+    fn word(&mut self) -> &'a str {
+        let start = self.pos;
+        while self.pos < self.bytes.len() && is_word_byte(self.bytes[self.pos]) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.bytes[start..self.pos]).unwrap_or("")
+    }
+
+    /// The next word, without moving past it.
+    fn peek_word(&self) -> &'a str {
+        let mut scanner = Scanner { ..*self };
+        scanner.skip_whitespace_and_word()
+    }
+
+    /// Move past the next word, and any whitespace and comments before it.
+    fn skip_whitespace_and_word(&mut self) -> &'a str {
+        while self.pos < self.bytes.len() && self.skip_whitespace_and_comments() {}
+        self.word()
+    }
+
+    fn advance(&mut self) {
+        if self.bytes[self.pos] == b'\n' {
+            self.line += 1;
+        }
+        self.pos += 1;
+    }
+}
+
+// Bytes of multi-byte characters count as part of a word, so words are never split mid-character.
+fn is_word_byte(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c >= 0x80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split_sql(sql: &str) -> Vec<(&str, usize)> {
+        split(sql, Dialect::Postgres)
+            .into_iter()
+            .map(|x| (x.sql, x.line))
+            .collect()
+    }
+
+    #[test]
+    /// Statements should be split on semicolons and report the line they start on.
+    fn test_split() {
+        let sql = "-- movine:no-transaction\n\
+                   CREATE TABLE users (id INTEGER);\n\
+                   \n\
+                   /* Seed\n   data */ INSERT INTO users\n  VALUES (1);\n\
+                   -- Nothing else to do";
+        assert_eq!(
+            split_sql(sql),
+            vec![
+                ("CREATE TABLE users (id INTEGER)", 2),
+                ("INSERT INTO users\n  VALUES (1)", 5),
+            ]
+        );
+        assert_eq!(split_sql("SELECT 1"), vec![("SELECT 1", 1)]);
+        assert!(split_sql(" ;\n-- only a comment\n").is_empty());
+    }
+
+    #[test]
+    /// Offsets should be found in the statement they fall in, or the one before any gap.
+    fn test_containing() {
+        let sql = "SELECT 1;\n-- Then\nSELECT 2;";
+        let statements = split(sql, Dialect::Postgres);
+        assert_eq!(statements[1].offset, sql.rfind("SELECT").unwrap());
+        assert_eq!(containing(&statements, 0), Some(0));
+        assert_eq!(containing(&statements, 12), Some(0));
+        assert_eq!(containing(&statements, statements[1].offset), Some(1));
+        assert_eq!(containing(&[], 0), None);
+    }
+
+    #[test]
+    /// Semicolons inside strings, identifiers and comments don't end a statement.
+    fn test_split_quoted() {
+        let sql = "INSERT INTO \"a;b\" VALUES ('it''s; fine', E'\\';', `c;d`) /* ; /* ; */ ; */;\n\
+                   SELECT '\n;';\n\
+                   SELECT 2 -- ;\n;";
+        assert_eq!(
+            split_sql(sql),
+            vec![
+                (
+                    "INSERT INTO \"a;b\" VALUES ('it''s; fine', E'\\';', `c;d`) /* ; /* ; */ ; */",
+                    1
+                ),
+                ("SELECT '\n;'", 2),
+                ("SELECT 2 -- ;", 4),
+            ]
+        );
+    }
+
+    #[test]
+    /// Function and trigger bodies are kept in one statement.
+    fn test_split_bodies() {
+        let sql = "CREATE FUNCTION f() RETURNS int AS $body$ BEGIN RETURN 1; END; $body$ LANGUAGE plpgsql;\n\
+                   DO $$ BEGIN PERFORM $1; END $$;\n\
+                   CREATE TRIGGER t AFTER INSERT ON users BEGIN\n\
+                   \x20 UPDATE users SET id = CASE WHEN id > 0 THEN id ELSE 0 END;\n\
+                   \x20 DELETE FROM users;\n\
+                   END;\n\
+                   BEGIN;\n\
+                   SELECT 1;\n\
+                   END;";
+        let statements = split_sql(sql);
+        assert_eq!(statements.len(), 6);
+        assert_eq!(statements[0].1, 1);
+        assert!(statements[0].0.ends_with("LANGUAGE plpgsql"));
+        assert_eq!(statements[1], ("DO $$ BEGIN PERFORM $1; END $$", 2));
+        assert_eq!(statements[2].1, 3);
+        assert!(statements[2].0.ends_with("DELETE FROM users;\nEND"));
+        assert_eq!(statements[3..], [("BEGIN", 7), ("SELECT 1", 8), ("END", 9)]);
+    }
+
+    #[test]
+    /// The keyword after a MySQL `END` belongs to it, rather than opening another block.
+    fn test_split_end_keywords() {
+        let sql = "CREATE PROCEDURE p(x INT)\n\
+                   BEGIN\n\
+                   \x20 CASE x WHEN 1 THEN SELECT 1; ELSE SELECT 2; END CASE;\n\
+                   \x20 IF x > 0 THEN SELECT 3; END IF;\n\
+                   END;\n\
+                   SELECT 4;";
+        let statements: Vec<_> = split(sql, Dialect::Mysql)
+            .into_iter()
+            .map(|x| (x.sql, x.line))
+            .collect();
+        assert_eq!(statements.len(), 2);
+        assert!(statements[0].0.ends_with("END IF;\nEND"));
+        assert_eq!(statements[1], ("SELECT 4", 6));
+    }
+
+    #[test]
+    /// MySQL strings can escape their quote with a backslash, which other databases don't allow.
+    fn test_split_backslash_escapes() {
+        let sql = "INSERT INTO t VALUES ('it\\'s; fine', \"a\\\";b\");\nSELECT 1;";
+        let statements: Vec<_> = split(sql, Dialect::Mysql)
+            .into_iter()
+            .map(|x| x.sql)
+            .collect();
+        assert_eq!(
+            statements,
+            vec![
+                "INSERT INTO t VALUES ('it\\'s; fine', \"a\\\";b\")",
+                "SELECT 1"
+            ]
+        );
+
+        let sql = "SELECT 'C:\\'; SELECT 1;";
+        let statements: Vec<_> = split(sql, Dialect::Sqlite)
+            .into_iter()
+            .map(|x| x.sql)
+            .collect();
+        assert_eq!(statements, vec!["SELECT 'C:\\'", "SELECT 1"]);
+    }
+}